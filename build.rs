@@ -1,16 +1,31 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
     tonic_build::configure()
         .build_server(true)
         .build_client(false)
-        // .type_attribute(
-        //     "SubscriptionCommandInitial",
-        //     "#[derive(validator::Validate)]",
-        // )
-        // .field_attribute(
-        //     "SubscriptionCommandInitial.subscriptions",
-        //     "#[validate(custom = \"crate::app::util::validator::validate_custom_length_vec\")]",
-        // )
-        .compile(&["proto/test_message.proto"], &["proto"])?;
+        // file descriptor set consumed by the gRPC reflection service
+        .file_descriptor_set_path(out_dir.join("test_message_descriptor.bin"))
+        // request validation rules enforced with `crate::app::util::validator::validate_request`
+        .type_attribute("TestMessage", "#[derive(validator::Validate)]")
+        .field_attribute(
+            "TestMessage.content",
+            "#[validate(custom = \"crate::app::util::validator::validate_not_blank\")]",
+        )
+        .type_attribute(
+            "SubscriptionCommandInitial",
+            "#[derive(validator::Validate)]",
+        )
+        .field_attribute(
+            "SubscriptionCommandInitial.subscriptions",
+            "#[validate(custom = \"crate::app::util::validator::validate_custom_length_vec\")]",
+        )
+        .compile(
+            &["proto/test_message.proto", "proto/amqp_subscription.proto"],
+            &["proto"],
+        )?;
 
     Ok(())
 }
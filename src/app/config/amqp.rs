@@ -0,0 +1,78 @@
+use crate::app::util::error::ServiceError;
+use lapin::{Connection, ConnectionProperties};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::sleep};
+use tracing::{info, warn};
+
+/// delay before the first reconnection retry, doubled after every failed attempt
+pub const RECONNECT_BACKOFF_BASE: Duration = Duration::from_millis(250);
+/// upper bound of the delay between two reconnection attempts
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// open a lapin connection to `address` driven by the current tokio runtime
+pub async fn connect_amqp(address: &str) -> Result<Connection, ServiceError> {
+    let properties =
+        ConnectionProperties::default().with_executor(tokio_executor_trait::Tokio::current());
+    #[cfg(unix)]
+    let properties = properties.with_reactor(tokio_reactor_trait::Tokio);
+
+    Ok(Connection::connect(address, properties).await?)
+}
+
+/// lapin connection shared by every subscription which is re-established once lost
+pub struct AmqpConnection {
+    address: String,
+    current: Mutex<Arc<Connection>>,
+}
+
+impl AmqpConnection {
+    pub async fn connect(address: &str) -> Result<Self, ServiceError> {
+        Ok(AmqpConnection {
+            address: address.to_string(),
+            current: Mutex::new(Arc::new(connect_amqp(address).await?)),
+        })
+    }
+
+    /// the connection currently in use
+    pub async fn current(&self) -> Arc<Connection> {
+        Arc::clone(&*self.current.lock().await)
+    }
+
+    /// replace the `lost` connection, retrying with an exponential backoff until the broker is
+    /// reachable again. Concurrent callers wait for the first one and share its new connection,
+    /// a connection already replaced (or still connected) is returned as-is
+    pub async fn reconnect(&self, lost: &Arc<Connection>) -> Arc<Connection> {
+        let mut current = self.current.lock().await;
+
+        if !Arc::ptr_eq(&current, lost) || current.status().connected() {
+            return Arc::clone(&current);
+        }
+
+        let mut backoff = RECONNECT_BACKOFF_BASE;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            match connect_amqp(&self.address).await {
+                Ok(connection) => {
+                    info!(
+                        "reconnected to the AMQP broker after {} attempt(s)",
+                        attempt
+                    );
+                    *current = Arc::new(connection);
+
+                    return Arc::clone(&current);
+                }
+                Err(e) => {
+                    warn!(
+                        "AMQP reconnection attempt {} failed, retrying in {:?}: {}",
+                        attempt, backoff, e
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+}
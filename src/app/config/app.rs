@@ -0,0 +1,844 @@
+use super::subscriber::{LogRotation, LogTarget, LOG_LEVEL};
+use crate::app::middleware::cookie::layer::DEFAULT_AUTH_EXEMPT_METHODS;
+use crate::app::middleware::tracing::layer::MAX_EXTRA_HEADERS;
+use crate::app::util::{
+    credential::CookieKey,
+    error::{ErrorDetailMode, ServiceError},
+    recent::{DEFAULT_RECENT_ERRORS_MAX_BYTES, DEFAULT_RECENT_ERRORS_MAX_ENTRIES},
+    session::{SessionBackend, SessionExpiryMode},
+    version::ClientVersion,
+};
+use hyper::header::HeaderName;
+use std::{env::var, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use tracing_subscriber::EnvFilter;
+
+/// placeholder of a secret value in `AppConfig::snapshot()`
+const REDACTED: &str = "<redacted>";
+
+/// upper bound of the session TTLs (100 years), far below what overflows the expire times redis
+/// keeps in milliseconds
+const MAX_SESSION_TTL_SECONDS: i64 = 100 * 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+/// every env var the application read, parsed and validated once at startup
+pub struct AppConfig {
+    pub addr: SocketAddr,
+    pub uds_path: Option<PathBuf>,
+    pub metrics_port: Option<u16>,
+    #[cfg(feature = "amqp")]
+    pub amqp_address: Option<String>,
+    pub redis_url: String,
+    pub redis_cluster: bool,
+    pub redis_connect_retries: u32,
+    pub redis_command_timeout: Duration,
+    pub redis_latency_metrics: bool,
+    pub grpc_status_metrics: bool,
+    pub retry_budget_ratio: f64,
+    pub sentry_url: String,
+    pub sentry_traces_sample_rate: f32,
+    pub service_id: String,
+    pub admin_token: Option<String>,
+    pub cookie_signing_key: Option<CookieKey>,
+    pub session_cookie_name: String,
+    pub error_detail_mode: ErrorDetailMode,
+    pub force_relogin_below_version: Option<ClientVersion>,
+    pub login_url: Option<String>,
+    pub session_read_ttl: time::Duration,
+    pub session_write_ttl: time::Duration,
+    pub session_write_methods: Vec<String>,
+    pub session_expiry_mode: SessionExpiryMode,
+    pub session_backend: SessionBackend,
+    pub require_idempotency_methods: Vec<String>,
+    pub disabled_services: Vec<String>,
+    pub auth_exempt_methods: Vec<String>,
+    pub rate_limit_max_requests: u64,
+    pub rate_limit_window: Duration,
+    pub login_attempts_per_minute: u64,
+    pub login_lockout_threshold: u64,
+    pub login_lockout_base: Duration,
+    pub max_concurrent_streams: usize,
+    pub stream_send_timeout: Duration,
+    pub min_event_delay: Duration,
+    pub reject_empty_content: bool,
+    pub recent_errors_max_entries: usize,
+    pub recent_errors_max_bytes: usize,
+    pub aggregate_min_interval: Duration,
+    pub aggregate_max_window: Duration,
+    pub shutdown_grace: Duration,
+    pub health_check_interval: Duration,
+    pub force_health_not_serving: bool,
+    pub log_filter: String,
+    pub log_target: LogTarget,
+    pub log_dir: PathBuf,
+    pub log_rotation: LogRotation,
+    #[cfg(feature = "otlp")]
+    pub otlp_endpoint: Option<String>,
+    pub trace_extra_headers: Vec<HeaderName>,
+    #[cfg(feature = "amqp")]
+    pub error_mirror_exchange: Option<String>,
+    #[cfg(feature = "amqp")]
+    pub error_mirror_routing_key: String,
+    #[cfg(feature = "amqp")]
+    pub amqp_subscription_exchange: String,
+    #[cfg(feature = "amqp")]
+    pub amqp_operation_timeout: Duration,
+    pub upload_dir: PathBuf,
+    pub max_upload_bytes: u64,
+    pub max_message_bytes: usize,
+    pub request_timeout: Option<Duration>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub allowed_sni_hosts: Vec<String>,
+    #[cfg(feature = "grpc-web")]
+    pub allowed_origins: Vec<hyper::header::HeaderValue>,
+}
+
+impl AppConfig {
+    /// read the config from the process environment
+    pub fn from_env() -> Result<Self, ServiceError> {
+        AppConfig::from_lookup(|key| var(key).ok())
+    }
+
+    /// read the config through `lookup` instead of the process environment. Every invalid or
+    /// missing var is collected into a single `ServiceError::InvalidConfig` instead of failing on
+    /// the first one
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, ServiceError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut reader = EnvReader {
+            lookup,
+            errors: vec![],
+        };
+
+        let app_url = reader.required("APP_URL");
+        let app_port = reader.required("APP_PORT");
+        let addr = match (app_url, app_port) {
+            (Some(url), Some(port)) => match format!("{}:{}", url, port).parse::<SocketAddr>() {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    reader.invalid("APP_URL/APP_PORT", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let redis_url = reader.required("REDIS_URL");
+        let sentry_url = reader.required("SENTRY_URL");
+        let session_ttl =
+            reader.positive_at_most("SESSION_TTL_SECONDS", 86400, MAX_SESSION_TTL_SECONDS);
+        let mut trace_extra_headers = vec![];
+        for name in reader.list("TRACE_EXTRA_HEADERS") {
+            match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(name) => trace_extra_headers.push(name),
+                Err(e) => reader.invalid("TRACE_EXTRA_HEADERS", format!("{}: {}", name, e)),
+            }
+        }
+        if trace_extra_headers.len() > MAX_EXTRA_HEADERS {
+            reader.invalid(
+                "TRACE_EXTRA_HEADERS",
+                format!("at most {} headers are allowed", MAX_EXTRA_HEADERS),
+            );
+        }
+
+        #[cfg(feature = "grpc-web")]
+        let mut allowed_origins = vec![];
+        #[cfg(feature = "grpc-web")]
+        for origin in reader.list("ALLOWED_ORIGINS") {
+            match origin.parse() {
+                Ok(origin) => allowed_origins.push(origin),
+                Err(e) => reader.invalid("ALLOWED_ORIGINS", format!("{}: {}", origin, e)),
+            }
+        }
+
+        let config = AppConfig {
+            addr: addr.unwrap_or_else(|| ([0, 0, 0, 0], 0).into()),
+            uds_path: reader.optional("UDS_PATH").map(PathBuf::from),
+            metrics_port: reader.parsed_optional("METRICS_PORT"),
+            #[cfg(feature = "amqp")]
+            amqp_address: reader.optional("AMQP_ADDRESS"),
+            redis_url: redis_url.unwrap_or_default(),
+            redis_cluster: reader.parsed("REDIS_CLUSTER", false),
+            redis_connect_retries: reader.parsed("REDIS_CONNECT_RETRIES", 5),
+            redis_command_timeout: Duration::from_millis(
+                reader.parsed("REDIS_COMMAND_TIMEOUT_MS", 2000),
+            ),
+            redis_latency_metrics: reader.parsed("REDIS_LATENCY_METRICS", false),
+            grpc_status_metrics: reader.parsed("GRPC_STATUS_METRICS", false),
+            retry_budget_ratio: reader.parsed("RETRY_BUDGET_RATIO", 0.1),
+            sentry_url: sentry_url.unwrap_or_default(),
+            sentry_traces_sample_rate: reader.parsed("SENTRY_TRACES_SAMPLE_RATE", 0.0),
+            service_id: reader
+                .optional("SERVICE_ID")
+                .unwrap_or_else(default_service_id),
+            admin_token: reader.optional("ADMIN_TOKEN"),
+            cookie_signing_key: reader.parsed_optional("COOKIE_SIGNING_KEY"),
+            session_cookie_name: reader
+                .optional("SESSION_COOKIE_NAME")
+                .unwrap_or_else(|| "session".to_string()),
+            error_detail_mode: reader.parsed("ERROR_DETAIL_MODE", ErrorDetailMode::default()),
+            force_relogin_below_version: reader.parsed_optional("FORCE_RELOGIN_BELOW_VERSION"),
+            login_url: reader.optional("LOGIN_URL"),
+            session_read_ttl: time::Duration::seconds(reader.positive_at_most(
+                "SESSION_READ_TTL",
+                session_ttl,
+                MAX_SESSION_TTL_SECONDS,
+            )),
+            session_write_ttl: time::Duration::seconds(reader.positive_at_most(
+                "SESSION_WRITE_TTL",
+                session_ttl,
+                MAX_SESSION_TTL_SECONDS,
+            )),
+            session_write_methods: reader.list("SESSION_WRITE_METHODS"),
+            session_expiry_mode: reader.parsed("SESSION_EXPIRY_MODE", SessionExpiryMode::default()),
+            session_backend: reader.parsed("SESSION_BACKEND", SessionBackend::default()),
+            require_idempotency_methods: reader.list("REQUIRE_IDEMPOTENCY_METHODS"),
+            disabled_services: reader.list("DISABLED_SERVICES"),
+            auth_exempt_methods: match reader.optional("AUTH_EXEMPT_METHODS") {
+                Some(_) => reader.list("AUTH_EXEMPT_METHODS"),
+                None => DEFAULT_AUTH_EXEMPT_METHODS
+                    .iter()
+                    .map(|method| method.to_string())
+                    .collect(),
+            },
+            rate_limit_max_requests: reader.parsed("RATE_LIMIT_MAX_REQUESTS", 100),
+            rate_limit_window: Duration::from_secs(reader.parsed("RATE_LIMIT_WINDOW_SECONDS", 60)),
+            login_attempts_per_minute: reader.parsed("LOGIN_ATTEMPTS_PER_MINUTE", 10),
+            login_lockout_threshold: reader.parsed("LOGIN_LOCKOUT_THRESHOLD", 5),
+            login_lockout_base: Duration::from_secs(
+                reader.parsed("LOGIN_LOCKOUT_BASE_SECONDS", 30),
+            ),
+            max_concurrent_streams: reader.parsed("MAX_CONCURRENT_STREAMS", 1024),
+            stream_send_timeout: Duration::from_millis(
+                reader.parsed("STREAM_SEND_TIMEOUT_MS", 5000),
+            ),
+            min_event_delay: Duration::from_millis(reader.positive("MIN_EVENT_DELAY_MS", 5)),
+            reject_empty_content: reader.parsed("REJECT_EMPTY_CONTENT", false),
+            recent_errors_max_entries: reader.parsed(
+                "RECENT_ERRORS_BUFFER_MAX_ENTRIES",
+                DEFAULT_RECENT_ERRORS_MAX_ENTRIES,
+            ),
+            recent_errors_max_bytes: reader.parsed(
+                "RECENT_ERRORS_BUFFER_MAX_BYTES",
+                DEFAULT_RECENT_ERRORS_MAX_BYTES,
+            ),
+            aggregate_min_interval: Duration::from_millis(
+                reader.positive("AGGREGATE_MIN_INTERVAL_MS", 100),
+            ),
+            aggregate_max_window: Duration::from_secs(
+                reader.parsed("AGGREGATE_MAX_WINDOW_SECONDS", 300),
+            ),
+            // documented to fall back to 10s rather than fail when the value is unparseable
+            shutdown_grace: Duration::from_secs(
+                reader.parsed_or_default("SHUTDOWN_GRACE_SECONDS", 10),
+            ),
+            health_check_interval: Duration::from_secs(
+                reader.positive("HEALTH_CHECK_INTERVAL_SECONDS", 5),
+            ),
+            force_health_not_serving: reader.parsed("FORCE_HEALTH_NOT_SERVING", false),
+            log_filter: reader
+                .optional("RUST_LOG")
+                .unwrap_or_else(|| LOG_LEVEL.to_string()),
+            log_target: reader.parsed("LOG_TARGET", LogTarget::default()),
+            log_dir: reader
+                .optional("LOG_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| {
+                    std::env::temp_dir()
+                        .join(env!("CARGO_PKG_NAME"))
+                        .join("log")
+                }),
+            log_rotation: reader.parsed("LOG_ROTATION", LogRotation::default()),
+            #[cfg(feature = "otlp")]
+            otlp_endpoint: reader.optional("OTEL_EXPORTER_OTLP_ENDPOINT"),
+            trace_extra_headers,
+            #[cfg(feature = "amqp")]
+            error_mirror_exchange: reader.optional("ERROR_MIRROR_EXCHANGE"),
+            #[cfg(feature = "amqp")]
+            error_mirror_routing_key: reader
+                .optional("ERROR_MIRROR_ROUTING_KEY")
+                .unwrap_or_else(|| "service.error".to_string()),
+            #[cfg(feature = "amqp")]
+            amqp_subscription_exchange: reader
+                .optional("AMQP_SUBSCRIPTION_EXCHANGE")
+                .unwrap_or_else(|| "amq.topic".to_string()),
+            #[cfg(feature = "amqp")]
+            amqp_operation_timeout: Duration::from_millis(
+                reader.parsed("AMQP_OPERATION_TIMEOUT_MS", 5000),
+            ),
+            upload_dir: reader
+                .optional("UPLOAD_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join(env!("CARGO_PKG_NAME"))),
+            max_upload_bytes: reader.parsed("MAX_UPLOAD_BYTES", 16 * 1024 * 1024),
+            // same default as the decoding limit of later tonic releases
+            max_message_bytes: reader.parsed("MAX_MESSAGE_BYTES", 4 * 1024 * 1024),
+            // zero disables the timeout
+            request_timeout: Some(reader.parsed("REQUEST_TIMEOUT_SECONDS", 30))
+                .filter(|seconds| *seconds > 0)
+                .map(Duration::from_secs),
+            tls_cert_path: reader.optional("TLS_CERT_PATH"),
+            tls_key_path: reader.optional("TLS_KEY_PATH"),
+            allowed_sni_hosts: reader.list("ALLOWED_SNI_HOSTS"),
+            #[cfg(feature = "grpc-web")]
+            allowed_origins,
+        };
+
+        // the shutdown drain acquire every permit at once which is bounded by `u32`
+        if config.max_concurrent_streams == 0 || config.max_concurrent_streams > u32::MAX as usize {
+            reader.invalid(
+                "MAX_CONCURRENT_STREAMS",
+                format!("must be between 1 and {}", u32::MAX),
+            );
+        }
+
+        if !(0.0..=1.0).contains(&config.retry_budget_ratio) {
+            reader.invalid("RETRY_BUDGET_RATIO", "must be between 0 and 1");
+        }
+
+        if !(0.0..=1.0).contains(&config.sentry_traces_sample_rate) {
+            reader.invalid("SENTRY_TRACES_SAMPLE_RATE", "must be between 0 and 1");
+        }
+
+        if let Err(e) = EnvFilter::try_new(&config.log_filter) {
+            reader.invalid("RUST_LOG", e);
+        }
+
+        match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(_), None) => reader.invalid(
+                "TLS_KEY_PATH",
+                "must be set when TLS_CERT_PATH is set. TLS requires both certificate and private key",
+            ),
+            (None, Some(_)) => reader.invalid(
+                "TLS_CERT_PATH",
+                "must be set when TLS_KEY_PATH is set. TLS requires both certificate and private key",
+            ),
+            _ => {}
+        }
+
+        if !config.allowed_sni_hosts.is_empty() && config.tls_cert_path.is_none() {
+            reader.invalid(
+                "ALLOWED_SNI_HOSTS",
+                "requires TLS_CERT_PATH and TLS_KEY_PATH to be set",
+            );
+        }
+
+        if config.max_message_bytes == 0 {
+            reader.invalid("MAX_MESSAGE_BYTES", "must be greater than 0");
+        }
+
+        if config.uds_path.is_some() && !config.allowed_sni_hosts.is_empty() {
+            reader.invalid(
+                "ALLOWED_SNI_HOSTS",
+                "is not supported when listening on UDS_PATH",
+            );
+        }
+
+        #[cfg(not(unix))]
+        if config.uds_path.is_some() {
+            reader.invalid("UDS_PATH", "unix domain sockets are only supported on unix");
+        }
+
+        // a cookie name is an RFC 6265 token: visible ASCII without separators
+        if config.session_cookie_name.is_empty()
+            || !config
+                .session_cookie_name
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+        {
+            reader.invalid(
+                "SESSION_COOKIE_NAME",
+                "must be a non-empty token without separators",
+            );
+        }
+
+        #[cfg(not(feature = "unsigned-cookie"))]
+        if config.cookie_signing_key.is_none() {
+            reader.invalid(
+                "COOKIE_SIGNING_KEY",
+                "must be set unless built with the unsigned-cookie feature",
+            );
+        }
+
+        #[cfg(feature = "amqp")]
+        if config.error_mirror_exchange.is_some() && config.amqp_address.is_none() {
+            reader.invalid(
+                "AMQP_ADDRESS",
+                "must be set when ERROR_MIRROR_EXCHANGE is set",
+            );
+        }
+
+        if reader.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ServiceError::InvalidConfig(reader.errors))
+        }
+    }
+
+    /// every effective setting as key/value pairs for debugging config drift. Secrets and URLs
+    /// that may embed credentials are redacted, an unset optional setting has an empty value
+    pub fn snapshot(&self) -> Vec<(&'static str, String)> {
+        fn optional<T: ToString>(value: &Option<T>) -> String {
+            value.as_ref().map(T::to_string).unwrap_or_default()
+        }
+
+        fn redacted<T>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|_| REDACTED.to_string())
+                .unwrap_or_default()
+        }
+
+        let features = [
+            ("reflection", cfg!(feature = "reflection")),
+            ("amqp", cfg!(feature = "amqp")),
+            ("compression", cfg!(feature = "compression")),
+            ("otlp", cfg!(feature = "otlp")),
+            ("grpc-web", cfg!(feature = "grpc-web")),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect::<Vec<_>>();
+
+        #[cfg_attr(
+            not(any(feature = "amqp", feature = "otlp", feature = "grpc-web")),
+            allow(unused_mut)
+        )]
+        let mut snapshot = vec![
+            ("addr", self.addr.to_string()),
+            (
+                "uds_path",
+                self.uds_path
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            ("metrics_port", optional(&self.metrics_port)),
+            ("redis_url", REDACTED.to_string()),
+            ("redis_cluster", self.redis_cluster.to_string()),
+            (
+                "redis_connect_retries",
+                self.redis_connect_retries.to_string(),
+            ),
+            (
+                "redis_command_timeout",
+                format!("{:?}", self.redis_command_timeout),
+            ),
+            (
+                "redis_latency_metrics",
+                self.redis_latency_metrics.to_string(),
+            ),
+            ("grpc_status_metrics", self.grpc_status_metrics.to_string()),
+            ("retry_budget_ratio", self.retry_budget_ratio.to_string()),
+            ("sentry_url", REDACTED.to_string()),
+            (
+                "sentry_traces_sample_rate",
+                self.sentry_traces_sample_rate.to_string(),
+            ),
+            ("service_id", self.service_id.clone()),
+            ("admin_token", redacted(&self.admin_token)),
+            ("cookie_signing_key", redacted(&self.cookie_signing_key)),
+            ("session_cookie_name", self.session_cookie_name.clone()),
+            (
+                "error_detail_mode",
+                format!("{:?}", self.error_detail_mode).to_lowercase(),
+            ),
+            (
+                "force_relogin_below_version",
+                optional(&self.force_relogin_below_version),
+            ),
+            ("login_url", optional(&self.login_url)),
+            (
+                "session_read_ttl",
+                format!("{}s", self.session_read_ttl.whole_seconds()),
+            ),
+            (
+                "session_write_ttl",
+                format!("{}s", self.session_write_ttl.whole_seconds()),
+            ),
+            (
+                "session_write_methods",
+                self.session_write_methods.join(","),
+            ),
+            (
+                "session_expiry_mode",
+                format!("{:?}", self.session_expiry_mode).to_lowercase(),
+            ),
+            (
+                "session_backend",
+                format!("{:?}", self.session_backend).to_lowercase(),
+            ),
+            (
+                "require_idempotency_methods",
+                self.require_idempotency_methods.join(","),
+            ),
+            ("disabled_services", self.disabled_services.join(",")),
+            ("auth_exempt_methods", self.auth_exempt_methods.join(",")),
+            (
+                "rate_limit_max_requests",
+                self.rate_limit_max_requests.to_string(),
+            ),
+            ("rate_limit_window", format!("{:?}", self.rate_limit_window)),
+            (
+                "login_attempts_per_minute",
+                self.login_attempts_per_minute.to_string(),
+            ),
+            (
+                "login_lockout_threshold",
+                self.login_lockout_threshold.to_string(),
+            ),
+            (
+                "login_lockout_base",
+                format!("{:?}", self.login_lockout_base),
+            ),
+            (
+                "max_concurrent_streams",
+                self.max_concurrent_streams.to_string(),
+            ),
+            (
+                "stream_send_timeout",
+                format!("{:?}", self.stream_send_timeout),
+            ),
+            ("min_event_delay", format!("{:?}", self.min_event_delay)),
+            (
+                "reject_empty_content",
+                self.reject_empty_content.to_string(),
+            ),
+            (
+                "recent_errors_max_entries",
+                self.recent_errors_max_entries.to_string(),
+            ),
+            (
+                "recent_errors_max_bytes",
+                self.recent_errors_max_bytes.to_string(),
+            ),
+            (
+                "aggregate_min_interval",
+                format!("{:?}", self.aggregate_min_interval),
+            ),
+            (
+                "aggregate_max_window",
+                format!("{:?}", self.aggregate_max_window),
+            ),
+            ("shutdown_grace", format!("{:?}", self.shutdown_grace)),
+            (
+                "health_check_interval",
+                format!("{:?}", self.health_check_interval),
+            ),
+            (
+                "force_health_not_serving",
+                self.force_health_not_serving.to_string(),
+            ),
+            ("upload_dir", self.upload_dir.display().to_string()),
+            ("max_upload_bytes", self.max_upload_bytes.to_string()),
+            ("max_message_bytes", self.max_message_bytes.to_string()),
+            (
+                "request_timeout",
+                optional(&self.request_timeout.map(|timeout| timeout.as_secs())),
+            ),
+            ("tls_cert_path", optional(&self.tls_cert_path)),
+            ("tls_key_path", optional(&self.tls_key_path)),
+            ("allowed_sni_hosts", self.allowed_sni_hosts.join(",")),
+            ("log_filter", self.log_filter.clone()),
+            (
+                "log_target",
+                format!("{:?}", self.log_target).to_lowercase(),
+            ),
+            ("log_dir", self.log_dir.display().to_string()),
+            (
+                "log_rotation",
+                format!("{:?}", self.log_rotation).to_lowercase(),
+            ),
+            (
+                "trace_extra_headers",
+                self.trace_extra_headers
+                    .iter()
+                    .map(HeaderName::as_str)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("features", features.join(",")),
+        ];
+
+        #[cfg(feature = "amqp")]
+        snapshot.extend([
+            ("amqp_address", redacted(&self.amqp_address)),
+            (
+                "error_mirror_exchange",
+                optional(&self.error_mirror_exchange),
+            ),
+            (
+                "error_mirror_routing_key",
+                self.error_mirror_routing_key.clone(),
+            ),
+            (
+                "amqp_subscription_exchange",
+                self.amqp_subscription_exchange.clone(),
+            ),
+            (
+                "amqp_operation_timeout",
+                format!("{:?}", self.amqp_operation_timeout),
+            ),
+        ]);
+
+        #[cfg(feature = "otlp")]
+        snapshot.push(("otlp_endpoint", optional(&self.otlp_endpoint)));
+
+        #[cfg(feature = "grpc-web")]
+        snapshot.push((
+            "allowed_origins",
+            self.allowed_origins
+                .iter()
+                .filter_map(|origin| origin.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(","),
+        ));
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+impl AppConfig {
+    /// config of the tests. The required vars are filled in, `vars` override or add to them
+    pub fn for_test(vars: &[(&str, &str)]) -> Self {
+        AppConfig::try_for_test(vars).expect("expect the test config to be valid")
+    }
+
+    /// same as `for_test()` but the validation result is returned instead of unwrapped
+    pub fn try_for_test(vars: &[(&str, &str)]) -> Result<Self, ServiceError> {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<std::collections::HashMap<_, _>>();
+
+        AppConfig::from_lookup(|key| match vars.get(key) {
+            Some(value) => Some(value.clone()),
+            None => match key {
+                "APP_URL" => Some("127.0.0.1".to_string()),
+                "APP_PORT" => Some("0".to_string()),
+                "REDIS_URL" => Some("redis://127.0.0.1:6379".to_string()),
+                "SENTRY_URL" => Some("https://public@127.0.0.1/1".to_string()),
+                "COOKIE_SIGNING_KEY" => Some("k".repeat(64)),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// id unique to this instance (package name, hostname and pid) so replicas sharing the same
+/// config never mistake each other for a proxy loop
+fn default_service_id() -> String {
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!(
+        "{}-{}-{}",
+        env!("CARGO_PKG_NAME"),
+        hostname,
+        std::process::id()
+    )
+}
+
+struct EnvReader<F> {
+    lookup: F,
+    errors: Vec<String>,
+}
+
+impl<F> EnvReader<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn optional(&self, key: &str) -> Option<String> {
+        (self.lookup)(key).filter(|value| !value.is_empty())
+    }
+
+    /// comma separated values with blank entries skipped
+    fn list(&self, key: &str) -> Vec<String> {
+        self.optional(key)
+            .map(|values| {
+                values
+                    .split(',')
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn required(&mut self, key: &str) -> Option<String> {
+        let value = self.optional(key);
+
+        if value.is_none() {
+            self.invalid(key, "must be set");
+        }
+
+        value
+    }
+
+    fn parsed<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.parsed_optional(key).unwrap_or(default)
+    }
+
+    /// same as `parsed()` but a zero or negative value is reported as invalid too. Only the set
+    /// value is checked so a setting defaulting to another one doesn't repeat its error
+    fn positive<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr + PartialOrd + Default,
+        T::Err: std::fmt::Display,
+    {
+        match self.parsed_optional(key) {
+            Some(value) if value <= T::default() => {
+                self.invalid(key, "must be greater than 0");
+                value
+            }
+            Some(value) => value,
+            None => default,
+        }
+    }
+
+    /// same as `positive()` but a value over `max` is reported as invalid too
+    fn positive_at_most<T>(&mut self, key: &str, default: T, max: T) -> T
+    where
+        T: FromStr + PartialOrd + Default + std::fmt::Display,
+        T::Err: std::fmt::Display,
+    {
+        let value = self.positive(key, default);
+
+        // an unset key takes the default, which is checked where it was read
+        if value > max && self.optional(key).is_some() {
+            self.invalid(key, format!("must be at most {}", max));
+        }
+
+        value
+    }
+
+    /// same as `parsed()` but an unparseable value falls back to `default` instead of being
+    /// reported as invalid. The effective value still shows up in `AppConfig::snapshot()`
+    fn parsed_or_default<T>(&self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+    {
+        self.optional(key)
+            .and_then(|value| value.parse::<T>().ok())
+            .unwrap_or(default)
+    }
+
+    fn parsed_optional<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        match self.optional(key).map(|value| value.parse::<T>()) {
+            Some(Ok(value)) => Some(value),
+            Some(Err(e)) => {
+                self.invalid(key, e);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn invalid<E>(&mut self, key: &str, reason: E)
+    where
+        E: std::fmt::Display,
+    {
+        self.errors.push(format!("{}: {}", key, reason));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_rejected(key: &str, value: &str) {
+        match AppConfig::try_for_test(&[(key, value)]) {
+            Err(ServiceError::InvalidConfig(errors)) => {
+                assert_eq!(errors, vec![format!("{}: must be greater than 0", key)])
+            }
+            other => panic!(
+                "expect {}={} to be rejected, got {:?}",
+                key,
+                value,
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn zero_aggregate_min_interval_is_rejected() {
+        assert_rejected("AGGREGATE_MIN_INTERVAL_MS", "0");
+        assert_eq!(
+            AppConfig::for_test(&[("AGGREGATE_MIN_INTERVAL_MS", "1")]).aggregate_min_interval,
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn zero_health_check_interval_is_rejected() {
+        assert_rejected("HEALTH_CHECK_INTERVAL_SECONDS", "0");
+    }
+
+    #[test]
+    fn zero_min_event_delay_is_rejected() {
+        assert_rejected("MIN_EVENT_DELAY_MS", "0");
+    }
+
+    #[test]
+    fn non_positive_session_ttl_is_rejected() {
+        assert_rejected("SESSION_TTL_SECONDS", "0");
+        assert_rejected("SESSION_TTL_SECONDS", "-1");
+    }
+
+    #[test]
+    fn non_positive_session_read_ttl_is_rejected() {
+        assert_rejected("SESSION_READ_TTL", "0");
+        assert_rejected("SESSION_READ_TTL", "-1");
+    }
+
+    #[test]
+    fn non_positive_session_write_ttl_is_rejected() {
+        assert_rejected("SESSION_WRITE_TTL", "0");
+        assert_rejected("SESSION_WRITE_TTL", "-1");
+    }
+
+    #[test]
+    fn session_ttls_over_the_maximum_are_rejected() {
+        let over = (MAX_SESSION_TTL_SECONDS + 1).to_string();
+
+        for key in [
+            "SESSION_TTL_SECONDS",
+            "SESSION_READ_TTL",
+            "SESSION_WRITE_TTL",
+        ] {
+            match AppConfig::try_for_test(&[(key, &over)]) {
+                Err(ServiceError::InvalidConfig(errors)) => assert_eq!(
+                    errors,
+                    vec![format!(
+                        "{}: must be at most {}",
+                        key, MAX_SESSION_TTL_SECONDS
+                    )]
+                ),
+                other => panic!("expect {} to be rejected, got {:?}", key, other.map(|_| ())),
+            }
+        }
+
+        let config =
+            AppConfig::for_test(&[("SESSION_TTL_SECONDS", &MAX_SESSION_TTL_SECONDS.to_string())]);
+        assert_eq!(
+            config.session_read_ttl.whole_seconds(),
+            MAX_SESSION_TTL_SECONDS
+        );
+        assert_eq!(
+            config.session_write_ttl.whole_seconds(),
+            MAX_SESSION_TTL_SECONDS
+        );
+    }
+}
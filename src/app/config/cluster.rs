@@ -0,0 +1,141 @@
+use super::{
+    app::AppConfig,
+    listener::{bind_listener, tcp_incoming, BindError},
+    subscriber::init_tracing,
+};
+use std::sync::OnceLock;
+use tonic::transport::{server::Router, Channel};
+use tracing_appender::non_blocking::WorkerGuard;
+
+/// guard of the tracing subscriber shared by every test cluster of the test binary
+static TRACING_GUARD: OnceLock<WorkerGuard> = OnceLock::new();
+
+/// config of a test cluster. Only the tracing setup read it, nothing connect to redis
+fn test_config() -> AppConfig {
+    let log_dir = std::env::temp_dir().join(concat!(env!("CARGO_PKG_NAME"), "-test-cluster"));
+
+    AppConfig::for_test(&[
+        ("RUST_LOG", "warn"),
+        // the harness does not capture the non-blocking writer, keep the logs out of its output
+        ("LOG_TARGET", "file"),
+        ("LOG_DIR", &log_dir.display().to_string()),
+    ])
+}
+
+/// serve each of `services` as its own in-process tonic server on the current runtime and return
+/// a channel connected to each server in the same order. Every listener is bound before any
+/// service is built so each service receive the channels of the whole cluster and can call the
+/// other instances. The servers run until the runtime shut down.
+///
+/// The tracing subscriber is installed by the first cluster of the process, later calls reuse it
+/// since `init_tracing()` return an error instead of panicking once it is set
+pub async fn spawn_test_cluster<F>(services: Vec<F>) -> Result<Vec<Channel>, BindError>
+where
+    F: FnOnce(Vec<Channel>) -> Router,
+{
+    if let Ok(guard) = init_tracing(
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        &test_config(),
+    ) {
+        let _ = TRACING_GUARD.set(guard);
+    }
+
+    let mut listeners = Vec::with_capacity(services.len());
+    let mut channels = Vec::with_capacity(services.len());
+    for _ in &services {
+        let listener = bind_listener(([127, 0, 0, 1], 0).into()).await?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| BindError::Io(([127, 0, 0, 1], 0).into(), e))?;
+
+        // lazy so a service may be built with the channel of a server not serving yet
+        channels.push(
+            Channel::from_shared(format!("http://{}", addr))
+                .expect("expect a socket address to be a valid uri")
+                .connect_lazy(),
+        );
+        listeners.push(listener);
+    }
+
+    for (service, listener) in services.into_iter().zip(listeners) {
+        let incoming = tcp_incoming(listener, None)?;
+        tokio::spawn(service(channels.clone()).serve_with_incoming(incoming));
+    }
+
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::BoxStream;
+    use tonic::{transport::Server, Request, Response, Status};
+    use tonic_health::proto::{
+        health_check_response::ServingStatus,
+        health_client::HealthClient,
+        health_server::{Health, HealthServer},
+        HealthCheckRequest, HealthCheckResponse,
+    };
+
+    /// answer `pong` itself and forward `ping` to its peer as `pong`
+    struct StubHealth {
+        peer: Channel,
+    }
+
+    #[tonic::async_trait]
+    impl Health for StubHealth {
+        async fn check(
+            &self,
+            request: Request<HealthCheckRequest>,
+        ) -> Result<Response<HealthCheckResponse>, Status> {
+            match request.into_inner().service.as_str() {
+                "ping" => {
+                    HealthClient::new(self.peer.clone())
+                        .check(HealthCheckRequest {
+                            service: "pong".to_string(),
+                        })
+                        .await
+                }
+                "pong" => Ok(Response::new(HealthCheckResponse {
+                    status: ServingStatus::Serving as i32,
+                })),
+                service => Err(Status::not_found(service)),
+            }
+        }
+
+        type WatchStream = BoxStream<'static, Result<HealthCheckResponse, Status>>;
+
+        async fn watch(
+            &self,
+            _: Request<HealthCheckRequest>,
+        ) -> Result<Response<Self::WatchStream>, Status> {
+            Err(Status::unimplemented("watch"))
+        }
+    }
+
+    /// stub service of the instance `index` calling the other instance of a two server cluster
+    fn stub(index: usize) -> impl FnOnce(Vec<Channel>) -> Router {
+        move |channels| {
+            Server::builder().add_service(HealthServer::new(StubHealth {
+                peer: channels[1 - index].clone(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn stub_services_call_each_other() {
+        let channels = spawn_test_cluster(vec![stub(0), stub(1)]).await.unwrap();
+
+        for channel in channels {
+            let response = HealthClient::new(channel)
+                .check(HealthCheckRequest {
+                    service: "ping".to_string(),
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(response.into_inner().status, ServingStatus::Serving as i32);
+        }
+    }
+}
@@ -1,10 +1,131 @@
-use crate::REDIS_URL;
-use redis::{aio::ConnectionManager, Client};
+use redis::{
+    aio::{ConnectionLike, ConnectionManager},
+    Client, Cmd, ErrorKind, Pipeline, RedisFuture, RedisResult, Value,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{info, warn};
 
-pub async fn init_redis() -> ConnectionManager {
-    let redis_url = &*REDIS_URL.clone();
+#[derive(Clone)]
+/// redis connection shared by every request. Both variants implement `ConnectionLike` so commands
+/// are issued with `query_async(&mut redis_pool)` regardless of the deployment mode.
+///
+/// In cluster mode every key of a multi-key command or an atomic pipeline must hash to the same
+/// slot
+pub enum RedisPool {
+    Single(ConnectionManager),
+    Cluster(redis_cluster_async::Connection),
+}
+
+impl ConnectionLike for RedisPool {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisPool::Single(connection) => connection.req_packed_command(cmd),
+            RedisPool::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisPool::Single(connection) => connection.req_packed_commands(cmd, offset, count),
+            RedisPool::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisPool::Single(connection) => connection.get_db(),
+            RedisPool::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+/// delay before the first connection retry, doubled after every failed attempt
+const CONNECT_BACKOFF_BASE: Duration = Duration::from_millis(250);
+/// upper bound of the delay between two connection attempts
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// connect to redis. When `cluster` is set `redis_url` is a comma separated list of the initial
+/// cluster nodes, otherwise it is the url of a single node.
+///
+/// A failed connection is retried up to `connect_retries` times with an exponential backoff so
+/// the application survive starting alongside redis. An invalid url is not retried. Return the
+/// last error once every attempt failed
+pub async fn init_redis(
+    redis_url: &str,
+    cluster: bool,
+    connect_retries: u32,
+) -> RedisResult<RedisPool> {
+    let mut backoff = CONNECT_BACKOFF_BASE;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match connect_redis(redis_url, cluster).await {
+            Ok(redis_pool) => {
+                if attempt > 1 {
+                    info!("connected to redis after {} attempt(s)", attempt);
+                }
+
+                return Ok(redis_pool);
+            }
+            Err(e) if attempt <= connect_retries && e.kind() != ErrorKind::InvalidClientConfig => {
+                warn!(
+                    "redis connection attempt {} of {} failed, retrying in {:?}: {}",
+                    attempt,
+                    connect_retries + 1,
+                    backoff,
+                    e
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(CONNECT_BACKOFF_MAX);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// open a single connection to redis, see `init_redis()` for the meaning of `cluster`
+pub async fn connect_redis(redis_url: &str, cluster: bool) -> RedisResult<RedisPool> {
+    if cluster {
+        let nodes = redis_url
+            .split(',')
+            .map(str::trim)
+            .filter(|node| !node.is_empty())
+            .collect::<Vec<_>>();
+
+        let connection = redis_cluster_async::Client::open(nodes)?
+            .get_connection()
+            .await?;
+
+        return Ok(RedisPool::Cluster(connection));
+    }
+
+    Ok(RedisPool::Single(
+        ConnectionManager::new(Client::open(redis_url)?).await?,
+    ))
+}
+
+/// pool connected to a local listener that accept connections but never answer a command. Only
+/// meant for tests of code holding a pool without reaching redis
+#[cfg(test)]
+pub async fn test_redis_pool() -> RedisPool {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redis_url = format!("redis://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let mut connections = vec![];
+
+        while let Ok((connection, _)) = listener.accept().await {
+            connections.push(connection);
+        }
+    });
 
-    ConnectionManager::new(Client::open(redis_url).expect("A valid redis connection"))
-        .await
-        .expect("A valid connection manager")
+    connect_redis(&redis_url, false).await.unwrap()
 }
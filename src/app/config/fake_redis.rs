@@ -0,0 +1,389 @@
+use super::database::{connect_redis, RedisPool};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(Vec<u8>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(error) => out.extend(format!("-{}\r\n", error).as_bytes()),
+            Reply::Integer(integer) => out.extend(format!(":{}\r\n", integer).as_bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(bulk)) => {
+                out.extend(format!("${}\r\n", bulk.len()).as_bytes());
+                out.extend(bulk);
+                out.extend(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                out.extend(format!("*{}\r\n", replies.len()).as_bytes());
+                replies.iter().for_each(|reply| reply.encode(out));
+            }
+        }
+    }
+}
+
+/// in-process redis server speaking just enough RESP for the commands this crate issue (strings
+/// with expiry, `HMGET`/`HSET`, `INCR`/`INCRBY` and `MULTI`/`EXEC` pipelines). The keyspace and the name of
+/// every command received are shared with the test through the `FakeRedis` handle so it can seed
+/// and inspect records
+#[derive(Debug, Clone, Default)]
+pub struct FakeRedis {
+    keyspace: Arc<Mutex<HashMap<Vec<u8>, Entry>>>,
+    commands: Arc<Mutex<Vec<String>>>,
+    /// answer `GETEX` as an unknown command like redis older than 6.2
+    legacy: bool,
+}
+
+impl FakeRedis {
+    /// start a server on a local port and return its handle along with a pool connected to it
+    pub async fn start() -> (FakeRedis, RedisPool) {
+        FakeRedis::default().listen().await
+    }
+
+    /// same as `FakeRedis::start()` but the server does not support `GETEX`
+    pub async fn start_legacy() -> (FakeRedis, RedisPool) {
+        FakeRedis {
+            legacy: true,
+            ..Default::default()
+        }
+        .listen()
+        .await
+    }
+
+    async fn listen(self) -> (FakeRedis, RedisPool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let redis_url = format!("redis://{}", listener.local_addr().unwrap());
+
+        tokio::spawn({
+            let fake_redis = self.clone();
+
+            async move {
+                while let Ok((connection, _)) = listener.accept().await {
+                    tokio::spawn(fake_redis.clone().serve(connection));
+                }
+            }
+        });
+
+        (self, connect_redis(&redis_url, false).await.unwrap())
+    }
+
+    /// string value of `key` if it exist and did not expire
+    pub fn get(&self, key: &str) -> Option<String> {
+        match self.lookup(key.as_bytes()) {
+            Some(Value::String(value)) => Some(String::from_utf8(value).unwrap()),
+            _ => None,
+        }
+    }
+
+    /// set the string `key` to `value` without expiry
+    pub fn set(&self, key: &str, value: &str) {
+        self.command(vec![
+            b"SET".to_vec(),
+            key.as_bytes().to_vec(),
+            value.as_bytes().to_vec(),
+        ]);
+    }
+
+    /// remaining lifetime of `key`, `None` if it does not exist or has no expiry
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let mut keyspace = self.keyspace.lock().unwrap();
+
+        live(&mut keyspace, key.as_bytes())
+            .and_then(|entry| entry.expires_at)
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// name of every command received so far, in order
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// set `fields` of the hash `key`
+    pub fn hset(&self, key: &str, fields: &[(&str, &str)]) {
+        self.command(
+            [b"HSET".to_vec(), key.as_bytes().to_vec()]
+                .into_iter()
+                .chain(fields.iter().flat_map(|(field, value)| {
+                    [field.as_bytes().to_vec(), value.as_bytes().to_vec()]
+                }))
+                .collect(),
+        );
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<Value> {
+        let mut keyspace = self.keyspace.lock().unwrap();
+
+        live(&mut keyspace, key).map(|entry| entry.value.clone())
+    }
+
+    async fn serve(self, connection: TcpStream) {
+        let mut connection = BufReader::new(connection);
+        let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+
+        while let Some(args) = read_command(&mut connection).await {
+            let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+
+            self.commands.lock().unwrap().push(name.clone());
+
+            let reply = match (name.as_str(), &mut queued) {
+                ("MULTI", None) => {
+                    queued = Some(vec![]);
+                    Reply::Status("OK")
+                }
+                ("EXEC", Some(_)) => Reply::Array(
+                    queued
+                        .take()
+                        .unwrap()
+                        .into_iter()
+                        .map(|args| self.command(args))
+                        .collect(),
+                ),
+                (_, Some(commands)) => {
+                    commands.push(args);
+                    Reply::Status("QUEUED")
+                }
+                (_, None) => self.command(args),
+            };
+            let mut out = vec![];
+
+            reply.encode(&mut out);
+
+            if connection.get_mut().write_all(&out).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn command(&self, args: Vec<Vec<u8>>) -> Reply {
+        let mut keyspace = self.keyspace.lock().unwrap();
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        let integer = |arg: &[u8]| String::from_utf8_lossy(arg).parse::<i64>().unwrap();
+        let expiry = |seconds: i64| Some(Instant::now() + Duration::from_secs(seconds as u64));
+
+        match name.as_str() {
+            "PING" => Reply::Status("PONG"),
+            "GET" => match live(&mut keyspace, &args[0]) {
+                Some(Entry {
+                    value: Value::String(value),
+                    ..
+                }) => Reply::Bulk(Some(value.clone())),
+                Some(_) => Reply::Error("WRONGTYPE".to_string()),
+                None => Reply::Bulk(None),
+            },
+            "MGET" => Reply::Array(
+                args.iter()
+                    .map(|key| match live(&mut keyspace, key) {
+                        Some(Entry {
+                            value: Value::String(value),
+                            ..
+                        }) => Reply::Bulk(Some(value.clone())),
+                        _ => Reply::Bulk(None),
+                    })
+                    .collect(),
+            ),
+            "GETEX" if !self.legacy => match live(&mut keyspace, &args[0]) {
+                Some(entry) => {
+                    if args.len() == 3 {
+                        entry.expires_at = expiry(integer(&args[2]));
+                    }
+
+                    match &entry.value {
+                        Value::String(value) => Reply::Bulk(Some(value.clone())),
+                        Value::Hash(_) => Reply::Error("WRONGTYPE".to_string()),
+                    }
+                }
+                None => Reply::Bulk(None),
+            },
+            "SET" => {
+                let options = args[2..]
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg).to_ascii_uppercase())
+                    .collect::<Vec<_>>();
+                let expires_at = options
+                    .iter()
+                    .position(|option| option == "EX")
+                    .and_then(|i| expiry(integer(&args[3 + i])));
+
+                if options.iter().any(|option| option == "NX")
+                    && live(&mut keyspace, &args[0]).is_some()
+                {
+                    return Reply::Bulk(None);
+                }
+
+                keyspace.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::String(args[1].clone()),
+                        expires_at,
+                    },
+                );
+
+                Reply::Status("OK")
+            }
+            "SETEX" => {
+                keyspace.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::String(args[2].clone()),
+                        expires_at: expiry(integer(&args[1])),
+                    },
+                );
+
+                Reply::Status("OK")
+            }
+            "DEL" => {
+                let mut deleted = 0;
+
+                for key in args {
+                    if live(&mut keyspace, key).is_some() {
+                        keyspace.remove(key);
+                        deleted += 1;
+                    }
+                }
+
+                Reply::Integer(deleted)
+            }
+            "EXPIRE" => match live(&mut keyspace, &args[0]) {
+                Some(entry) => {
+                    entry.expires_at = expiry(integer(&args[1]));
+                    Reply::Integer(1)
+                }
+                None => Reply::Integer(0),
+            },
+            "TTL" => match live(&mut keyspace, &args[0]) {
+                Some(Entry {
+                    expires_at: Some(expires_at),
+                    ..
+                }) => Reply::Integer(
+                    expires_at
+                        .saturating_duration_since(Instant::now())
+                        .as_secs_f64()
+                        .round() as i64,
+                ),
+                Some(_) => Reply::Integer(-1),
+                None => Reply::Integer(-2),
+            },
+            "INCR" | "INCRBY" => {
+                let increment = args.get(1).map_or(1, |increment| integer(increment));
+                let entry = live(&mut keyspace, &args[0]).map(|entry| entry.value.clone());
+                let (value, expires_at) = match entry {
+                    Some(Value::String(value)) => (
+                        integer(&value) + increment,
+                        keyspace.get(&args[0]).and_then(|entry| entry.expires_at),
+                    ),
+                    Some(Value::Hash(_)) => return Reply::Error("WRONGTYPE".to_string()),
+                    None => (increment, None),
+                };
+
+                keyspace.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::String(value.to_string().into_bytes()),
+                        expires_at,
+                    },
+                );
+
+                Reply::Integer(value)
+            }
+            "HSET" => {
+                let entry = keyspace.entry(args[0].clone()).or_insert(Entry {
+                    value: Value::Hash(HashMap::new()),
+                    expires_at: None,
+                });
+
+                match &mut entry.value {
+                    Value::Hash(hash) => Reply::Integer(
+                        args[1..]
+                            .chunks(2)
+                            .filter(|field| {
+                                hash.insert(field[0].clone(), field[1].clone()).is_none()
+                            })
+                            .count() as i64,
+                    ),
+                    Value::String(_) => Reply::Error("WRONGTYPE".to_string()),
+                }
+            }
+            "HMGET" => match live(&mut keyspace, &args[0]).map(|entry| &entry.value) {
+                Some(Value::Hash(hash)) => Reply::Array(
+                    args[1..]
+                        .iter()
+                        .map(|field| Reply::Bulk(hash.get(field).cloned()))
+                        .collect(),
+                ),
+                Some(Value::String(_)) => Reply::Error("WRONGTYPE".to_string()),
+                None => Reply::Array(args[1..].iter().map(|_| Reply::Bulk(None)).collect()),
+            },
+            name => Reply::Error(format!("ERR unknown command '{}'", name)),
+        }
+    }
+}
+
+/// the entry of `key` unless it expired, in which case it is evicted
+fn live<'a>(keyspace: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a mut Entry> {
+    let expired = keyspace
+        .get(key)
+        .and_then(|entry| entry.expires_at)
+        .is_some_and(|expires_at| expires_at <= Instant::now());
+
+    if expired {
+        keyspace.remove(key);
+    }
+
+    keyspace.get_mut(key)
+}
+
+/// read a command sent as an array of bulk strings, `None` once the client disconnected
+async fn read_command(connection: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let count = read_header(connection, b'*').await?;
+    let mut args = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let len = read_header(connection, b'$').await?;
+        let mut arg = vec![0; len + 2];
+
+        connection.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Some(args)
+}
+
+async fn read_header(connection: &mut BufReader<TcpStream>, prefix: u8) -> Option<usize> {
+    let mut line = String::new();
+
+    connection.read_line(&mut line).await.ok()?;
+
+    match line.as_bytes().first() {
+        Some(first) if *first == prefix => line[1..].trim_end().parse().ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,194 @@
+#[cfg(feature = "amqp")]
+use super::amqp::connect_amqp;
+use super::{
+    app::AppConfig,
+    database::{connect_redis, RedisPool},
+    task::spawn_with_name,
+};
+use crate::app::util::{error::ServiceError, redis::redis_with_timeout};
+use std::{future::Future, time::Duration};
+use tokio::time::timeout;
+use tonic::transport::NamedService;
+use tonic_health::server::HealthReporter;
+use tracing::{info, info_span, warn};
+use tracing_futures::Instrument;
+
+/// upper bound of a single dependency check, including opening the connection
+const DEPENDENCY_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// check that every external dependency is reachable before serving: redis answer `PING` and the
+/// AMQP broker accept a connection when `AMQP_ADDRESS` is set (only with the `amqp` feature).
+/// The checks run concurrently on their own short lived connections and every failure is
+/// collected into a single `ServiceError::DependencyUnavailable`
+pub async fn verify_dependencies(config: &AppConfig) -> Result<(), ServiceError> {
+    let redis = check("redis", ping_redis(config));
+    #[cfg(feature = "amqp")]
+    let amqp = async {
+        match &config.amqp_address {
+            Some(amqp_address) => Some(check("amqp", open_amqp(amqp_address)).await),
+            None => None,
+        }
+    };
+    #[cfg(not(feature = "amqp"))]
+    let amqp = async { None::<Result<(), String>> };
+
+    let (redis, amqp) = tokio::join!(redis, amqp);
+    let errors = std::iter::once(redis)
+        .chain(amqp)
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        info!("every dependency is reachable");
+        Ok(())
+    } else {
+        Err(ServiceError::DependencyUnavailable(errors))
+    }
+}
+
+/// run a dependency check bounded by `DEPENDENCY_CHECK_TIMEOUT`, describing a failure as
+/// `<name>: <reason>`
+async fn check<F>(name: &str, operation: F) -> Result<(), String>
+where
+    F: Future<Output = Result<(), ServiceError>>,
+{
+    match timeout(DEPENDENCY_CHECK_TIMEOUT, operation).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("{}: {}", name, e)),
+        Err(_) => Err(format!(
+            "{}: no answer within {:?}",
+            name, DEPENDENCY_CHECK_TIMEOUT
+        )),
+    }
+}
+
+async fn ping_redis(config: &AppConfig) -> Result<(), ServiceError> {
+    let mut connection = connect_redis(&config.redis_url, config.redis_cluster).await?;
+
+    redis_with_timeout(redis::cmd("PING").query_async::<_, String>(&mut connection)).await?;
+
+    Ok(())
+}
+
+/// report the health of the service `S` through `health_reporter`. The service starts serving and
+/// a background task periodically ping redis to flip the status so load balancers can route away
+/// from instances with a broken dependency. `FORCE_HEALTH_NOT_SERVING` pins the status to not
+/// serving instead and no check is run
+pub async fn report_health<S: NamedService>(
+    mut health_reporter: HealthReporter,
+    config: &AppConfig,
+    mut redis_pool: RedisPool,
+) {
+    if config.force_health_not_serving {
+        // keep the instance out of load balancer rotation (e.g. during canary analysis)
+        // regardless of the actual backend health
+        warn!(
+            "FORCE_HEALTH_NOT_SERVING is set, reporting not serving regardless of backend health"
+        );
+        health_reporter.set_not_serving::<S>().await;
+
+        return;
+    }
+
+    health_reporter.set_serving::<S>().await;
+
+    let root_span = info_span!("redis health check");
+    let health_check_interval = config.health_check_interval;
+
+    spawn_with_name(
+        async move {
+            let mut interval = tokio::time::interval(health_check_interval);
+            let mut serving = true;
+
+            loop {
+                interval.tick().await;
+
+                let ping = redis_with_timeout(
+                    redis::cmd("PING").query_async::<_, String>(&mut redis_pool),
+                )
+                .await;
+
+                match (ping, serving) {
+                    (Ok(_), false) => {
+                        info!("redis is reachable again, marking service as serving");
+                        health_reporter.set_serving::<S>().await;
+                        serving = true;
+                    }
+                    (Err(e), true) => {
+                        warn!(
+                            "redis health check failed, marking service as not serving: {}",
+                            e
+                        );
+                        health_reporter.set_not_serving::<S>().await;
+                        serving = false;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        .instrument(root_span),
+        "redis health check",
+    );
+}
+
+#[cfg(feature = "amqp")]
+async fn open_amqp(amqp_address: &str) -> Result<(), ServiceError> {
+    let connection = connect_amqp(amqp_address).await?;
+
+    connection.close(0, "dependency check").await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::{cluster::spawn_test_cluster, fake_redis::FakeRedis};
+    use tonic::transport::Server;
+    use tonic_health::proto::{
+        health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+    };
+
+    struct Probe;
+
+    impl NamedService for Probe {
+        const NAME: &'static str = "test.Probe";
+    }
+
+    /// status of `Probe` reported by a health service fed by `report_health()` once the redis
+    /// health check had the time to run a few times
+    async fn reported_status(vars: &[(&str, &str)]) -> ServingStatus {
+        let (_fake_redis, redis_pool) = FakeRedis::start().await;
+        let (health_reporter, health_service) = tonic_health::server::health_reporter();
+
+        report_health::<Probe>(health_reporter, &AppConfig::for_test(vars), redis_pool).await;
+
+        let channels = spawn_test_cluster(vec![|_| Server::builder().add_service(health_service)])
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let response = HealthClient::new(channels[0].clone())
+            .check(HealthCheckRequest {
+                service: Probe::NAME.to_string(),
+            })
+            .await
+            .unwrap();
+
+        response.into_inner().status()
+    }
+
+    #[tokio::test]
+    async fn healthy_redis_is_serving() {
+        assert_eq!(reported_status(&[]).await, ServingStatus::Serving);
+    }
+
+    #[tokio::test]
+    async fn forced_not_serving_overrides_a_healthy_redis() {
+        assert_eq!(
+            reported_status(&[("FORCE_HEALTH_NOT_SERVING", "true")]).await,
+            ServingStatus::NotServing
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use futures::Stream;
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+#[cfg(unix)]
+use std::path::Path;
+use std::{io, net::SocketAddr, path::PathBuf, pin::Pin, time::Duration};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BindError {
+    #[error("address {0} is already in use, check APP_URL and APP_PORT or stop the other process")]
+    AddrInUse(SocketAddr),
+    #[error("failed to bind {0}: {1}")]
+    Io(SocketAddr, io::Error),
+    #[cfg(unix)]
+    #[error("socket {} is already in use, check UDS_PATH or stop the other process", .0.display())]
+    SocketInUse(PathBuf),
+    #[cfg(unix)]
+    #[error("failed to bind socket {}: {1}", .0.display())]
+    SocketIo(PathBuf, io::Error),
+    #[cfg(not(unix))]
+    #[error("socket {} can not be bound, unix domain sockets are only supported on unix", .0.display())]
+    SocketUnsupported(PathBuf),
+}
+
+impl BindError {
+    fn new(addr: SocketAddr, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::AddrInUse => BindError::AddrInUse(addr),
+            _ => BindError::Io(addr, error),
+        }
+    }
+}
+
+/// listener the server accept its connections on
+pub enum ServerListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// bind the server listener up front so a taken address is reported as a `BindError` naming
+/// the address instead of an opaque failure once the server is already being served
+pub async fn bind_listener(addr: SocketAddr) -> Result<TcpListener, BindError> {
+    TcpListener::bind(addr)
+        .await
+        .map_err(|e| BindError::new(addr, e))
+}
+
+/// bind a unix domain socket at `path`. A socket file left behind by a previous process that
+/// no longer accept connections is replaced, a live one is reported as `BindError::SocketInUse`
+#[cfg(unix)]
+pub fn bind_unix_listener(path: &Path) -> Result<UnixListener, BindError> {
+    let error = |e: io::Error| BindError::SocketIo(path.to_path_buf(), e);
+
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => Err(BindError::SocketInUse(path.to_path_buf())),
+                Err(_) => {
+                    std::fs::remove_file(path).map_err(error)?;
+                    UnixListener::bind(path).map_err(error)
+                }
+            }
+        }
+        listener => listener.map_err(error),
+    }
+}
+
+/// incoming connections accepted by the unix domain socket `listener`
+#[cfg(unix)]
+pub fn unix_incoming(listener: UnixListener) -> impl Stream<Item = io::Result<UnixStream>> {
+    futures::stream::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    })
+}
+
+/// plain TCP incoming connections accepted by `listener` with the same socket options tonic
+/// apply when it bind the address on its own
+pub fn tcp_incoming(
+    listener: TcpListener,
+    keepalive: Option<Duration>,
+) -> Result<impl Stream<Item = io::Result<AddrStream>>, BindError> {
+    let addr = listener
+        .local_addr()
+        .map_err(|e| BindError::Io(([0, 0, 0, 0], 0).into(), e))?;
+    let mut incoming = AddrIncoming::from_listener(listener)
+        .map_err(|e| BindError::Io(addr, io::Error::other(e)))?;
+    incoming.set_nodelay(true);
+    incoming.set_keepalive(keepalive);
+
+    Ok(futures::stream::poll_fn(move |cx| {
+        Pin::new(&mut incoming).poll_accept(cx)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bound_address_is_addr_in_use() {
+        let listener = bind_listener(([127, 0, 0, 1], 0).into()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let error = bind_listener(addr).await.unwrap_err();
+
+        assert!(matches!(error, BindError::AddrInUse(in_use) if in_use == addr));
+        assert!(error.to_string().contains(&addr.to_string()), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn live_socket_is_in_use_and_a_stale_one_is_replaced() {
+        let path = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+        let listener = bind_unix_listener(&path).unwrap();
+        let error = bind_unix_listener(&path).unwrap_err();
+
+        assert!(matches!(&error, BindError::SocketInUse(in_use) if *in_use == path));
+
+        // the socket file outlive its listener like after a crash
+        drop(listener);
+
+        let listener = bind_unix_listener(&path).unwrap();
+
+        assert!(UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use crate::app::util::{metrics::REQUEST_METRICS, shutdown::ShutdownSignal};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tracing::{error, info};
+
+/// serve the prometheus `/metrics` endpoint on `addr` until the shutdown signal is triggered
+pub async fn serve_metrics(addr: SocketAddr, shutdown_signal_notifier: Arc<ShutdownSignal>) {
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(metrics_handler)) });
+
+    info!("serving prometheus metrics on {}", addr);
+
+    if let Err(e) = Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown_signal_notifier.notified().await })
+        .await
+    {
+        error!("metrics server failed: {:?}", e);
+    }
+}
+
+async fn metrics_handler(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => match REQUEST_METRICS.encode() {
+            Ok(metrics) => Response::builder()
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics)),
+            Err(e) => {
+                error!("failed to encode metrics: {:?}", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            }
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.unwrap_or_default())
+}
@@ -1,2 +1,16 @@
 pub mod task;
 pub mod database;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod subscriber;
+pub mod app;
+pub mod metrics;
+pub mod tls;
+pub mod listener;
+#[cfg(feature = "grpc-web")]
+pub mod web;
+pub mod healthcheck;
+#[cfg(test)]
+pub mod cluster;
+#[cfg(test)]
+pub mod fake_redis;
@@ -0,0 +1,197 @@
+use super::app::AppConfig;
+use sentry_tracing::EventFilter;
+use std::{path::PathBuf, str::FromStr};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_log::LogTracer;
+use tracing_subscriber::{
+    layer::SubscriberExt,
+    {EnvFilter, Registry},
+};
+
+/// directive of the tracing env filter used when `RUST_LOG` is not set
+pub const LOG_LEVEL: &str = "INFO";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// destination of the bunyan formatted logs
+pub enum LogTarget {
+    #[default]
+    Stdout,
+    /// rolling files under `AppConfig::log_dir`
+    File,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target.trim().to_ascii_lowercase().as_str() {
+            "stdout" => Ok(LogTarget::Stdout),
+            "file" => Ok(LogTarget::File),
+            target => Err(format!("expect either stdout or file but got {}", target)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// how often a new log file is started when logging to files
+pub enum LogRotation {
+    #[default]
+    Hourly,
+    Daily,
+    /// a single file growing forever
+    Never,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(rotation: &str) -> Result<Self, Self::Err> {
+        match rotation.trim().to_ascii_lowercase().as_str() {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            "never" => Ok(LogRotation::Never),
+            rotation => Err(format!(
+                "expect one of hourly, daily or never but got {}",
+                rotation
+            )),
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TracingInitError {
+    #[error(transparent)]
+    LogTracer(#[from] tracing_log::log::SetLoggerError),
+    #[error(transparent)]
+    SetGlobalDefault(#[from] tracing::subscriber::SetGlobalDefaultError),
+    #[error("failed to create the log directory {}: {1}", .0.display())]
+    LogDir(PathBuf, std::io::Error),
+    #[cfg(feature = "otlp")]
+    #[error(transparent)]
+    Otlp(#[from] opentelemetry::trace::TraceError),
+}
+
+/// batch exporter sending every span to the OTLP collector listening on `endpoint` (gRPC).
+/// Must be called within a tokio runtime
+#[cfg(feature = "otlp")]
+fn otlp_tracer(
+    name: &str,
+    version: &str,
+    endpoint: &str,
+) -> Result<opentelemetry::sdk::trace::Tracer, opentelemetry::trace::TraceError> {
+    use opentelemetry::{sdk::trace, sdk::Resource, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([
+            KeyValue::new("service.name", name.to_string()),
+            KeyValue::new("service.version", version.to_string()),
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+/// install the `log -> tracing` converter and the global tracing subscriber. Calling this function
+/// again after the subscriber was already set return an error instead of panicking.
+///
+/// The returned guard flush the non-blocking writer when dropped and must be held for as long as
+/// the application is logging. Logs are written to stdout or to rolling files depending on
+/// `AppConfig::log_target`. The subscriber is filtered with `AppConfig::log_filter`. When the
+/// `otlp` feature is enabled and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are also exported
+/// over OTLP (see `shutdown_tracing()`)
+pub fn init_tracing(
+    name: &str,
+    version: &str,
+    config: &AppConfig,
+) -> Result<WorkerGuard, TracingInitError> {
+    // install `log -> tracing` converter
+    LogTracer::init()?;
+
+    let (non_blocking_writer, non_blocking_writer_guard) = match config.log_target {
+        LogTarget::Stdout => tracing_appender::non_blocking(std::io::stdout()),
+        LogTarget::File => {
+            // the appender panics when it cannot open its first file
+            std::fs::create_dir_all(&config.log_dir)
+                .map_err(|e| TracingInitError::LogDir(config.log_dir.clone(), e))?;
+
+            tracing_appender::non_blocking(RollingFileAppender::new(
+                config.log_rotation.into(),
+                &config.log_dir,
+                concat!(env!("CARGO_PKG_NAME"), ".log"),
+            ))
+        }
+    };
+
+    let bunyan_formatting_layer =
+        BunyanFormattingLayer::new(format!("{}-{}", name, version), non_blocking_writer);
+    let sentry_layer = sentry_tracing::layer().event_filter(|md| match *md.level() {
+        tracing::Level::ERROR | tracing::Level::WARN => EventFilter::Breadcrumb,
+        _ => EventFilter::Ignore,
+    });
+
+    let filter_layer = EnvFilter::new(&config.log_filter);
+    let subscriber = Registry::default()
+        .with(filter_layer)
+        .with(JsonStorageLayer)
+        .with(bunyan_formatting_layer)
+        .with(sentry_layer);
+
+    #[cfg(feature = "otlp")]
+    let subscriber = subscriber.with(
+        config
+            .otlp_endpoint
+            .as_deref()
+            .map(|endpoint| otlp_tracer(name, version, endpoint))
+            .transpose()?
+            .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)),
+    );
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    Ok(non_blocking_writer_guard)
+}
+
+/// flush the spans still buffered by the OTLP exporter. No-op without the `otlp` feature. This
+/// call block until the exporter is done thus it should run on a blocking thread
+pub fn shutdown_tracing() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_init_is_an_error() {
+        let log_dir = std::env::temp_dir().join(concat!(env!("CARGO_PKG_NAME"), "-test-tracing"));
+        let config = AppConfig::for_test(&[
+            ("LOG_TARGET", "file"),
+            ("LOG_DIR", &log_dir.display().to_string()),
+        ]);
+
+        // another test of the binary may already have installed the subscriber
+        let _guard = init_tracing("test", "0.0.0", &config);
+
+        assert!(init_tracing("test", "0.0.0", &config).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use futures::Stream;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use std::{collections::HashSet, fs::File, io, io::BufReader, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+use super::task::spawn_with_name;
+
+/// a client that did not finish the TLS handshake by then is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// accepted connections waiting to be picked up by the server
+const ACCEPT_BACKLOG: usize = 128;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsSetupError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("no private key found in TLS_KEY_PATH")]
+    MissingPrivateKey,
+    #[error(transparent)]
+    Sign(#[from] rustls::sign::SignError),
+}
+
+/// serve the same certificate to every client whose SNI hostname is allowed and abort the
+/// handshake of every other client, including the ones that did not send an SNI at all
+struct SniResolver {
+    allowed_hosts: HashSet<String>,
+    certified_key: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name() {
+            Some(host) if self.allowed_hosts.contains(&host.to_ascii_lowercase()) => {
+                Some(Arc::clone(&self.certified_key))
+            }
+            host => {
+                warn!("rejecting TLS handshake for SNI hostname: {:?}", host);
+                None
+            }
+        }
+    }
+}
+
+/// build a rustls config serving the PEM encoded certificate chain and private key only to
+/// clients asking for one of `allowed_hosts`
+pub fn sni_server_config(
+    cert_path: &str,
+    key_path: &str,
+    allowed_hosts: &[String],
+) -> Result<ServerConfig, TlsSetupError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(TlsSetupError::MissingPrivateKey)?;
+    let resolver = SniResolver {
+        allowed_hosts: allowed_hosts
+            .iter()
+            .map(|host| host.to_ascii_lowercase())
+            .collect(),
+        certified_key: Arc::new(CertifiedKey::new(certs, any_supported_type(&key)?)),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(config)
+}
+
+/// accept TCP connections on `listener` and perform the TLS handshake with `config` off the accept
+/// loop so a slow client can not stall the others. Only connections that completed the
+/// handshake are yielded
+pub fn tls_incoming(
+    listener: TcpListener,
+    config: ServerConfig,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (connection_pusher, connection_receiver) = mpsc::channel(ACCEPT_BACKLOG);
+
+    spawn_with_name(
+        async move {
+            // stop accepting once the server dropped the incoming stream
+            while !connection_pusher.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("failed to accept TCP connection: {}", e);
+                        continue;
+                    }
+                };
+                let _ = stream.set_nodelay(true);
+                let acceptor = acceptor.clone();
+                let handshake_pusher = connection_pusher.clone();
+
+                spawn_with_name(
+                    async move {
+                        match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                debug!(
+                                    "TLS handshake with {} completed for SNI hostname: {:?}",
+                                    peer,
+                                    stream.get_ref().1.sni_hostname()
+                                );
+                                let _ = handshake_pusher.send(Ok(stream)).await;
+                            }
+                            Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                            Err(_) => debug!("TLS handshake with {} timed out", peer),
+                        }
+                    },
+                    "tls handshake",
+                );
+            }
+        },
+        "tls accept loop",
+    );
+
+    ReceiverStream::new(connection_receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio::io::duplex;
+    use tokio_rustls::TlsConnector;
+
+    /// certificate of `api.example.com` and `other.example.com` issued by the test CA `ca.pem`
+    const SERVER_CERT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/app/config/testdata/server.pem"
+    );
+    const SERVER_KEY: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/app/config/testdata/server.key"
+    );
+    const CA_CERT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/app/config/testdata/ca.pem"
+    );
+
+    /// run a handshake for `host` against a server only allowing `api.example.com` and return
+    /// whether both ends completed it
+    async fn handshake(host: &str) -> bool {
+        let config =
+            sni_server_config(SERVER_CERT, SERVER_KEY, &["API.example.com".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+
+        for cert in
+            rustls_pemfile::certs(&mut BufReader::new(File::open(CA_CERT).unwrap())).unwrap()
+        {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let (client, server) = duplex(16 * 1024);
+        let (client, server) = tokio::join!(
+            connector.connect(ServerName::try_from(host).unwrap(), client),
+            TlsAcceptor::from(Arc::new(config)).accept(server),
+        );
+
+        match (client, server) {
+            (Ok(_), Ok(server)) => {
+                assert_eq!(server.get_ref().1.sni_hostname(), Some(host));
+                true
+            }
+            (Err(_), Err(_)) => false,
+            (client, server) => panic!(
+                "expect both ends to agree on the handshake outcome: {:?} {:?}",
+                client.err(),
+                server.err()
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn allowed_sni_completes_the_handshake() {
+        assert!(handshake("api.example.com").await);
+    }
+
+    #[tokio::test]
+    async fn disallowed_sni_is_rejected_at_handshake() {
+        // the certificate is valid for this host, only the SNI check reject it
+        assert!(!handshake("other.example.com").await);
+    }
+}
@@ -0,0 +1,63 @@
+use http::{header::HeaderName, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// how long browsers may cache a preflight response
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// request metadata a grpc-web client is allowed to send
+const ALLOWED_HEADERS: &[&str] = &[
+    "content-type",
+    "x-grpc-web",
+    "x-user-agent",
+    "grpc-timeout",
+    "grpc-accept-encoding",
+    "session",
+    "idempotency-key",
+    "x-admin-token",
+    "x-client-version",
+    "traceparent",
+];
+
+/// response metadata a grpc-web client is allowed to read
+const EXPOSED_HEADERS: &[&str] = &[
+    "grpc-status",
+    "grpc-message",
+    "grpc-status-details-bin",
+    "x-login-url",
+    "x-error-field",
+    "x-error-reason",
+    "x-error-from",
+    "x-error-into",
+    "x-error-expect",
+    "retry-after",
+];
+
+/// CORS policy of the grpc-web clients. Only `allowed_origins` may call the API from a browser,
+/// with credentials (the `session` cookie). A single `*` allows every origin but without
+/// credentials since browsers reject a wildcard origin on credentialed requests. No origin is
+/// allowed when the list is empty
+pub fn cors_layer(allowed_origins: &[HeaderValue]) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_methods([Method::POST])
+        .allow_headers(
+            ALLOWED_HEADERS
+                .iter()
+                .map(|name| HeaderName::from_static(name))
+                .collect::<Vec<_>>(),
+        )
+        .expose_headers(
+            EXPOSED_HEADERS
+                .iter()
+                .map(|name| HeaderName::from_static(name))
+                .collect::<Vec<_>>(),
+        )
+        .max_age(PREFLIGHT_MAX_AGE);
+
+    match allowed_origins {
+        [origin] if origin == "*" => cors.allow_origin(Any),
+        origins => cors
+            .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+            .allow_credentials(true),
+    }
+}
@@ -1,9 +1,19 @@
-use crate::app::{middleware::cookie::service::CookieSessionContainer, util::error::ServiceError};
+use crate::app::{
+    middleware::cookie::service::{CookieSessionContainer, SessionExempt},
+    util::error::ServiceError,
+};
 use tonic::{Request, Status};
 
+/// reject every call without a valid session with `Code::Unauthenticated` unless the
+/// `CookieSessionLayer` marked the method as exempt. Requires the `CookieSessionLayer` middleware
+#[allow(clippy::result_large_err)] // the signature of a tonic interceptor
 pub fn cookie_session_interceptor(req: Request<()>) -> Result<Request<()>, Status> {
     let extension = req.extensions();
 
+    if extension.get::<SessionExempt>().is_some() {
+        return Ok(req);
+    }
+
     if let Some(container) = extension.get::<CookieSessionContainer>() {
         if container.0.is_some() {
             Ok(req)
@@ -14,3 +24,85 @@ pub fn cookie_session_interceptor(req: Request<()>) -> Result<Request<()>, Statu
         Err(ServiceError::MiddlewareNotSet("cookie").into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        middleware::cookie::{layer::CookieSessionLayer, service::CookieMiddleware},
+        util::session::{MemorySessionStore, SessionPolicy, SessionStore},
+    };
+    use hyper::Body;
+    use std::sync::Arc;
+    use tonic::{codegen::InterceptedService, Code};
+    use tower::{BoxError, Service};
+    use uuid::Uuid;
+
+    const LOGIN: &str = "/test_message.TestMessageService/Login";
+    const SEND_MESSAGE: &str = "/test_message.TestMessageService/SendMessage";
+
+    /// call `path` with the `session` metadata set to `sid` through the cookie middleware and the
+    /// interceptor, `Login` being exempt. Return the code of the call, `Ok` if it reached the
+    /// service
+    async fn call(path: &str, sid: Option<&str>) -> Code {
+        let session_store = Arc::new(MemorySessionStore::new(SessionPolicy::default()));
+
+        session_store
+            .set("sid", Uuid::new_v4(), None)
+            .await
+            .unwrap();
+
+        let mut service = CookieMiddleware {
+            inner: InterceptedService::new(
+                tower::service_fn(|_: hyper::Request<Body>| async {
+                    Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+                }),
+                cookie_session_interceptor,
+            ),
+            config: CookieSessionLayer::builder(session_store)
+                .auth_exempt_methods(vec![LOGIN.to_string()])
+                .finish(),
+        };
+        let mut req = hyper::Request::post(path);
+
+        if let Some(sid) = sid {
+            req = req.header("session", sid);
+        }
+
+        match service.call(req.body(Body::empty()).unwrap()).await {
+            // a rejected call is answered with a trailers-only response
+            Ok(response) => Status::from_header_map(response.headers())
+                .map(|status| status.code())
+                .unwrap_or(Code::Ok),
+            Err(e) => e.downcast::<Status>().unwrap().code(),
+        }
+    }
+
+    #[tokio::test]
+    async fn call_without_a_session_is_unauthenticated() {
+        assert_eq!(call(SEND_MESSAGE, None).await, Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn call_with_an_expired_session_is_rejected_by_the_middleware() {
+        assert_eq!(call(SEND_MESSAGE, Some("expired")).await, Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn call_with_a_valid_session_is_served() {
+        assert_eq!(call(SEND_MESSAGE, Some("sid")).await, Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn exempt_method_is_served_without_a_session() {
+        assert_eq!(call(LOGIN, None).await, Code::Ok);
+        assert_eq!(call(LOGIN, Some("expired")).await, Code::Ok);
+    }
+
+    #[test]
+    fn call_without_the_cookie_middleware_is_internal() {
+        let status = cookie_session_interceptor(Request::new(())).unwrap_err();
+
+        assert_eq!(status.code(), Code::Internal);
+    }
+}
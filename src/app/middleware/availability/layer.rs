@@ -0,0 +1,44 @@
+use super::service::AvailabilityMiddleware;
+use std::{collections::HashSet, sync::Arc};
+use tower::Layer;
+
+/// reject every method of the listed services (fully qualified name e.g.
+/// `test_message.TestMessageService`) with `Code::Unavailable` while the other services sharing
+/// the server keep serving
+#[derive(Debug, Clone)]
+pub struct AvailabilityLayer {
+    disabled_services: Arc<HashSet<String>>,
+}
+
+impl AvailabilityLayer {
+    /// Creates a new service availability middleware.
+    pub fn new(disabled_services: Vec<String>) -> Self {
+        AvailabilityLayer {
+            disabled_services: Arc::new(disabled_services.into_iter().collect()),
+        }
+    }
+
+    /// the service part of a gRPC path i.e. `pkg.Service` of `/pkg.Service/Method`
+    pub fn service_name(path: &str) -> &str {
+        path.trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or_default()
+    }
+
+    pub fn is_disabled(&self, path: &str) -> bool {
+        self.disabled_services
+            .contains(AvailabilityLayer::service_name(path))
+    }
+}
+
+impl<S> Layer<S> for AvailabilityLayer {
+    type Service = AvailabilityMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AvailabilityMiddleware {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
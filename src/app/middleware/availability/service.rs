@@ -0,0 +1,112 @@
+use super::layer::AvailabilityLayer;
+use crate::app::util::error::ServiceError;
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+
+#[derive(Debug, Clone)]
+pub struct AvailabilityMiddleware<S> {
+    pub inner: S,
+    pub config: AvailabilityLayer,
+}
+
+impl<S> Service<hyper::Request<Body>> for AvailabilityMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let config = self.config.clone();
+
+        async move {
+            let path = req.uri().path();
+
+            if config.is_disabled(path) {
+                let service = AvailabilityLayer::service_name(path).to_string();
+
+                return Err(
+                    Box::new(Status::from(ServiceError::ServiceDisabled(service))) as BoxError,
+                );
+            }
+
+            inner.call(req).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+    use tower::{util::BoxCloneService, Layer};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    fn middleware() -> AvailabilityMiddleware<Inner> {
+        AvailabilityLayer::new(vec!["amqp_subscription.AmqpSubscriptionService".to_string()]).layer(
+            BoxCloneService::new(tower::service_fn(|_: hyper::Request<Body>| async {
+                Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+            })),
+        )
+    }
+
+    fn request(path: &str) -> hyper::Request<Body> {
+        hyper::Request::post(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn disabled_service_methods_are_unavailable() {
+        let error = middleware()
+            .call(request(
+                "/amqp_subscription.AmqpSubscriptionService/Subscribe",
+            ))
+            .await
+            .unwrap_err();
+        let status = error.downcast::<Status>().unwrap();
+
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn other_services_keep_serving() {
+        let mut middleware = middleware();
+
+        for path in [
+            "/test_message.TestMessageService/SendMessage",
+            "/grpc.health.v1.Health/Check",
+        ] {
+            middleware.call(request(path)).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn service_name_is_the_path_prefix() {
+        assert_eq!(
+            AvailabilityLayer::service_name("/test_message.TestMessageService/SendMessage"),
+            "test_message.TestMessageService"
+        );
+        assert_eq!(AvailabilityLayer::service_name("/"), "");
+    }
+}
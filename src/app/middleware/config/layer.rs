@@ -1,9 +1,20 @@
 use super::service::ConfigMiddleware;
-use redis::aio::ConnectionManager;
+use crate::app::config::database::RedisPool;
 use tower::Layer;
 
+/// inject the shared `RedisPool` into the request extensions. Every middleware reading it (the
+/// rate limit middleware) must be added after this layer in the `ServiceBuilder` so it runs
+/// inside of it, otherwise the request fails with `ServiceError::MiddlewareNotSet("config")`
 #[derive(Clone)]
-pub struct ConfigSessionLayer(pub ConnectionManager);
+pub struct ConfigSessionLayer(pub RedisPool);
+
+impl ConfigSessionLayer {
+    /// Creates a new config middleware sharing `redis_pool`. Whether redis is reachable is checked
+    /// once at startup by `verify_dependencies()`.
+    pub fn new(redis_pool: RedisPool) -> Self {
+        ConfigSessionLayer(redis_pool)
+    }
+}
 
 impl<S> Layer<S> for ConfigSessionLayer {
     type Service = ConfigMiddleware<S>;
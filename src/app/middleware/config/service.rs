@@ -1,13 +1,13 @@
+use crate::app::config::database::RedisPool;
 use futures::future::{BoxFuture, FutureExt as _};
 use hyper::Body;
-use redis::aio::ConnectionManager;
 use tonic::body::BoxBody;
 use tower::Service;
 
 #[derive(Clone)]
 pub struct ConfigMiddleware<S> {
     pub inner: S,
-    pub redis_pool: ConnectionManager,
+    pub redis_pool: RedisPool,
 }
 
 impl<S> Service<hyper::Request<Body>> for ConfigMiddleware<S>
@@ -47,3 +47,65 @@ where
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        config::fake_redis::FakeRedis,
+        middleware::{config::layer::ConfigSessionLayer, ratelimit::layer::RateLimitLayer},
+    };
+    use std::time::Duration;
+    use tonic::{Code, Status};
+    use tower::{util::BoxCloneService, BoxError, ServiceBuilder, ServiceExt};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    /// service failing unless the redis pool was injected by the time it is called
+    fn inner() -> Inner {
+        BoxCloneService::new(tower::service_fn(|req: hyper::Request<Body>| async move {
+            assert!(req.extensions().get::<RedisPool>().is_some());
+
+            Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+        }))
+    }
+
+    fn request() -> hyper::Request<Body> {
+        hyper::Request::post("/test_message.TestMessageService/SendMessage")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn code<S>(service: S) -> Code
+    where
+        S: Service<hyper::Request<Body>, Error = BoxError>,
+    {
+        match service.oneshot(request()).await {
+            Ok(_) => Code::Ok,
+            Err(e) => e.downcast::<Status>().unwrap().code(),
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_pool_is_injected_before_the_layers_added_after_it() {
+        let (_fake_redis, redis_pool) = FakeRedis::start().await;
+        let service = ServiceBuilder::new()
+            .layer(ConfigSessionLayer::new(redis_pool))
+            .layer(RateLimitLayer::new(1, Duration::from_secs(60)))
+            .service(inner());
+
+        assert_eq!(code(service.clone()).await, Code::Ok);
+        // the rate limit layer counted the first request through the injected pool
+        assert_eq!(code(service).await, Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_without_the_config_layer_is_not_set() {
+        let service = ServiceBuilder::new()
+            .layer(RateLimitLayer::new(1, Duration::from_secs(60)))
+            .service(inner());
+
+        assert_eq!(code(service).await, Code::Internal);
+    }
+}
@@ -1,13 +1,156 @@
 use super::service::CookieMiddleware;
+use crate::app::util::{credential::CookieKey, session::SessionStore, version::ClientVersion};
+use std::{collections::HashSet, sync::Arc};
 use tower::Layer;
 
+/// Methods reachable without a session by default: `Login` itself, the health checks (including
+/// `HealthCheck`) and the admin RPCs which are authorized through `x-admin-token` instead.
+pub const DEFAULT_AUTH_EXEMPT_METHODS: &[&str] = &[
+    "/test_message.TestMessageService/Login",
+    "/test_message.TestMessageService/ResetRateLimit",
+    "/test_message.TestMessageService/StreamMetrics",
+    "/test_message.TestMessageService/ResolveSessions",
+    "/test_message.TestMessageService/GetConfig",
+    "/test_message.TestMessageService/StreamAggregates",
+    "/test_message.TestMessageService/RecentErrors",
+    "/test_message.TestMessageService/HealthCheck",
+    "/grpc.health.v1.Health/Check",
+    "/grpc.health.v1.Health/Watch",
+];
+
+/// A helper construct that can be used to reconfigure and build the middleware.
+pub struct CookieSessionLayerBuilder {
+    middleware: CookieSessionLayer,
+}
+
+impl CookieSessionLayerBuilder {
+    /// Finishes the building and returns a middleware
+    pub fn finish(self) -> CookieSessionLayer {
+        self.middleware
+    }
+
+    /// Rejects sessions issued by a client older than `version` so the user has to login again.
+    ///
+    /// Sessions without a recorded client version are treated as outdated.
+    pub fn force_relogin_below_version(mut self, version: Option<ClientVersion>) -> Self {
+        self.middleware.force_relogin_below_version = version;
+        self
+    }
+
+    /// If configured the url is attached to a x-login-url metadata when a re-login is required.
+    pub fn login_url(mut self, url: Option<String>) -> Self {
+        self.middleware.login_url = url;
+        self
+    }
+
+    /// Verifies the signature of the session cookie, and of the session id headers, with `key`
+    /// before looking the session up. Unsigned session ids are accepted as-is when no key is set.
+    pub fn signing_key(mut self, key: Option<CookieKey>) -> Self {
+        self.middleware.signing_key = key;
+        self
+    }
+
+    /// Sets the name of the session cookie. Defaults to `session`.
+    pub fn cookie_name(mut self, name: String) -> Self {
+        self.middleware.cookie_name = name;
+        self
+    }
+
+    /// Sets the full gRPC method paths (e.g. `/test_message.TestMessageService/Login`) that can
+    /// be called without a session. Defaults to `DEFAULT_AUTH_EXEMPT_METHODS`.
+    pub fn auth_exempt_methods(mut self, methods: Vec<String>) -> Self {
+        self.middleware.auth_exempt_methods = Arc::new(methods.into_iter().collect());
+        self
+    }
+
+    /// Sets the full gRPC method paths (e.g. `/test_message.TestMessageService/SendMessage`)
+    /// that are classified as writes. Every method is treated as a write when the list is empty.
+    pub fn write_methods(mut self, methods: Vec<String>) -> Self {
+        self.middleware.write_methods = Arc::new(methods.into_iter().collect());
+        self
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct CookieSessionLayer;
+pub struct CookieSessionLayer {
+    cookie_name: String,
+    force_relogin_below_version: Option<ClientVersion>,
+    login_url: Option<String>,
+    session_store: Arc<dyn SessionStore>,
+    write_methods: Arc<HashSet<String>>,
+    auth_exempt_methods: Arc<HashSet<String>>,
+    signing_key: Option<CookieKey>,
+}
+
+impl CookieSessionLayer {
+    /// Creates a new cookie session middleware resolving the sessions through `session_store`.
+    ///
+    /// The session lifetime (read and write TTL, expiry mode) is the policy of the store.
+    pub fn new(session_store: Arc<dyn SessionStore>) -> Self {
+        CookieSessionLayer {
+            cookie_name: "session".to_string(),
+            force_relogin_below_version: None,
+            login_url: None,
+            session_store,
+            write_methods: Arc::new(HashSet::new()),
+            auth_exempt_methods: Arc::new(
+                DEFAULT_AUTH_EXEMPT_METHODS
+                    .iter()
+                    .map(|method| method.to_string())
+                    .collect(),
+            ),
+            signing_key: None,
+        }
+    }
+
+    /// Creates a new middleware builder.
+    pub fn builder(session_store: Arc<dyn SessionStore>) -> CookieSessionLayerBuilder {
+        CookieSessionLayer::new(session_store).into_builder()
+    }
+
+    /// Converts the middleware into a builder.
+    pub fn into_builder(self) -> CookieSessionLayerBuilder {
+        CookieSessionLayerBuilder { middleware: self }
+    }
+
+    pub fn get_cookie_name(&self) -> &str {
+        &self.cookie_name
+    }
+
+    pub fn get_force_relogin_below_version(&self) -> &Option<ClientVersion> {
+        &self.force_relogin_below_version
+    }
+
+    pub fn get_login_url(&self) -> &Option<String> {
+        &self.login_url
+    }
+
+    pub fn get_session_store(&self) -> &Arc<dyn SessionStore> {
+        &self.session_store
+    }
+
+    pub fn get_signing_key(&self) -> &Option<CookieKey> {
+        &self.signing_key
+    }
+
+    /// Whether the gRPC method at `path` is classified as a write.
+    pub fn is_write_method(&self, path: &str) -> bool {
+        self.write_methods.is_empty() || self.write_methods.contains(path)
+    }
+
+    /// Whether the gRPC method at `path` can be called without a session.
+    pub fn is_auth_exempt(&self, path: &str) -> bool {
+        self.auth_exempt_methods.contains(path)
+    }
+}
 
 impl<S> Layer<S> for CookieSessionLayer {
     type Service = CookieMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        CookieMiddleware { inner }
+        CookieMiddleware {
+            inner,
+            config: self.clone(),
+        }
     }
 }
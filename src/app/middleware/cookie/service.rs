@@ -1,22 +1,33 @@
-use crate::app::util::error::ServiceError;
+use super::layer::CookieSessionLayer;
+use crate::app::middleware::sentry::service::set_session_user;
+use crate::app::util::{
+    context::RequestContext, error::ServiceError, session::SessionAccess, version::ClientVersion,
+};
 use cookie::{Cookie, CookieJar};
 use futures::future::{BoxFuture, FutureExt as _};
-use hyper::Body;
-use redis::aio::ConnectionManager;
+use hyper::{
+    header::{HeaderMap, ToStrError, AUTHORIZATION},
+    Body,
+};
 // use redis::aio::ConnectionManager;
-use time::Duration;
-use tonic::body::BoxBody;
+use tonic::{body::BoxBody, Status};
 use tower::{BoxError, Service};
+use tracing::debug;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct CookieMiddleware<S> {
     pub inner: S,
+    pub config: CookieSessionLayer,
 }
 
 #[derive(Debug, Clone)]
 pub struct CookieSessionContainer(pub Option<CookieSession>);
 
+/// marker extension of a call to a method exempted from authentication
+#[derive(Debug, Clone, Copy)]
+pub struct SessionExempt;
+
 #[derive(Debug, Clone)]
 pub struct CookieSession {
     pub sid: String,
@@ -49,11 +60,35 @@ where
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
 
+        let config = self.config.clone();
+
         async move {
-            inspect_request_metadata(&mut req).await?;
+            let is_auth_exempt = config.is_auth_exempt(req.uri().path());
+
+            // a stale session must not lock a client out of the exempt methods (e.g. `Login` after
+            // the session expired) so those are served anonymously when the lookup fail
+            match inspect_request_metadata(&mut req, &config).await {
+                Ok(()) => {}
+                Err(e) if is_auth_exempt => {
+                    debug!("ignoring the session of an auth exempt call: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+
+            if let Some(CookieSessionContainer(Some(session))) = req.extensions().get() {
+                set_session_user(&session.uid);
+
+                if let Some(context) = RequestContext::current() {
+                    context.set_uid(session.uid);
+                }
+            }
 
             insert_empty_extension(&mut req);
 
+            if is_auth_exempt {
+                req.extensions_mut().insert(SessionExempt);
+            }
+
             inner.call(req).await
         }
         .boxed()
@@ -64,7 +99,78 @@ fn box_into_error<T, E>(error: E) -> Result<T, BoxError>
 where
     E: Into<ServiceError>,
 {
-    Err(Box::new(error.into()))
+    Err(Box::new(Status::from(error.into())))
+}
+
+/// reject a session issued by a client older than the configured minimum version. Sessions
+/// issued before the version was recorded (or with an unparsable one) are outdated
+fn verify_client_version(
+    version: Option<&str>,
+    config: &CookieSessionLayer,
+) -> Result<(), ServiceError> {
+    let minimum = match config.get_force_relogin_below_version() {
+        Some(minimum) => minimum,
+        None => return Ok(()),
+    };
+
+    match version.map(str::parse::<ClientVersion>) {
+        Some(Ok(version)) if version >= *minimum => Ok(()),
+        version => Err(ServiceError::ReloginRequired {
+            version: match version {
+                Some(Ok(version)) => version.to_string(),
+                _ => "unknown".to_string(),
+            },
+            minimum: minimum.to_string(),
+            login_url: config.get_login_url().clone(),
+        }),
+    }
+}
+
+/// the session cookie with its signature verified (and stripped) if a signing key is configured
+fn session_cookie(
+    cookie_jar: &CookieJar,
+    config: &CookieSessionLayer,
+) -> Result<Option<Cookie<'static>>, ServiceError> {
+    match (
+        cookie_jar.get(config.get_cookie_name()),
+        config.get_signing_key(),
+    ) {
+        (None, _) => Ok(None),
+        (Some(_), Some(key)) => key
+            .verify(cookie_jar, config.get_cookie_name())
+            .map(Some)
+            .ok_or(ServiceError::BadCredential),
+        (Some(cookie), None) => Ok(Some(cookie.clone())),
+    }
+}
+
+/// the session id of the `session` or bearer header with its signature verified (and stripped) if
+/// a signing key is configured. The headers carry the signed cookie value returned by `Login`
+fn header_session_id(
+    sid: Result<String, ToStrError>,
+    config: &CookieSessionLayer,
+) -> Result<String, ServiceError> {
+    let sid = sid?;
+
+    match config.get_signing_key() {
+        Some(key) => key
+            .verify_value(config.get_cookie_name(), &sid)
+            .ok_or(ServiceError::BadCredential),
+        None => Ok(sid),
+    }
+}
+
+/// the session id of an `authorization: Bearer <sid>` header. Other schemes are ignored
+fn bearer_token(headers: &HeaderMap) -> Option<Result<String, ToStrError>> {
+    let header = match headers.get(AUTHORIZATION)?.to_str() {
+        Ok(header) => header,
+        Err(e) => return Some(Err(e)),
+    };
+    let (scheme, token) = header.trim().split_once(' ')?;
+
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| Ok(token.trim().to_string()))
 }
 
 fn insert_empty_extension(req: &mut hyper::Request<Body>) {
@@ -75,7 +181,11 @@ fn insert_empty_extension(req: &mut hyper::Request<Body>) {
     }
 }
 
-async fn inspect_request_metadata(req: &mut hyper::Request<Body>) -> Result<(), BoxError> {
+async fn inspect_request_metadata(
+    req: &mut hyper::Request<Body>,
+    config: &CookieSessionLayer,
+) -> Result<(), BoxError> {
+    let is_write = config.is_write_method(req.uri().path());
     let header = req.headers().get("cookie").map(|header| {
         header.to_str().map(|header| {
             let mut raw_cookies = header.split("; ").map(String::from);
@@ -94,73 +204,269 @@ async fn inspect_request_metadata(req: &mut hyper::Request<Body>) -> Result<(),
         })
     });
 
+    // header names are case-insensitive so the lowercase `session` metadata sent by native gRPC
+    // clients match as well. The cookie take precedence over both session id headers
     let session = req
         .headers()
         .get("Session")
-        .map(|header| header.to_str().map(|header| header.to_string()));
-
-    let redis_pool = {
-        let extension = req.extensions();
-
-        extension.get::<ConnectionManager>()
-    }
-    .cloned();
-
-    match (header, session, redis_pool) {
-        (Some(Ok(Ok(cookie_jar))), _, Some(mut redis_pool)) => {
-            if let Some(cookie) = cookie_jar.get("session") {
-                let record = redis::cmd("GETEX")
-                    .arg(cookie.value())
-                    .arg("EX")
-                    .arg(Duration::hours(24).whole_seconds())
-                    .query_async::<_, Option<String>>(&mut redis_pool)
-                    .await;
-
-                match record.map(|uid| uid.map(|uid| Uuid::parse_str(&uid))) {
-                    Ok(Some(Ok(uid))) => {
-                        let extension = req.extensions_mut();
-
-                        extension.insert(CookieSessionContainer(Some(CookieSession {
-                            sid: cookie.value().to_string(),
-                            uid,
-                        })));
-
-                        Ok(())
-                    }
-                    Ok(Some(Err(e))) => box_into_error(e)?,
-                    Ok(None) => box_into_error(ServiceError::BadCredential)?,
-                    Err(e) => box_into_error(e)?,
-                }
-            } else {
-                Ok(())
+        .map(|header| header.to_str().map(|header| header.to_string()))
+        .or_else(|| bearer_token(req.headers()));
+
+    // a cookie header without a `session` cookie (e.g. only analytics cookies) fall back to the
+    // session id headers
+    let sid = match (header, session) {
+        (Some(Ok(Ok(cookie_jar))), session) => match session_cookie(&cookie_jar, config) {
+            Ok(Some(cookie)) => Some(Ok(cookie.value().to_string())),
+            Ok(None) => session.map(|sid| header_session_id(sid, config)),
+            Err(e) => box_into_error(e)?,
+        },
+        (Some(Ok(Err(e))), _) => box_into_error(e)?,
+        (Some(Err(e)), _) => box_into_error(e)?,
+        (None, session) => session.map(|sid| header_session_id(sid, config)),
+    };
+    let sid = match sid {
+        Some(Ok(sid)) => sid,
+        Some(Err(e)) => box_into_error(e)?,
+        // (None, _) => box_into_error(GeekyRepercussion::HttpHeaderNotFound)?,
+        None => return Ok(()),
+    };
+
+    let access = SessionAccess {
+        is_write,
+        with_client_version: config.get_force_relogin_below_version().is_some(),
+    };
+
+    match config.get_session_store().get(&sid, access).await {
+        Ok(Some(record)) => {
+            if let Err(e) = verify_client_version(record.client_version.as_deref(), config) {
+                box_into_error(e)?
             }
+
+            let extension = req.extensions_mut();
+
+            extension.insert(CookieSessionContainer(Some(CookieSession {
+                sid,
+                uid: record.uid,
+            })));
+
+            Ok(())
         }
-        (_, Some(Ok(sid)), Some(mut redis_pool)) => {
-            let record = redis::cmd("GETEX")
-                .arg(&sid)
-                .arg("EX")
-                .arg(Duration::hours(24).whole_seconds())
-                .query_async::<_, Option<String>>(&mut redis_pool)
-                .await;
+        // the session id is well formed but its session expired, was deleted or was corrupted
+        Ok(None) => box_into_error(ServiceError::NotFound("session"))?,
+        Err(e) => box_into_error(e)?,
+    }
+}
 
-            match record.map(|uid| uid.map(|uid| Uuid::parse_str(&uid))) {
-                Ok(Some(Ok(uid))) => {
-                    let extension = req.extensions_mut();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::util::session::{MemorySessionStore, SessionPolicy, SessionStore};
+    use std::sync::Arc;
+    use tonic::Code;
 
-                    extension.insert(CookieSessionContainer(Some(CookieSession { sid, uid })));
+    const LOGIN_URL: &str = "https://example.com/login";
+    const SEND_MESSAGE: &str = "/test_message.TestMessageService/SendMessage";
 
-                    Ok(())
+    /// a store holding the session `sid` issued by `client_version` and a middleware forcing
+    /// re-login below `2.0`
+    async fn relogin_config(sid: &str, client_version: Option<&str>) -> CookieSessionLayer {
+        let session_store = Arc::new(MemorySessionStore::new(SessionPolicy::default()));
+
+        session_store
+            .set(sid, Uuid::new_v4(), client_version)
+            .await
+            .unwrap();
+
+        CookieSessionLayer::builder(session_store)
+            .force_relogin_below_version(Some("2.0".parse().unwrap()))
+            .login_url(Some(LOGIN_URL.to_string()))
+            .finish()
+    }
+
+    /// call `path` through the middleware with the `session` metadata set to `sid` and return the
+    /// session the inner service received
+    async fn call(
+        config: CookieSessionLayer,
+        path: &str,
+        sid: Option<&str>,
+    ) -> Result<Option<CookieSession>, Status> {
+        let headers = sid.map(|sid| ("session", sid));
+
+        call_with(config, path, headers.as_slice()).await
+    }
+
+    /// call `path` through the middleware with `headers` set and return the session the inner
+    /// service received
+    async fn call_with(
+        config: CookieSessionLayer,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Option<CookieSession>, Status> {
+        let mut middleware = CookieMiddleware {
+            inner: tower::service_fn(|req: hyper::Request<Body>| async move {
+                let mut response = hyper::Response::new(tonic::body::empty_body());
+
+                if let Some(session) = req.extensions().get::<CookieSessionContainer>() {
+                    response.extensions_mut().insert(session.clone());
                 }
-                Ok(Some(Err(e))) => box_into_error(e)?,
-                Ok(None) => box_into_error(ServiceError::BadCredential)?,
-                Err(e) => box_into_error(e)?,
-            }
+
+                Ok::<_, BoxError>(response)
+            }),
+            config,
+        };
+        let mut req = hyper::Request::builder().uri(path);
+
+        for (name, value) in headers {
+            req = req.header(*name, *value);
         }
-        (Some(Ok(Err(e))), _, _) => box_into_error(e)?,
-        (Some(Err(e)), _, _) => box_into_error(e)?,
-        (_, Some(Err(e)), _) => box_into_error(e)?,
-        (_, _, None) => box_into_error(ServiceError::MiddlewareNotSet("config"))?,
-        // (None, _) => box_into_error(GeekyRepercussion::HttpHeaderNotFound)?,
-        (_, None, _) => Ok(()),
+
+        match middleware.call(req.body(Body::empty()).unwrap()).await {
+            Ok(response) => Ok(response
+                .extensions()
+                .get::<CookieSessionContainer>()
+                .and_then(|container| container.0.clone())),
+            Err(e) => Err(*e.downcast::<Status>().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn session_at_or_above_the_minimum_version_is_accepted() {
+        for client_version in ["2.0", "2.0.0", "2.1", "10.0"] {
+            let config = relogin_config("sid", Some(client_version)).await;
+            let session = call(config, SEND_MESSAGE, Some("sid")).await.unwrap();
+
+            assert_eq!(session.unwrap().sid, "sid", "{}", client_version);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_below_the_minimum_version_requires_a_relogin() {
+        for client_version in [Some("1.9.9"), Some("not a version"), None] {
+            let config = relogin_config("sid", client_version).await;
+            let status = call(config, SEND_MESSAGE, Some("sid")).await.unwrap_err();
+
+            assert_eq!(status.code(), Code::Unauthenticated, "{:?}", client_version);
+            assert_eq!(status.metadata().get("x-login-url").unwrap(), LOGIN_URL);
+        }
+    }
+
+    /// a store holding one session per source of session id, named after it
+    async fn sources_config() -> CookieSessionLayer {
+        let session_store = Arc::new(MemorySessionStore::new(SessionPolicy::default()));
+
+        for sid in ["cookie-sid", "header-sid", "bearer-sid"] {
+            session_store.set(sid, Uuid::new_v4(), None).await.unwrap();
+        }
+
+        CookieSessionLayer::builder(session_store).finish()
+    }
+
+    async fn sid_of(headers: &[(&str, &str)]) -> Option<String> {
+        call_with(sources_config().await, SEND_MESSAGE, headers)
+            .await
+            .unwrap()
+            .map(|session| session.sid)
+    }
+
+    #[tokio::test]
+    async fn bearer_authorization_carries_the_session_id() {
+        for authorization in [
+            "Bearer bearer-sid",
+            "bearer  bearer-sid ",
+            "BEARER bearer-sid",
+        ] {
+            assert_eq!(
+                sid_of(&[("authorization", authorization)]).await.as_deref(),
+                Some("bearer-sid"),
+                "{}",
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn other_authorization_schemes_are_ignored() {
+        assert_eq!(sid_of(&[("authorization", "Basic bearer-sid")]).await, None);
+    }
+
+    #[tokio::test]
+    async fn session_id_sources_are_in_precedence_order() {
+        let cookie = ("cookie", "theme=dark; session=cookie-sid");
+        let header = ("Session", "header-sid");
+        let bearer = ("Authorization", "Bearer bearer-sid");
+
+        assert_eq!(
+            sid_of(&[bearer, header, cookie]).await.as_deref(),
+            Some("cookie-sid")
+        );
+        assert_eq!(
+            sid_of(&[bearer, header]).await.as_deref(),
+            Some("header-sid")
+        );
+        // a cookie header without the session cookie fall back to the headers
+        assert_eq!(
+            sid_of(&[bearer, ("cookie", "theme=dark")]).await.as_deref(),
+            Some("bearer-sid")
+        );
+    }
+
+    #[tokio::test]
+    async fn header_session_ids_must_be_signed_with_the_signing_key() {
+        use crate::app::util::credential::CookieKey;
+
+        let key = "k".repeat(64).parse::<CookieKey>().unwrap();
+        let config = sources_config()
+            .await
+            .into_builder()
+            .signing_key(Some(key.clone()))
+            .finish();
+        let signed = key
+            .sign(Cookie::new(
+                config.get_cookie_name().to_string(),
+                "header-sid".to_string(),
+            ))
+            .value()
+            .to_string();
+        let bearer = format!("Bearer {}", signed);
+
+        for headers in [[("session", signed.as_str())], [("authorization", &bearer)]] {
+            let session = call_with(config.clone(), SEND_MESSAGE, &headers)
+                .await
+                .unwrap();
+
+            // the signature is stripped before the session lookup
+            assert_eq!(session.unwrap().sid, "header-sid", "{:?}", headers);
+        }
+
+        for headers in [
+            [("session", "header-sid")],
+            [("authorization", "Bearer bearer-sid")],
+        ] {
+            let status = call_with(config.clone(), SEND_MESSAGE, &headers)
+                .await
+                .unwrap_err();
+
+            assert_eq!(status.code(), Code::Unauthenticated, "{:?}", headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_session_is_not_found() {
+        use crate::app::{
+            config::fake_redis::FakeRedis,
+            util::session::{RedisSessionStore, SessionPolicy},
+        };
+
+        let (fake_redis, redis_pool) = FakeRedis::start().await;
+        let session_store = Arc::new(RedisSessionStore::new(redis_pool, SessionPolicy::default()));
+
+        fake_redis.set("sid", "not-a-uuid");
+
+        let config = CookieSessionLayer::builder(session_store).finish();
+        let status = call(config, SEND_MESSAGE, Some("sid")).await.unwrap_err();
+
+        // server side corruption is not reported as an invalid argument of the client
+        assert_eq!(status.code(), Code::NotFound);
+        assert!(fake_redis.get("sid").is_none());
     }
 }
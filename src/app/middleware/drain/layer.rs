@@ -0,0 +1,43 @@
+use super::service::DrainMiddleware;
+use crate::app::util::shutdown::ShutdownSignal;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tower::Layer;
+
+/// once shutdown is triggered, reject with `Code::Unavailable` every request whose client
+/// deadline expire before the drain phase complete so the client retry on another replica
+/// instead of waiting on this one. Requests without a deadline are let through
+#[derive(Debug, Clone)]
+pub struct DrainLayer {
+    shutdown_signal_notifier: Arc<ShutdownSignal>,
+    drain_grace: Duration,
+}
+
+impl DrainLayer {
+    /// Creates a new drain middleware. The drain phase is considered complete `drain_grace`
+    /// after the shutdown was triggered.
+    pub fn new(shutdown_signal_notifier: Arc<ShutdownSignal>, drain_grace: Duration) -> Self {
+        DrainLayer {
+            shutdown_signal_notifier,
+            drain_grace,
+        }
+    }
+
+    /// point in time at which the drain phase complete, `None` while still serving
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        self.shutdown_signal_notifier
+            .triggered_at()
+            .map(|triggered_at| triggered_at + self.drain_grace)
+    }
+}
+
+impl<S> Layer<S> for DrainLayer {
+    type Service = DrainMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DrainMiddleware {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
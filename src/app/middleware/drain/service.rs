@@ -0,0 +1,134 @@
+use super::layer::DrainLayer;
+use crate::app::util::{deadline::RequestDeadline, error::ServiceError};
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct DrainMiddleware<S> {
+    pub inner: S,
+    pub config: DrainLayer,
+}
+
+impl<S> Service<hyper::Request<Body>> for DrainMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let config = self.config.clone();
+
+        async move {
+            // the deadline extension is inserted by the tracing middleware
+            let deadline = req.extensions().get::<RequestDeadline>().copied();
+
+            if let (Some(RequestDeadline(deadline)), Some(drain_deadline)) =
+                (deadline, config.drain_deadline())
+            {
+                if deadline < drain_deadline {
+                    debug!(
+                        "rejecting {} whose deadline expire before the drain phase complete",
+                        req.uri().path()
+                    );
+
+                    return Err(Box::new(Status::from(ServiceError::ShuttingDown)) as BoxError);
+                }
+            }
+
+            inner.call(req).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::util::shutdown::ShutdownSignal;
+    use std::{sync::Arc, time::Duration};
+    use tonic::Code;
+    use tower::{util::BoxCloneService, Layer};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    fn middleware(shutdown_signal_notifier: &Arc<ShutdownSignal>) -> DrainMiddleware<Inner> {
+        DrainLayer::new(
+            Arc::clone(shutdown_signal_notifier),
+            Duration::from_secs(30),
+        )
+        .layer(BoxCloneService::new(tower::service_fn(
+            |_: hyper::Request<Body>| async {
+                Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+            },
+        )))
+    }
+
+    /// request whose client deadline is its `grpc-timeout` from now, as the tracing middleware
+    /// would record it
+    fn request(grpc_timeout: Option<&str>) -> hyper::Request<Body> {
+        let mut req = hyper::Request::post("/test_message.TestMessageService/SendMessage")
+            .body(Body::empty())
+            .unwrap();
+
+        if let Some(grpc_timeout) = grpc_timeout {
+            req.headers_mut()
+                .insert("grpc-timeout", grpc_timeout.parse().unwrap());
+        }
+
+        if let Some(deadline) = RequestDeadline::from_headers(req.headers()) {
+            req.extensions_mut().insert(deadline);
+        }
+
+        req
+    }
+
+    #[tokio::test]
+    async fn short_deadline_is_unavailable_while_draining() {
+        let shutdown_signal_notifier = Arc::new(ShutdownSignal::new());
+        let mut middleware = middleware(&shutdown_signal_notifier);
+
+        // served as usual until the shutdown is triggered
+        middleware.call(request(Some("100m"))).await.unwrap();
+        shutdown_signal_notifier.trigger();
+
+        let error = middleware.call(request(Some("100m"))).await.unwrap_err();
+
+        assert_eq!(
+            error.downcast::<Status>().unwrap().code(),
+            Code::Unavailable
+        );
+    }
+
+    #[tokio::test]
+    async fn long_or_missing_deadline_proceeds_while_draining() {
+        let shutdown_signal_notifier = Arc::new(ShutdownSignal::new());
+        let mut middleware = middleware(&shutdown_signal_notifier);
+
+        shutdown_signal_notifier.trigger();
+
+        middleware.call(request(Some("5M"))).await.unwrap();
+        middleware.call(request(None)).await.unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+use super::service::IdempotencyMiddleware;
+use std::{collections::HashSet, sync::Arc};
+use tower::Layer;
+
+/// reject calls to any of the listed methods (full gRPC path e.g.
+/// `/test_message.TestMessageService/Login`) that do not carry an `idempotency-key` metadata so
+/// clients can only retry them safely
+#[derive(Debug, Clone)]
+pub struct IdempotencyLayer {
+    methods: Arc<HashSet<String>>,
+}
+
+impl IdempotencyLayer {
+    /// Creates a new idempotency key middleware.
+    pub fn new(methods: Vec<String>) -> Self {
+        IdempotencyLayer {
+            methods: Arc::new(methods.into_iter().collect()),
+        }
+    }
+
+    pub fn requires_key(&self, path: &str) -> bool {
+        self.methods.contains(path)
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = IdempotencyMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdempotencyMiddleware {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
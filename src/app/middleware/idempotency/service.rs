@@ -0,0 +1,130 @@
+use super::layer::IdempotencyLayer;
+use crate::app::util::error::ServiceError;
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyMiddleware<S> {
+    pub inner: S,
+    pub config: IdempotencyLayer,
+}
+
+impl<S> Service<hyper::Request<Body>> for IdempotencyMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let config = self.config.clone();
+
+        async move {
+            if let Err(e) = require_idempotency_key(&req, &config) {
+                return Err(Box::new(Status::from(e)) as BoxError);
+            }
+
+            inner.call(req).await
+        }
+        .boxed()
+    }
+}
+
+fn require_idempotency_key(
+    req: &hyper::Request<Body>,
+    config: &IdempotencyLayer,
+) -> Result<(), ServiceError> {
+    let path = req.uri().path();
+
+    if !config.requires_key(path) {
+        return Ok(());
+    }
+
+    match req.headers().get("idempotency-key") {
+        Some(key) if !key.is_empty() => Ok(()),
+        _ => Err(ServiceError::MissingIdempotencyKey(path.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+    use tower::{util::BoxCloneService, Layer};
+
+    const SEND_MESSAGE: &str = "/test_message.TestMessageService/SendMessage";
+    const EVENT_MESSAGE: &str = "/test_message.TestMessageService/EventMessage";
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    fn middleware() -> IdempotencyMiddleware<Inner> {
+        IdempotencyLayer::new(vec![SEND_MESSAGE.to_string()]).layer(BoxCloneService::new(
+            tower::service_fn(|_: hyper::Request<Body>| async {
+                Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+            }),
+        ))
+    }
+
+    fn request(path: &str, idempotency_key: Option<&str>) -> hyper::Request<Body> {
+        let mut req = hyper::Request::post(path);
+
+        if let Some(idempotency_key) = idempotency_key {
+            req = req.header("idempotency-key", idempotency_key);
+        }
+
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn listed_method_without_a_key_is_invalid_argument() {
+        for idempotency_key in [None, Some("")] {
+            let error = middleware()
+                .call(request(SEND_MESSAGE, idempotency_key))
+                .await
+                .unwrap_err();
+
+            assert_eq!(
+                error.downcast::<Status>().unwrap().code(),
+                Code::InvalidArgument,
+                "{:?}",
+                idempotency_key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_method_with_a_key_is_accepted() {
+        middleware()
+            .call(request(SEND_MESSAGE, Some("9f2c1e")))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unlisted_method_does_not_need_a_key() {
+        middleware()
+            .call(request(EVENT_MESSAGE, None))
+            .await
+            .unwrap();
+    }
+}
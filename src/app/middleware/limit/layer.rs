@@ -0,0 +1,19 @@
+use super::service::MessageLimitMiddleware;
+use tower::Layer;
+
+/// reject every request carrying a gRPC message larger than the wrapped number of bytes with
+/// `ServiceError::MessageTooLarge`. The size is read from the length prefix of each message so
+/// an oversized message is refused before its payload is buffered
+#[derive(Debug, Clone)]
+pub struct MessageLimitLayer(pub usize);
+
+impl<S> Layer<S> for MessageLimitLayer {
+    type Service = MessageLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MessageLimitMiddleware {
+            inner,
+            max_message_bytes: self.0,
+        }
+    }
+}
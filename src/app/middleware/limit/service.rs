@@ -0,0 +1,181 @@
+use crate::app::util::error::ServiceError;
+use futures::{
+    future::{BoxFuture, FutureExt as _},
+    stream,
+};
+use hyper::{body::HttpBody, Body};
+use std::pin::Pin;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+
+/// size of the gRPC message prefix: a compressed flag followed by a big endian `u32` length
+const MESSAGE_PREFIX_BYTES: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct MessageLimitMiddleware<S> {
+    pub inner: S,
+    pub max_message_bytes: usize,
+}
+
+impl<S> Service<hyper::Request<Body>> for MessageLimitMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let req = req.map(|body| limit_messages(body, self.max_message_bytes));
+
+        async move { inner.call(req).await }.boxed()
+    }
+}
+
+/// pass `body` through while checking the length prefix of every message. The body fail with a
+/// `Status` built from `ServiceError::MessageTooLarge` once a message exceed `max_message_bytes`,
+/// which tonic hand back to the client as the call status
+fn limit_messages(body: Body, max_message_bytes: usize) -> Body {
+    let frames = MessageFrames::new(max_message_bytes);
+
+    Body::wrap_stream(stream::unfold(Some((body, frames)), |state| async move {
+        let (mut body, mut frames) = state?;
+        let chunk = match Pin::new(&mut body).data().await? {
+            Ok(chunk) => chunk,
+            Err(e) => return Some((Err(BoxError::from(e)), None)),
+        };
+
+        match frames.inspect(&chunk) {
+            Ok(()) => Some((Ok(chunk), Some((body, frames)))),
+            Err(e) => Some((Err(BoxError::from(Status::from(e))), None)),
+        }
+    }))
+}
+
+/// incremental parser of the message prefixes of a gRPC request body split in arbitrary chunks
+struct MessageFrames {
+    max_message_bytes: usize,
+    prefix: [u8; MESSAGE_PREFIX_BYTES],
+    prefix_len: usize,
+    /// payload bytes of the current message not seen yet
+    remaining: usize,
+}
+
+impl MessageFrames {
+    fn new(max_message_bytes: usize) -> Self {
+        MessageFrames {
+            max_message_bytes,
+            prefix: [0; MESSAGE_PREFIX_BYTES],
+            prefix_len: 0,
+            remaining: 0,
+        }
+    }
+
+    fn inspect(&mut self, mut chunk: &[u8]) -> Result<(), ServiceError> {
+        while !chunk.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(chunk.len());
+
+                self.remaining -= skipped;
+                chunk = &chunk[skipped..];
+                continue;
+            }
+
+            let read = (MESSAGE_PREFIX_BYTES - self.prefix_len).min(chunk.len());
+
+            self.prefix[self.prefix_len..self.prefix_len + read].copy_from_slice(&chunk[..read]);
+            self.prefix_len += read;
+            chunk = &chunk[read..];
+
+            if self.prefix_len == MESSAGE_PREFIX_BYTES {
+                let length = u32::from_be_bytes([
+                    self.prefix[1],
+                    self.prefix[2],
+                    self.prefix[3],
+                    self.prefix[4],
+                ]) as usize;
+
+                if length > self.max_message_bytes {
+                    return Err(ServiceError::MessageTooLarge {
+                        size: length,
+                        limit: self.max_message_bytes,
+                    });
+                }
+
+                self.prefix_len = 0;
+                self.remaining = length;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// gRPC framing of an uncompressed message of `len` bytes
+    fn frame(len: usize) -> Vec<u8> {
+        let mut frame = vec![0];
+
+        frame.extend((len as u32).to_be_bytes());
+        frame.extend(vec![b'x'; len]);
+
+        frame
+    }
+
+    #[test]
+    fn message_at_the_limit_is_accepted() {
+        let mut frames = MessageFrames::new(16);
+
+        assert!(frames.inspect(&frame(16)).is_ok());
+        assert!(frames.inspect(&frame(0)).is_ok());
+    }
+
+    #[test]
+    fn message_over_the_limit_is_too_large() {
+        let mut frames = MessageFrames::new(16);
+
+        match frames.inspect(&frame(17)) {
+            Err(ServiceError::MessageTooLarge { size, limit }) => {
+                assert_eq!((size, limit), (17, 16))
+            }
+            other => panic!("expect MessageTooLarge but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn prefixes_split_across_chunks_are_parsed() {
+        let mut frames = MessageFrames::new(16);
+        let mut body = frame(16);
+
+        body.extend(frame(17));
+
+        // every chunk boundary, including the ones cutting through the length prefix
+        let (accepted, oversized) = body.split_at(16 + MESSAGE_PREFIX_BYTES + 3);
+
+        for byte in accepted {
+            assert!(frames.inspect(&[*byte]).is_ok());
+        }
+
+        assert!(frames.inspect(oversized).is_err());
+    }
+}
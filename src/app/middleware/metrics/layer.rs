@@ -0,0 +1,27 @@
+use super::service::MetricsMiddleware;
+use tower::Layer;
+
+/// record per method request totals, error totals and latencies into `REQUEST_METRICS`
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    status_codes: bool,
+}
+
+impl MetricsLayer {
+    /// Creates a new metrics middleware. When `status_codes` is set every response is also
+    /// counted by its gRPC status code.
+    pub fn new(status_codes: bool) -> Self {
+        MetricsLayer { status_codes }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsMiddleware {
+            inner,
+            status_codes: self.status_codes,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::app::util::metrics::REQUEST_METRICS;
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use std::time::Instant;
+use tonic::{body::BoxBody, Code, Status};
+use tower::{BoxError, Service};
+
+#[derive(Debug, Clone)]
+pub struct MetricsMiddleware<S> {
+    pub inner: S,
+    pub status_codes: bool,
+}
+
+impl<S> Service<hyper::Request<Body>> for MetricsMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let method = req.uri().path().to_string();
+        let started_at = Instant::now();
+        let status_codes = self.status_codes;
+
+        async move {
+            let result = inner.call(req).await;
+
+            // latency is measured until the response headers are ready. For server streams this
+            // is the time to the first message rather than the lifetime of the stream
+            let code = match &result {
+                Ok(res) => res
+                    .headers()
+                    .get("grpc-status")
+                    .map(|status| Code::from_bytes(status.as_bytes()))
+                    .unwrap_or(Code::Ok),
+                Err(e) => e
+                    .downcast_ref::<Status>()
+                    .map(Status::code)
+                    .unwrap_or(Code::Unknown),
+            };
+
+            REQUEST_METRICS.observe(&method, code, started_at.elapsed());
+
+            if status_codes {
+                REQUEST_METRICS.observe_status_code(code);
+            }
+
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::middleware::metrics::layer::MetricsLayer;
+    use tower::{util::BoxCloneService, Layer};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    /// middleware around a service answering with the code named by the request path, e.g.
+    /// `/5` is `NOT_FOUND`. Paths under `/error/` fail with the status instead
+    fn middleware(status_codes: bool) -> MetricsMiddleware<Inner> {
+        MetricsLayer::new(status_codes).layer(BoxCloneService::new(tower::service_fn(
+            |req: hyper::Request<Body>| async move {
+                let path = req.uri().path();
+                let code = Code::from(path.rsplit('/').next().unwrap().parse::<i32>().unwrap());
+
+                if path.starts_with("/error/") {
+                    return Err(Status::new(code, "failed").into());
+                }
+
+                let mut res = hyper::Response::new(tonic::body::empty_body());
+
+                if code != Code::Ok {
+                    res.headers_mut()
+                        .insert("grpc-status", (code as i32).to_string().parse().unwrap());
+                }
+
+                Ok::<_, BoxError>(res)
+            },
+        )))
+    }
+
+    async fn call(middleware: &mut MetricsMiddleware<Inner>, path: &str) {
+        let _ = middleware
+            .call(hyper::Request::post(path).body(Body::empty()).unwrap())
+            .await;
+    }
+
+    #[tokio::test]
+    async fn responses_are_counted_per_status_code() {
+        let codes = [Code::Ok, Code::NotFound, Code::Unavailable, Code::Internal];
+        let before = codes.map(|code| REQUEST_METRICS.status_code_count(code));
+        let mut middleware = middleware(true);
+
+        for path in ["/0", "/0", "/5", "/error/5", "/error/14"] {
+            call(&mut middleware, path).await;
+        }
+
+        let counted = codes.map(|code| REQUEST_METRICS.status_code_count(code));
+
+        // only this test count status codes so the deltas are exact
+        assert_eq!(counted[0] - before[0], 2);
+        assert_eq!(counted[1] - before[1], 2);
+        assert_eq!(counted[2] - before[2], 1);
+        assert_eq!(counted[3] - before[3], 0);
+    }
+
+    #[tokio::test]
+    async fn status_codes_are_not_counted_unless_enabled() {
+        let before = REQUEST_METRICS.status_code_count(Code::DataLoss);
+        let mut middleware = middleware(false);
+
+        call(&mut middleware, "/15").await;
+
+        assert_eq!(REQUEST_METRICS.status_code_count(Code::DataLoss), before);
+    }
+}
@@ -1,4 +1,12 @@
+pub mod availability;
 pub mod config;
 pub mod cookie;
+pub mod drain;
+pub mod idempotency;
+pub mod limit;
+pub mod metrics;
+pub mod ratelimit;
 pub mod sentry;
+pub mod timeout;
 pub mod tracing;
+pub mod via;
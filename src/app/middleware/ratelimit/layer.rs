@@ -0,0 +1,37 @@
+use super::service::RateLimitMiddleware;
+use std::time::Duration;
+use tower::Layer;
+
+/// throttle every client to at most `limit` requests per `window`. Must be layered after the
+/// `CookieSessionLayer` so authenticated requests are keyed by their session uid
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limit: u64,
+    window: Duration,
+}
+
+impl RateLimitLayer {
+    /// Creates a new rate limit middleware.
+    pub fn new(limit: u64, window: Duration) -> Self {
+        RateLimitLayer { limit, window }
+    }
+
+    pub fn get_limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn get_window(&self) -> &Duration {
+        &self.window
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
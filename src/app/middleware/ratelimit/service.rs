@@ -0,0 +1,142 @@
+use super::layer::RateLimitLayer;
+use crate::app::config::database::RedisPool;
+use crate::app::{
+    middleware::cookie::service::CookieSessionContainer,
+    util::{
+        error::ServiceError,
+        ratelimit::{forwarded_for, rate_limit_key},
+        redis::redis_with_timeout,
+    },
+};
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{
+    body::BoxBody,
+    transport::server::{TcpConnectInfo, TlsConnectInfo},
+    Status,
+};
+use tower::{BoxError, Service};
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitMiddleware<S> {
+    pub inner: S,
+    pub config: RateLimitLayer,
+}
+
+impl<S> Service<hyper::Request<Body>> for RateLimitMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let config = self.config.clone();
+
+        async move {
+            if let Err(e) = enforce_rate_limit(&req, &config).await {
+                return Err(Box::new(Status::from(e)) as BoxError);
+            }
+
+            inner.call(req).await
+        }
+        .boxed()
+    }
+}
+
+/// identify the client by its session uid, falling back to the first `X-Forwarded-For` address
+/// and then to the peer address of the connection for unauthenticated requests
+fn client_identity(req: &hyper::Request<Body>) -> Option<String> {
+    if let Some(CookieSessionContainer(Some(session))) = req.extensions().get() {
+        return Some(session.uid.to_string());
+    }
+
+    req.headers()
+        .get("X-Forwarded-For")
+        .and_then(|header| header.to_str().ok())
+        .and_then(forwarded_for)
+        .or_else(|| {
+            let extensions = req.extensions();
+
+            // connections accepted by the SNI restricting acceptor carry the TCP info inside
+            extensions
+                .get::<TcpConnectInfo>()
+                .or_else(|| {
+                    extensions
+                        .get::<TlsConnectInfo<TcpConnectInfo>>()
+                        .map(TlsConnectInfo::get_ref)
+                })
+                .and_then(TcpConnectInfo::remote_addr)
+                .map(|address| address.ip().to_string())
+        })
+}
+
+async fn enforce_rate_limit(
+    req: &hyper::Request<Body>,
+    config: &RateLimitLayer,
+) -> Result<(), ServiceError> {
+    let identity = match client_identity(req) {
+        Some(identity) => identity,
+        None => return Ok(()),
+    };
+    let mut redis_pool = req
+        .extensions()
+        .get::<RedisPool>()
+        .cloned()
+        .ok_or(ServiceError::MiddlewareNotSet("config"))?;
+    let key = rate_limit_key(&identity);
+
+    let count = redis_with_timeout(
+        redis::cmd("INCR")
+            .arg(&key)
+            .query_async::<_, u64>(&mut redis_pool),
+    )
+    .await?;
+
+    // the window start with the first request so only the first increment set the expiry
+    if count == 1 {
+        redis_with_timeout(
+            redis::cmd("EXPIRE")
+                .arg(&key)
+                .arg(config.get_window().as_secs())
+                .query_async::<_, ()>(&mut redis_pool),
+        )
+        .await?;
+    }
+
+    if count > config.get_limit() {
+        // the window end once the counter expire, hint the client how long to back off
+        let ttl = redis_with_timeout(
+            redis::cmd("TTL")
+                .arg(&key)
+                .query_async::<_, i64>(&mut redis_pool),
+        )
+        .await?;
+
+        debug!("rate limit exceeded for: {}", identity);
+
+        // a key without expiry (-1) or already gone (-2) still ask for a minimal backoff
+        return Err(ServiceError::TooManyRequests(ttl.max(1) as u64));
+    }
+
+    Ok(())
+}
@@ -28,7 +28,8 @@ impl SentrySessionLayerBuilder {
         self
     }
 
-    /// If configured the sentry id is attached to a X-Sentry-Event header.
+    /// If configured the id of the sentry event captured for a failed request is attached to
+    /// its response as a `x-sentry-event` metadata.
     pub fn emit_header(mut self, val: bool) -> Self {
         self.middleware.emit_header = val;
         self
@@ -37,7 +38,8 @@ impl SentrySessionLayerBuilder {
     #[allow(dead_code)]
     /// Enables or disables error reporting.
     ///
-    /// The default is to report all errors.
+    /// The default is to report all server errors (`Internal`, `Unknown`, `Unavailable` and
+    /// `DataLoss`). Rejected client requests are never reported.
     pub fn capture_server_errors(mut self, val: bool) -> Self {
         self.middleware.capture_server_errors = val;
         self
@@ -79,7 +81,6 @@ impl SentrySessionLayer {
         self.capture_server_errors
     }
 
-    #[allow(dead_code)]
     pub fn get_emit_header(&self) -> bool {
         self.emit_header
     }
@@ -3,13 +3,14 @@ use crate::app::util::error::ServiceError;
 use futures::future::{BoxFuture, FutureExt as _};
 use hyper::Body;
 use sentry_core::{
-    protocol::{ClientSdkPackage, Event, Request},
-    Breadcrumb, Hub, Level, SentryFutureExt,
+    protocol::{ClientSdkPackage, Event, Request, SpanStatus, User},
+    Hub, Level, SentryFutureExt, TransactionContext,
 };
 use std::{borrow::Cow, boxed::Box, sync::Arc};
-use tonic::{body::BoxBody, transport::Error};
+use tonic::{body::BoxBody, transport::Error, Code, Status};
 use tower::{BoxError, Service};
 use tracing::error;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct SentrySessionTracker<S> {
@@ -48,7 +49,7 @@ where
             session.get_hub().clone().unwrap_or_else(Hub::main),
         ));
         let client = hub.client();
-        let track_sessions = client.as_ref().map_or(false, |client| {
+        let track_sessions = client.as_ref().is_some_and(|client| {
             let options = client.options();
             options.auto_session_tracking
                 && options.session_mode == sentry_core::SessionMode::Request
@@ -58,49 +59,173 @@ where
         }
         let with_pii = client
             .as_ref()
-            .map_or(false, |client| client.options().send_default_pii);
+            .is_some_and(|client| client.options().send_default_pii);
 
         let (tx, sentry_req) = sentry_request_from_http(&req, with_pii);
+
+        // the performance transaction continue the distributed trace of the caller if it sent a
+        // `sentry-trace` header. Whether it is sent at all is decided by `traces_sample_rate`
+        let headers = req
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        let transaction = hub.start_transaction(TransactionContext::continue_from_headers(
+            tx.as_deref().unwrap_or_default(),
+            "grpc.server",
+            headers,
+        ));
+        transaction.set_request(sentry_req.clone());
+        transaction.set_data("http.method", req.method().as_str().into());
+        transaction.set_data("http.route", req.uri().path().into());
+
         hub.configure_scope(|scope| {
             scope.set_transaction(tx.as_deref());
+            scope.set_span(Some(transaction.clone().into()));
             scope.add_event_processor(Box::new(move |event| {
                 Some(process_event(event, &sentry_req))
             }))
         });
 
         async move {
-            match inner.call(req).bind_hub(hub.clone()).await {
-                Ok(res) => Ok(res),
+            let response = match inner.call(req).bind_hub(hub.clone()).await {
+                Ok(res) => {
+                    // only a trailers-only response carry its status in the headers, a streamed
+                    // response is reported as ok once its headers are ready
+                    let code = Status::from_header_map(res.headers())
+                        .map_or(Code::Ok, |status| status.code());
+                    transaction.set_status(span_status(code));
+
+                    Ok(res)
+                }
                 Err(err) => {
-                    if session.get_capture_server_errors() {
-                        capture_boxed_error(&err, hub);
+                    let err = into_status_error(err);
+                    let code = err
+                        .downcast_ref::<Status>()
+                        .map_or(Code::Internal, |status| status.code());
+                    transaction.set_status(span_status(code));
+
+                    let event_id = match session.get_capture_server_errors() {
+                        true => capture_boxed_error(&err, hub),
+                        false => None,
+                    };
+
+                    match event_id {
+                        Some(event_id) if session.get_emit_header() => {
+                            Err(with_event_header(err, event_id))
+                        }
+                        _ => Err(err),
                     }
-                    Err(err)
                 }
-            }
+            };
+
+            transaction.finish();
+
+            response
         }
         .boxed()
     }
 }
 
-fn capture_boxed_error(err: &BoxError, hub: Arc<Hub>) {
-    if let Some(e) = err.downcast_ref::<Error>() {
+/// attach the authenticated `uid` as the sentry user of the current request. The sentry middleware
+/// sit before the cookie middleware in the layer stack so the session is not known yet when its
+/// `call` run. Every inner service is however polled with the request hub bound so calling this
+/// from a later middleware still attach the user to the request scope
+pub fn set_session_user(uid: &Uuid) {
+    Hub::current().configure_scope(|scope| {
+        scope.set_user(Some(User {
+            id: Some(uid.to_string()),
+            ..Default::default()
+        }))
+    });
+}
+
+/// sentry span status equivalent of the gRPC status `code`
+fn span_status(code: Code) -> SpanStatus {
+    match code {
+        Code::Ok => SpanStatus::Ok,
+        Code::Cancelled => SpanStatus::Cancelled,
+        Code::Unknown => SpanStatus::UnknownError,
+        Code::InvalidArgument => SpanStatus::InvalidArgument,
+        Code::DeadlineExceeded => SpanStatus::DeadlineExceeded,
+        Code::NotFound => SpanStatus::NotFound,
+        Code::AlreadyExists => SpanStatus::AlreadyExists,
+        Code::PermissionDenied => SpanStatus::PermissionDenied,
+        Code::ResourceExhausted => SpanStatus::ResourceExhausted,
+        Code::FailedPrecondition => SpanStatus::FailedPrecondition,
+        Code::Aborted => SpanStatus::Aborted,
+        Code::OutOfRange => SpanStatus::OutOfRange,
+        Code::Unimplemented => SpanStatus::Unimplemented,
+        Code::Internal => SpanStatus::InternalError,
+        Code::Unavailable => SpanStatus::Unavailable,
+        Code::DataLoss => SpanStatus::DataLoss,
+        Code::Unauthenticated => SpanStatus::Unauthenticated,
+    }
+}
+
+/// convert a bare `ServiceError` into the status it is answered with, once, so its code is
+/// known here and the side effects of its mapping (logging, warning capture) run a single time
+fn into_status_error(err: BoxError) -> BoxError {
+    match err.downcast::<ServiceError>() {
+        Ok(e) => Box::new(Status::from(*e)),
+        Err(err) => err,
+    }
+}
+
+/// whether `code` report a failure of the server rather than a rejected client request
+fn is_server_error(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Internal | Code::Unknown | Code::Unavailable | Code::DataLoss
+    )
+}
+
+/// attach `event_id` as the `x-sentry-event` metadata of the status `err` is so a client reported
+/// error can be found in sentry. Other errors are returned as-is
+fn with_event_header(err: BoxError, event_id: Uuid) -> BoxError {
+    let mut status = match err.downcast::<Status>() {
+        Ok(status) => status,
+        Err(err) => return err,
+    };
+
+    if let Ok(value) = event_id.simple().to_string().parse() {
+        status.metadata_mut().insert("x-sentry-event", value);
+    }
+
+    status
+}
+
+/// report `err` to sentry if it is a server side failure. Rejected client requests (e.g.
+/// unauthenticated or rate limited) are expected and never reported. Return the id of the
+/// captured event, `None` if nothing was sent (e.g. no DSN configured)
+fn capture_boxed_error(err: &BoxError, hub: Arc<Hub>) -> Option<Uuid> {
+    let event_id = if let Some(e) = err.downcast_ref::<Error>() {
         // downcast to `tonic::transport::Error`
         error!("failure in service layer: {:?}", e);
-        hub.capture_error(e);
-    } else if let Some(e) = err.downcast_ref::<ServiceError>() {
-        // downcast to `crate::app::util::error::GeekyRepercussion`
-        error!("failure in service layer: {:?}", e);
-        hub.add_breadcrumb(Breadcrumb {
-            ty: "service layer".to_string(),
-            message: Some(format!("failure in service layer: {:?}", e)),
+        hub.capture_error(e)
+    } else if let Some(status) = err.downcast_ref::<Status>() {
+        // downcast to `tonic::Status` converted from `crate::app::util::error::ServiceError`
+        if !is_server_error(status.code()) {
+            return None;
+        }
+
+        error!("failure in service layer: {:?}", status);
+        let mut event = Event {
+            message: Some(format!("{:?}: {}", status.code(), status.message())),
+            level: Level::Error,
             ..Default::default()
-        });
-        hub.capture_message(
-            "Service encountered failure while attempting to process service layer",
-            Level::Error,
+        };
+        event.tags.insert(
+            "grpc.status_code".to_string(),
+            format!("{:?}", status.code()),
         );
-    }
+
+        hub.capture_event(event)
+    } else {
+        return None;
+    };
+
+    // the nil id is returned when the event was discarded
+    (!event_id.is_nil()).then_some(event_id)
 }
 
 /// Build a Sentry request struct from the HTTP request
@@ -0,0 +1,51 @@
+use super::service::TimeoutMiddleware;
+use std::time::Duration;
+use tower::Layer;
+
+/// Methods exempted from the request timeout. Their calls are long-lived by design: the
+/// response of a server streaming call is produced after its handler returned, and a client
+/// streaming call complete only once the client finished sending.
+pub const STREAMING_METHODS: &[&str] = &[
+    "/test_message.TestMessageService/StreamMessage",
+    "/test_message.TestMessageService/EventMessage",
+    "/test_message.TestMessageService/ChatMessage",
+    "/test_message.TestMessageService/DelayedEcho",
+    "/test_message.TestMessageService/UploadChunks",
+    "/test_message.TestMessageService/StreamAggregates",
+    "/amqp_subscription.AmqpSubscriptionService/Subscribe",
+    "/grpc.health.v1.Health/Watch",
+    "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+];
+
+/// fail every unary call that did not produce its response within the configured timeout with
+/// `ServiceError::RequestTimeout`, so a handler stuck on a backend cannot hang the call forever.
+/// Streaming calls (see `STREAMING_METHODS`) are let through, they are bounded by the client
+/// deadline and the stream send timeout instead. A client `grpc-timeout` shorter than the
+/// timeout is enforced by tonic on its own
+#[derive(Debug, Clone)]
+pub struct TimeoutLayer {
+    timeout: Option<Duration>,
+}
+
+impl TimeoutLayer {
+    /// Creates a new timeout middleware. Calls are never timed out when `timeout` is `None`.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        TimeoutLayer { timeout }
+    }
+
+    /// timeout applied to the call of the gRPC method at `path`, `None` if exempted
+    pub fn timeout_of(&self, path: &str) -> Option<Duration> {
+        self.timeout.filter(|_| !STREAMING_METHODS.contains(&path))
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = TimeoutMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TimeoutMiddleware {
+            inner,
+            config: self.clone(),
+        }
+    }
+}
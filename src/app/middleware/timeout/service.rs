@@ -0,0 +1,155 @@
+use super::layer::TimeoutLayer;
+use crate::app::util::error::ServiceError;
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+
+#[derive(Debug, Clone)]
+pub struct TimeoutMiddleware<S> {
+    pub inner: S,
+    pub config: TimeoutLayer,
+}
+
+impl<S> Service<hyper::Request<Body>> for TimeoutMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let timeout = self.config.timeout_of(req.uri().path());
+
+        async move {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return inner.call(req).await,
+            };
+
+            // dropping the inner future on elapse cancel the handler at its current await point
+            match tokio::time::timeout(timeout, inner.call(req)).await {
+                Ok(response) => response,
+                Err(_) => Err(Box::new(Status::from(ServiceError::RequestTimeout(
+                    timeout.as_secs(),
+                ))) as BoxError),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tonic::Code;
+    use tower::{util::BoxCloneService, Layer};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    const SEND_MESSAGE: &str = "/test_message.TestMessageService/SendMessage";
+    const EVENT_MESSAGE: &str = "/test_message.TestMessageService/EventMessage";
+
+    /// middleware around a handler taking `latency` to respond, which set `completed` once done
+    fn middleware(
+        timeout: Option<Duration>,
+        latency: Duration,
+        completed: &Arc<AtomicBool>,
+    ) -> TimeoutMiddleware<Inner> {
+        let completed = Arc::clone(completed);
+
+        TimeoutLayer::new(timeout).layer(BoxCloneService::new(tower::service_fn(
+            move |_: hyper::Request<Body>| {
+                let completed = Arc::clone(&completed);
+
+                async move {
+                    tokio::time::sleep(latency).await;
+                    completed.store(true, Ordering::SeqCst);
+
+                    Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+                }
+            },
+        )))
+    }
+
+    fn request(path: &str) -> hyper::Request<Body> {
+        hyper::Request::post(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_unary_call_is_deadline_exceeded() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let mut middleware = middleware(
+            Some(Duration::from_millis(20)),
+            Duration::from_millis(500),
+            &completed,
+        );
+
+        let error = middleware.call(request(SEND_MESSAGE)).await.unwrap_err();
+
+        assert_eq!(
+            error.downcast::<Status>().unwrap().code(),
+            Code::DeadlineExceeded
+        );
+        // the handler was cancelled rather than left running
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(!completed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn fast_unary_call_completes() {
+        let completed = Arc::new(AtomicBool::new(false));
+        let mut middleware = middleware(
+            Some(Duration::from_millis(500)),
+            Duration::from_millis(10),
+            &completed,
+        );
+
+        middleware.call(request(SEND_MESSAGE)).await.unwrap();
+
+        assert!(completed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn streaming_calls_and_disabled_timeout_are_not_bounded() {
+        let completed = Arc::new(AtomicBool::new(false));
+
+        middleware(
+            Some(Duration::from_millis(20)),
+            Duration::from_millis(100),
+            &completed,
+        )
+        .call(request(EVENT_MESSAGE))
+        .await
+        .unwrap();
+        middleware(None, Duration::from_millis(100), &completed)
+            .call(request(SEND_MESSAGE))
+            .await
+            .unwrap();
+    }
+}
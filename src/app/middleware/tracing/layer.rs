@@ -1,13 +1,33 @@
 use super::service::TracingMiddleware;
+use hyper::header::HeaderName;
+use std::sync::Arc;
 use tower::Layer;
 
+/// upper bound of the number of extra metadata recorded on the request span
+pub const MAX_EXTRA_HEADERS: usize = 8;
+
 #[derive(Debug, Clone)]
-pub struct TracingLayer;
+pub struct TracingLayer {
+    extra_headers: Arc<Vec<HeaderName>>,
+}
+
+impl TracingLayer {
+    /// Creates a new tracing middleware recording the value of every `extra_headers` metadata
+    /// present on the request. Only the first `MAX_EXTRA_HEADERS` headers are kept.
+    pub fn new(extra_headers: Vec<HeaderName>) -> Self {
+        TracingLayer {
+            extra_headers: Arc::new(extra_headers.into_iter().take(MAX_EXTRA_HEADERS).collect()),
+        }
+    }
+}
 
 impl<S> Layer<S> for TracingLayer {
     type Service = TracingMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        TracingMiddleware { inner }
+        TracingMiddleware {
+            inner,
+            extra_headers: Arc::clone(&self.extra_headers),
+        }
     }
 }
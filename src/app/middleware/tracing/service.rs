@@ -1,23 +1,41 @@
+use crate::app::util::{context::RequestContext, deadline::RequestDeadline, text::truncate_utf8};
 use futures::future::{BoxFuture, FutureExt as _};
-use hyper::Body;
-use tonic::body::BoxBody;
-use tower::Service;
-use tracing::{field::Empty, info_span, Span};
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    header::{HeaderMap, HeaderName, CONTENT_LENGTH},
+    Body,
+};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+use tracing::{field::Empty, info, info_span, warn, Span};
 use tracing_futures::Instrument;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct TracingMiddleware<S> {
     pub inner: S,
+    pub extra_headers: Arc<Vec<HeaderName>>,
 }
 
+/// upper bound (in bytes) of each extra metadata value recorded on the request span
+const MAX_EXTRA_HEADER_BYTES: usize = 128;
+
 impl<S> Service<hyper::Request<Body>> for TracingMiddleware<S>
 where
-    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>> + Clone + Send + 'static,
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
-    type Error = S::Error;
+    type Error = BoxError;
     type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(
@@ -27,7 +45,7 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: hyper::Request<Body>) -> Self::Future {
         // This is necessary because tonic internally uses `tower::buffer::Buffer`.
         // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
         // for details on why this is necessary
@@ -59,6 +77,13 @@ where
             .scheme()
             .map_or(Default::default(), |scheme| scheme.as_str());
         let request_id = Uuid::new_v4();
+        // correlate with the upstream distributed trace when the caller sent a valid `traceparent`
+        let trace_id = req
+            .headers()
+            .get("traceparent")
+            .and_then(|header| header.to_str().ok())
+            .and_then(parse_trace_id)
+            .unwrap_or_else(|| request_id.simple().to_string());
 
         let root_span = info_span!(
             "Incoming gRPC request",
@@ -70,19 +95,316 @@ where
             http.user_agent = %user_agent,
             http.user_ip = %user_ip,
             http.status = Empty,
-            request_id = %request_id
+            http.request_content_length = Empty,
+            http.response_content_length = Empty,
+            http.extra_headers = Empty,
+            rpc.grpc.status_code = Empty,
+            request_id = %request_id,
+            trace_id = %trace_id
         );
 
-        async move {
+        if let Some(content_length) = content_length(req.headers()) {
+            root_span.record("http.request_content_length", content_length);
+        }
+
+        if let Some(extra_headers) = extra_headers(req.headers(), &self.extra_headers) {
+            root_span.record("http.extra_headers", &extra_headers[..]);
+        }
+
+        let context = RequestContext::new(request_id.to_string(), req.uri().path().to_string());
+        let method = http_method.to_string();
+        let route = req.uri().path().to_string();
+        let started_at = Instant::now();
+
+        // let handlers stop producing once the client gave up on the call
+        if let Some(deadline) = RequestDeadline::from_headers(req.headers()) {
+            req.extensions_mut().insert(deadline);
+        }
+
+        let response = async move {
             match inner.call(req).await {
                 Ok(res) => {
-                    Span::current().record("http.status", &&res.status().to_string()[..]);
-                    Ok(res)
+                    let span = Span::current();
+                    span.record("http.status", &res.status().to_string()[..]);
+
+                    if let Some(content_length) = content_length(res.headers()) {
+                        span.record("http.response_content_length", content_length);
+                    }
+
+                    // a trailers-only response (e.g. an immediate error) carry the status in its
+                    // headers, otherwise it only arrives with the trailers at the end of the body
+                    let code = grpc_status_code(res.headers());
+
+                    // one line per request for the access log, streams are logged once their
+                    // response headers are ready rather than when they end
+                    info!(
+                        duration_ms = started_at.elapsed().as_millis() as u64,
+                        http.method = %method,
+                        http.route = %route,
+                        http.status = res.status().as_u16(),
+                        rpc.grpc.status_code = code,
+                        "request completed"
+                    );
+
+                    match code {
+                        Some(code) => {
+                            span.record("rpc.grpc.status_code", code);
+
+                            Ok(res)
+                        }
+                        None => {
+                            Ok(res
+                                .map(|body| BoxBody::new(GrpcStatusRecorder { inner: body, span })))
+                        }
+                    }
+                }
+                Err(e) => {
+                    let code = e
+                        .downcast_ref::<Status>()
+                        .map(|status| status.code() as i32);
+
+                    if let Some(code) = code {
+                        Span::current().record("rpc.grpc.status_code", code);
+                    }
+
+                    warn!(
+                        duration_ms = started_at.elapsed().as_millis() as u64,
+                        http.method = %method,
+                        http.route = %route,
+                        rpc.grpc.status_code = code,
+                        error = %e,
+                        "request failed"
+                    );
+
+                    Err(e)
                 }
-                Err(e) => Err(e),
+            }
+        };
+
+        RequestContext::scope(Some(context), response)
+            .instrument(root_span)
+            .boxed()
+    }
+}
+
+/// format the configured extra metadata present in `headers` as `name=value` pairs separated by
+/// `; `. Values are capped to `MAX_EXTRA_HEADER_BYTES` and any non printable ASCII character as
+/// well as the `;` separator is replaced by `_`. Return `None` if none of them is present
+fn extra_headers(headers: &HeaderMap, names: &[HeaderName]) -> Option<String> {
+    let fields = names
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name)?;
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let value = truncate_utf8(&value, MAX_EXTRA_HEADER_BYTES)
+                .chars()
+                .map(|c| match c {
+                    ';' => '_',
+                    c if c == ' ' || c.is_ascii_graphic() => c,
+                    _ => '_',
+                })
+                .collect::<String>();
+
+            Some(format!("{}={}", name, value))
+        })
+        .collect::<Vec<_>>();
+
+    (!fields.is_empty()).then(|| fields.join("; "))
+}
+
+/// response body recording the `grpc-status` found in the trailers on the request span
+struct GrpcStatusRecorder {
+    inner: BoxBody,
+    span: Span,
+}
+
+impl HttpBody for GrpcStatusRecorder {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = ready!(Pin::new(&mut self.inner).poll_trailers(cx));
+
+        if let Ok(Some(trailers)) = &trailers {
+            if let Some(code) = grpc_status_code(trailers) {
+                self.span.record("rpc.grpc.status_code", code);
+            }
+        }
+
+        Poll::Ready(trailers)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// parse the numeric `grpc-status` header. Return `None` if absent or not a valid integer
+fn grpc_status_code(headers: &HeaderMap) -> Option<i32> {
+    headers
+        .get("grpc-status")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse().ok())
+}
+
+/// parse the `content-length` header. Return `None` if absent or not a valid integer
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse().ok())
+}
+
+/// extract the trace-id from a W3C Trace Context `traceparent` header formatted as
+/// `{version}-{trace-id}-{parent-id}-{trace-flags}`. Return `None` if the header is malformed
+fn parse_trace_id(traceparent: &str) -> Option<String> {
+    let is_hex = |value: &str, len: usize| {
+        value.len() == len
+            && value
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let trace_flags = parts.next()?;
+
+    // version `ff` is forbidden and version `00` must not carry any extra field
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+
+    // all zero trace-id and parent-id are invalid
+    if !is_hex(trace_id, 32)
+        || !is_hex(parent_id, 16)
+        || !is_hex(trace_flags, 2)
+        || trace_id.bytes().all(|b| b == b'0')
+        || parent_id.bytes().all(|b| b == b'0')
+    {
+        return None;
+    }
+
+    Some(trace_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::middleware::tracing::layer::TracingLayer;
+    use std::{fmt, sync::Mutex};
+    use tower::{util::BoxCloneService, Layer as _};
+    use tracing::{
+        field::{Field, Visit},
+        span::{Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context as LayerContext, prelude::*, Layer, Registry};
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    /// keep the last value recorded into the `http.extra_headers` field of any span
+    #[derive(Clone, Default)]
+    struct ExtraHeadersRecorder(Arc<Mutex<Option<String>>>);
+
+    impl<S: Subscriber> Layer<S> for ExtraHeadersRecorder {
+        fn on_record(&self, _: &Id, values: &Record<'_>, _: LayerContext<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    impl Visit for ExtraHeadersRecorder {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "http.extra_headers" {
+                *self.0.lock().unwrap() = Some(value.to_string());
             }
         }
-        .instrument(root_span)
-        .boxed()
+
+        fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+    }
+
+    /// extra headers recorded on the request span of `req` by a middleware configured with the
+    /// `x-tenant-id` and `x-app-build` extra headers
+    async fn recorded_extra_headers(req: hyper::Request<Body>) -> Option<String> {
+        let recorder = ExtraHeadersRecorder::default();
+        let _default = Registry::default().with(recorder.clone()).set_default();
+        let mut middleware = TracingLayer::new(vec![
+            HeaderName::from_static("x-tenant-id"),
+            HeaderName::from_static("x-app-build"),
+        ])
+        .layer(Inner::new(tower::service_fn(
+            |_: hyper::Request<Body>| async {
+                Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+            },
+        )));
+
+        middleware.call(req).await.unwrap();
+
+        let recorded = recorder.0.lock().unwrap().clone();
+
+        recorded
+    }
+
+    #[tokio::test]
+    async fn configured_headers_are_recorded_on_the_span() {
+        let req = hyper::Request::post("/test_message.TestMessageService/SendMessage")
+            .header("x-tenant-id", "acme")
+            // obsolete non ASCII header bytes are still accepted by hyper
+            .header(
+                "x-app-build",
+                hyper::header::HeaderValue::from_bytes(b"1.4.2; b\xe9ta").unwrap(),
+            )
+            .header("x-session-secret", "hunter2")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            recorded_extra_headers(req).await.as_deref(),
+            Some("x-tenant-id=acme; x-app-build=1.4.2_ b_ta")
+        );
+    }
+
+    #[tokio::test]
+    async fn unconfigured_headers_are_not_recorded() {
+        let req = hyper::Request::post("/test_message.TestMessageService/SendMessage")
+            .header("x-session-secret", "hunter2")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(recorded_extra_headers(req).await, None);
+    }
+
+    #[test]
+    fn extra_header_values_are_capped() {
+        let name = HeaderName::from_static("x-tenant-id");
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            &name,
+            "a".repeat(MAX_EXTRA_HEADER_BYTES * 2).parse().unwrap(),
+        );
+
+        let recorded = extra_headers(&headers, &[name]).unwrap();
+
+        assert!(recorded.starts_with(&format!(
+            "x-tenant-id={}",
+            "a".repeat(MAX_EXTRA_HEADER_BYTES)
+        )));
+        assert!(recorded.len() < MAX_EXTRA_HEADER_BYTES + 64);
     }
 }
@@ -0,0 +1,18 @@
+use super::service::ViaMiddleware;
+use tower::Layer;
+
+/// detect proxy loops using the `x-via` metadata. The wrapped value is the id this service
+/// identify itself with
+#[derive(Debug, Clone)]
+pub struct ViaLayer(pub String);
+
+impl<S> Layer<S> for ViaLayer {
+    type Service = ViaMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ViaMiddleware {
+            inner,
+            service_id: self.0.clone(),
+        }
+    }
+}
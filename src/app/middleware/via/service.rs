@@ -0,0 +1,152 @@
+use crate::app::util::{context::RequestContext, error::ServiceError};
+use futures::future::{BoxFuture, FutureExt as _};
+use hyper::Body;
+use tonic::{body::BoxBody, Status};
+use tower::{BoxError, Service};
+
+pub const VIA_HEADER: &str = "x-via";
+
+#[derive(Debug, Clone)]
+pub struct ViaMiddleware<S> {
+    pub inner: S,
+    pub service_id: String,
+}
+
+impl<S> Service<hyper::Request<Body>> for ViaMiddleware<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        // This is necessary because tonic internally uses `tower::buffer::Buffer`.
+        // See https://github.com/tower-rs/tower/issues/547#issuecomment-767629149
+        // for details on why this is necessary
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let service_id = self.service_id.clone();
+
+        async move {
+            if has_visited(&req, &service_id) {
+                return Err(Box::new(Status::from(ServiceError::ProxyLoop(service_id))) as BoxError);
+            }
+
+            if let Some(context) = RequestContext::current() {
+                context.set_via(outbound_via(&req, &service_id));
+            }
+
+            inner.call(req).await
+        }
+        .boxed()
+    }
+}
+
+/// whether `service_id` is already listed in any of the comma separated `x-via` values
+fn has_visited(req: &hyper::Request<Body>, service_id: &str) -> bool {
+    req.headers()
+        .get_all(VIA_HEADER)
+        .iter()
+        .filter_map(|via| via.to_str().ok())
+        .flat_map(|via| via.split(','))
+        .any(|via| via.trim() == service_id)
+}
+
+/// the inbound `x-via` values followed by `service_id`. Outbound requests carry it (see
+/// `RequestContext::via()`) so the loop is detected when the call come back to this instance
+fn outbound_via(req: &hyper::Request<Body>, service_id: &str) -> String {
+    req.headers()
+        .get_all(VIA_HEADER)
+        .iter()
+        .filter_map(|via| via.to_str().ok())
+        .flat_map(|via| via.split(','))
+        .map(str::trim)
+        .filter(|via| !via.is_empty())
+        .chain(std::iter::once(service_id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+    use tower::util::BoxCloneService;
+
+    const SERVICE_ID: &str = "demo-api-1";
+
+    type Inner = BoxCloneService<hyper::Request<Body>, hyper::Response<BoxBody>, BoxError>;
+
+    fn middleware() -> ViaMiddleware<Inner> {
+        ViaMiddleware {
+            inner: BoxCloneService::new(tower::service_fn(|_: hyper::Request<Body>| async {
+                Ok::<_, BoxError>(hyper::Response::new(tonic::body::empty_body()))
+            })),
+            service_id: SERVICE_ID.to_string(),
+        }
+    }
+
+    fn request(via: &[&str]) -> hyper::Request<Body> {
+        let mut req = hyper::Request::builder();
+
+        for via in via {
+            req = req.header(VIA_HEADER, *via);
+        }
+
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_carrying_our_id_is_rejected() {
+        for via in [
+            &[SERVICE_ID][..],
+            &["gateway, demo-api-1"],
+            &["gateway", SERVICE_ID],
+        ] {
+            let error = middleware().call(request(via)).await.unwrap_err();
+
+            assert_eq!(
+                error.downcast::<Status>().unwrap().code(),
+                Code::FailedPrecondition,
+                "{:?}",
+                via
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn request_without_our_id_passes_and_records_the_outbound_via() {
+        let context = RequestContext::new("request".to_string(), "method".to_string());
+
+        RequestContext::scope(
+            Some(context.clone()),
+            middleware().call(request(&["gateway, demo-api-10"])),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(context.via(), Some("gateway, demo-api-10, demo-api-1"));
+    }
+
+    #[test]
+    fn outbound_via_adds_our_id() {
+        assert_eq!(outbound_via(&request(&[]), SERVICE_ID), SERVICE_ID);
+        assert_eq!(
+            outbound_via(&request(&["gateway", "edge,"]), SERVICE_ID),
+            "gateway, edge, demo-api-1"
+        );
+    }
+}
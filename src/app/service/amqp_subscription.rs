@@ -0,0 +1,556 @@
+use self::amqp_subscription::{
+    amqp_subscription_service_server::AmqpSubscriptionService, subscription_notice::Kind,
+    AmqpMessage, SubscriptionCommandInitial, SubscriptionNotice,
+};
+use crate::app::{
+    config::{
+        amqp::{AmqpConnection, RECONNECT_BACKOFF_BASE, RECONNECT_BACKOFF_MAX},
+        task::spawn_with_name,
+    },
+    util::{
+        error::ServiceError,
+        metrics::STREAM_METRICS,
+        shutdown::ShutdownSignal,
+        stream::{stream_aborted, warn_on_backlog, ClientCancellableStream},
+        validator::validate_request,
+    },
+};
+use futures::{Stream, StreamExt};
+use lapin::{
+    acker::Acker,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
+    types::FieldTable,
+    Channel, Connection, Consumer,
+};
+use sentry::{Hub, SentryFutureExt};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Notify},
+    time::{sleep, timeout},
+};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, warn};
+use tracing_futures::Instrument;
+
+#[allow(clippy::module_inception)]
+pub mod amqp_subscription {
+    tonic::include_proto!("amqp_subscription");
+}
+
+pub struct AmqpSubscriptionGreeter {
+    pub(crate) shutdown_signal_notifier: Arc<ShutdownSignal>,
+    pub(crate) connection: Arc<AmqpConnection>,
+    pub(crate) exchange: String,
+    pub(crate) operation_timeout: Duration,
+}
+
+#[tonic::async_trait]
+impl AmqpSubscriptionService for AmqpSubscriptionGreeter {
+    type SubscribeStream = ClientCancellableStream<Result<AmqpMessage, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscriptionCommandInitial>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let command = request.into_inner();
+
+        validate_request(&command)?;
+
+        let operation_timeout = self.operation_timeout;
+        let subscription = Subscription {
+            exchange: self.exchange.clone(),
+            routing_keys: command.subscriptions,
+            operation_timeout,
+        };
+        let connection = self.connection.current().await;
+        let (channel, consumer) = subscription.open(&connection).await?;
+        let source = LapinSource {
+            subscription,
+            amqp_connection: Arc::clone(&self.connection),
+            connection,
+            channel,
+        };
+
+        let (responder, response_stream, cancellation_notifier) = ClientCancellableStream::new();
+        let completion = response_stream.completion(&responder, stream_aborted);
+        let shutdown_signal_notifier = Arc::clone(&self.shutdown_signal_notifier);
+        let hub = Hub::current();
+
+        spawn_with_name(
+            async move {
+                forward_deliveries(
+                    source,
+                    consumer,
+                    &responder,
+                    &cancellation_notifier,
+                    &shutdown_signal_notifier,
+                    operation_timeout,
+                )
+                .await;
+
+                completion.finish();
+            }
+            .in_current_span()
+            .bind_hub(hub),
+            "amqp_subscription",
+        );
+
+        Ok(Response::new(response_stream))
+    }
+}
+
+/// forward the `deliveries` of `source` to the client until it goes away or the server shuts
+/// down. A lost consumer is resumed through `source` and the client is told to resync
+async fn forward_deliveries<S>(
+    mut source: S,
+    mut deliveries: S::Deliveries,
+    responder: &mpsc::Sender<Result<AmqpMessage, Status>>,
+    cancellation_notifier: &Notify,
+    shutdown_signal_notifier: &ShutdownSignal,
+    operation_timeout: Duration,
+) where
+    S: SubscriptionSource,
+{
+    loop {
+        let delivery = tokio::select! {
+            delivery = deliveries.next() => delivery,
+            _ = cancellation_notifier.notified() => break,
+            _ = shutdown_signal_notifier.notified() => {
+                let _ = responder.send(Err(ServiceError::ShuttingDown.into())).await;
+                break;
+            }
+        };
+
+        match delivery {
+            Some(Ok(delivery)) => {
+                let message = AmqpMessage {
+                    routing_key: delivery.routing_key.as_str().to_string(),
+                    payload: delivery.data.clone(),
+                    notice: None,
+                };
+
+                warn_on_backlog(responder);
+
+                // only ack once the message reached the stream, a message read but never
+                // forwarded is handed back to the broker
+                let forwarded = responder.send(Ok(message)).await;
+                let settled =
+                    settle_delivery(&delivery.acker, forwarded.is_ok(), operation_timeout).await;
+
+                match settled {
+                    Ok(()) => {}
+                    Err(e @ ServiceError::QueueBasicAckTimeout) => warn!("{}", e),
+                    Err(e) => error!("amqp delivery settlement failed: {}", e),
+                }
+
+                if let Err(error) = forwarded {
+                    error!("response failed: {}", error);
+                    STREAM_METRICS.items_dropped(1);
+                    break;
+                }
+            }
+            // the consumer only fail or end once its channel or the connection is gone
+            lost => {
+                match lost {
+                    Some(Err(e)) => warn!("amqp subscription lost: {}", e),
+                    _ => warn!("amqp subscription consumer closed by the broker"),
+                }
+
+                deliveries = tokio::select! {
+                    resumed = source.resume() => resumed,
+                    _ = cancellation_notifier.notified() => break,
+                    _ = shutdown_signal_notifier.notified() => {
+                        let _ = responder.send(Err(ServiceError::ShuttingDown.into())).await;
+                        break;
+                    }
+                };
+
+                let notice = AmqpMessage {
+                    notice: Some(SubscriptionNotice {
+                        kind: Kind::Resync.into(),
+                        detail: "subscription resumed on a new broker connection".to_string(),
+                    }),
+                    ..Default::default()
+                };
+
+                if responder.send(Ok(notice)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    source.close().await;
+}
+
+#[tonic::async_trait]
+/// where the deliveries of a subscription come from, implemented by `LapinSource` and faked in
+/// tests
+trait SubscriptionSource: Send {
+    type Deliveries: Stream<Item = Result<Delivery, lapin::Error>> + Unpin + Send;
+
+    /// reopen the subscription once its deliveries ended or failed
+    async fn resume(&mut self) -> Self::Deliveries;
+
+    /// release the subscription once nothing is forwarded anymore
+    async fn close(self);
+}
+
+/// subscription consuming a queue on a channel of the shared broker connection
+struct LapinSource {
+    subscription: Subscription,
+    amqp_connection: Arc<AmqpConnection>,
+    connection: Arc<Connection>,
+    channel: Channel,
+}
+
+#[tonic::async_trait]
+impl SubscriptionSource for LapinSource {
+    type Deliveries = Consumer;
+
+    async fn resume(&mut self) -> Consumer {
+        let lost = Arc::clone(&self.connection);
+        let (connection, channel, consumer) =
+            self.subscription.resume(&self.amqp_connection, lost).await;
+
+        self.connection = connection;
+        self.channel = channel;
+
+        consumer
+    }
+
+    async fn close(self) {
+        if let Err(e) = self.channel.close(200, "subscription closed").await {
+            debug!("amqp channel close failed: {}", e);
+        }
+    }
+}
+
+/// queue bindings of a subscription, replayed on a new channel once the previous one was lost
+struct Subscription {
+    exchange: String,
+    routing_keys: Vec<String>,
+    operation_timeout: Duration,
+}
+
+impl Subscription {
+    /// declare a server named queue on a new channel of `connection`, bind it to every routing
+    /// key and start consuming it. Every step is bounded by `operation_timeout`
+    async fn open(&self, connection: &Connection) -> Result<(Channel, Consumer), ServiceError> {
+        let operation_timeout = self.operation_timeout;
+        let channel = connection
+            .create_channel()
+            .await
+            .map_err(ServiceError::from)?;
+        // every subscription get its own server named queue which is removed by the broker once
+        // the channel is closed
+        let queue = timeout(
+            operation_timeout,
+            channel.queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            ),
+        )
+        .await
+        .map_err(|_| ServiceError::QueueDeclareTimeout)?
+        .map_err(ServiceError::from)?;
+
+        for routing_key in &self.routing_keys {
+            timeout(
+                operation_timeout,
+                channel.queue_bind(
+                    queue.name().as_str(),
+                    &self.exchange,
+                    routing_key,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                ),
+            )
+            .await
+            .map_err(|_| ServiceError::QueueBindTimeout)?
+            .map_err(ServiceError::from)?;
+        }
+
+        let consumer = timeout(
+            operation_timeout,
+            channel.basic_consume(
+                queue.name().as_str(),
+                "",
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            ),
+        )
+        .await
+        .map_err(|_| ServiceError::QueueBasicConsumeTimeout)?
+        .map_err(ServiceError::from)?;
+
+        Ok((channel, consumer))
+    }
+
+    /// reopen the subscription once its channel on `lost` is gone, re-establishing the broker
+    /// connection first if needed. Retried with an exponential backoff until it succeeded
+    async fn resume(
+        &self,
+        amqp_connection: &AmqpConnection,
+        lost: Arc<Connection>,
+    ) -> (Arc<Connection>, Channel, Consumer) {
+        let mut lost = lost;
+        let mut backoff = RECONNECT_BACKOFF_BASE;
+
+        loop {
+            let connection = amqp_connection.reconnect(&lost).await;
+
+            match self.open(&connection).await {
+                Ok((channel, consumer)) => return (connection, channel, consumer),
+                Err(e) => {
+                    warn!(
+                        "amqp subscription resume failed, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                    lost = connection;
+                }
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+/// settlement of a single delivery, implemented by `Acker` and faked in tests
+trait DeliveryAcker: Sync {
+    async fn ack(&self) -> Result<(), lapin::Error>;
+    async fn nack_requeue(&self) -> Result<(), lapin::Error>;
+}
+
+#[tonic::async_trait]
+impl DeliveryAcker for Acker {
+    async fn ack(&self) -> Result<(), lapin::Error> {
+        Acker::ack(self, BasicAckOptions::default()).await
+    }
+
+    async fn nack_requeue(&self) -> Result<(), lapin::Error> {
+        self.nack(BasicNackOptions {
+            requeue: true,
+            ..Default::default()
+        })
+        .await
+    }
+}
+
+/// ack the delivery if it was `forwarded` to the client, otherwise nack it with requeue so it is
+/// not lost. Both are bounded by `operation_timeout`
+async fn settle_delivery<A>(
+    acker: &A,
+    forwarded: bool,
+    operation_timeout: Duration,
+) -> Result<(), ServiceError>
+where
+    A: DeliveryAcker,
+{
+    let settlement = if forwarded {
+        timeout(operation_timeout, acker.ack()).await
+    } else {
+        timeout(operation_timeout, acker.nack_requeue()).await
+    };
+
+    settlement
+        .map_err(|_| ServiceError::QueueBasicAckTimeout)?
+        .map_err(ServiceError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::{self, BoxStream};
+    use lapin::{protocol::BasicProperties, ConnectionState};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    const OPERATION_TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[derive(Default)]
+    /// acker recording the settlements it receives
+    struct RecordingAcker {
+        settlements: Mutex<Vec<&'static str>>,
+        /// never answer, like a broker that stopped responding
+        hang: bool,
+    }
+
+    impl RecordingAcker {
+        async fn settle(&self, settlement: &'static str) -> Result<(), lapin::Error> {
+            self.settlements.lock().unwrap().push(settlement);
+
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[tonic::async_trait]
+    impl DeliveryAcker for RecordingAcker {
+        async fn ack(&self) -> Result<(), lapin::Error> {
+            self.settle("ack").await
+        }
+
+        async fn nack_requeue(&self) -> Result<(), lapin::Error> {
+            self.settle("nack_requeue").await
+        }
+    }
+
+    /// forward a message into `responder` then settle its delivery like the subscription does
+    async fn forward(
+        responder: &tokio::sync::mpsc::Sender<Result<AmqpMessage, Status>>,
+        acker: &RecordingAcker,
+    ) -> Result<(), ServiceError> {
+        let forwarded = responder.send(Ok(AmqpMessage::default())).await;
+
+        settle_delivery(acker, forwarded.is_ok(), OPERATION_TIMEOUT).await
+    }
+
+    #[tokio::test]
+    async fn forwarded_delivery_is_acked() {
+        let (responder, _response_stream, _) =
+            ClientCancellableStream::<Result<AmqpMessage, Status>>::new();
+        let acker = RecordingAcker::default();
+
+        forward(&responder, &acker).await.unwrap();
+
+        assert_eq!(*acker.settlements.lock().unwrap(), ["ack"]);
+    }
+
+    #[tokio::test]
+    async fn delivery_not_forwarded_is_nacked_with_requeue() {
+        let (responder, response_stream, _) =
+            ClientCancellableStream::<Result<AmqpMessage, Status>>::new();
+        let acker = RecordingAcker::default();
+
+        // the client went away
+        drop(response_stream);
+        forward(&responder, &acker).await.unwrap();
+
+        assert_eq!(*acker.settlements.lock().unwrap(), ["nack_requeue"]);
+    }
+
+    #[tokio::test]
+    async fn unanswered_settlement_times_out() {
+        let acker = RecordingAcker {
+            hang: true,
+            ..Default::default()
+        };
+        let error = settle_delivery(&acker, true, OPERATION_TIMEOUT)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, ServiceError::QueueBasicAckTimeout));
+    }
+
+    type Deliveries = BoxStream<'static, Result<Delivery, lapin::Error>>;
+
+    fn delivery(routing_key: &str) -> Result<Delivery, lapin::Error> {
+        Ok(Delivery {
+            delivery_tag: 1,
+            exchange: "events".into(),
+            routing_key: routing_key.into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: routing_key.as_bytes().to_vec(),
+            acker: Acker::default(),
+        })
+    }
+
+    /// source whose every resume hand out the next of `resumed`, like a broker coming back
+    struct FakeSource {
+        resumed: Vec<Deliveries>,
+        resumes: Arc<AtomicUsize>,
+        closed: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl SubscriptionSource for FakeSource {
+        type Deliveries = Deliveries;
+
+        async fn resume(&mut self) -> Deliveries {
+            self.resumes.fetch_add(1, Ordering::SeqCst);
+
+            match self.resumed.is_empty() {
+                true => stream::pending().boxed(),
+                false => self.resumed.remove(0),
+            }
+        }
+
+        async fn close(self) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_connection_is_resumed_and_delivery_continue() {
+        let (responder, mut response_stream, cancellation_notifier) =
+            ClientCancellableStream::<Result<AmqpMessage, Status>>::new();
+        let shutdown_signal_notifier = ShutdownSignal::new();
+        let resumes = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicUsize::new(0));
+        let source = FakeSource {
+            resumed: vec![stream::iter([delivery("after")])
+                .chain(stream::pending())
+                .boxed()],
+            resumes: Arc::clone(&resumes),
+            closed: Arc::clone(&closed),
+        };
+        // the connection drops right after the first delivery
+        let deliveries = stream::iter([
+            delivery("before"),
+            Err(lapin::Error::InvalidConnectionState(
+                ConnectionState::Closed,
+            )),
+        ])
+        .boxed();
+
+        let forwarding = tokio::spawn(async move {
+            forward_deliveries(
+                source,
+                deliveries,
+                &responder,
+                &cancellation_notifier,
+                &shutdown_signal_notifier,
+                OPERATION_TIMEOUT,
+            )
+            .await
+        });
+
+        let mut received = vec![];
+
+        for _ in 0..3 {
+            received.push(response_stream.next().await.unwrap().unwrap());
+        }
+
+        assert_eq!(received[0].routing_key, "before");
+        assert_eq!(
+            received[1].notice.as_ref().map(|notice| notice.kind),
+            Some(Kind::Resync as i32)
+        );
+        assert_eq!(received[2].routing_key, "after");
+        assert_eq!(received[2].payload, b"after");
+        assert_eq!(resumes.load(Ordering::SeqCst), 1);
+
+        // the client going away stop the forwarding and release the subscription
+        drop(response_stream);
+        forwarding.await.unwrap();
+
+        assert_eq!(closed.load(Ordering::SeqCst), 1);
+    }
+}
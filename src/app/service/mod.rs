@@ -1 +1,3 @@
+#[cfg(feature = "amqp")]
+pub mod amqp_subscription;
 pub mod test_message;
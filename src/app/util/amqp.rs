@@ -0,0 +1,113 @@
+use super::{context::RequestContext, error::ServiceError};
+use crate::app::{config::amqp::connect_amqp, middleware::via::service::VIA_HEADER};
+use lapin::{
+    options::BasicPublishOptions,
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection,
+};
+use std::sync::Arc;
+
+#[tonic::async_trait]
+/// channel the payloads of an `AmqpPublisher` are published through, implemented by
+/// `LapinChannel` and faked in tests
+pub trait PublishChannel: Send + Sync {
+    /// publish `payload` and wait for the broker to confirm it
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), ServiceError>;
+}
+
+/// channel of a broker connection owned by a single publisher
+struct LapinChannel {
+    // the connection must outlive the channel it created
+    _connection: Connection,
+    channel: Channel,
+}
+
+#[tonic::async_trait]
+impl PublishChannel for LapinChannel {
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), ServiceError> {
+        self.channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions::default(),
+                payload,
+                properties,
+            )
+            .await?
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+/// publish payloads to a single AMQP exchange with a fixed routing key
+pub struct AmqpPublisher {
+    channel: Arc<dyn PublishChannel>,
+    exchange: String,
+    routing_key: String,
+}
+
+impl AmqpPublisher {
+    pub fn new(channel: Arc<dyn PublishChannel>, exchange: &str, routing_key: &str) -> Self {
+        AmqpPublisher {
+            channel,
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+        }
+    }
+
+    pub async fn connect(
+        address: &str,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<Self, ServiceError> {
+        let connection = connect_amqp(address).await?;
+        let channel = connection.create_channel().await?;
+
+        Ok(AmqpPublisher::new(
+            Arc::new(LapinChannel {
+                _connection: connection,
+                channel,
+            }),
+            exchange,
+            routing_key,
+        ))
+    }
+
+    /// publish a msgpack encoded `payload` and wait for the broker to confirm it. A payload
+    /// published while serving a request carry its `x-via` header for proxy loop detection
+    pub async fn publish(&self, payload: &[u8]) -> Result<(), ServiceError> {
+        let mut headers = FieldTable::default();
+
+        if let Some(via) = RequestContext::current()
+            .as_ref()
+            .and_then(RequestContext::via)
+        {
+            headers.insert(VIA_HEADER.into(), AMQPValue::LongString(via.into()));
+        }
+
+        self.channel
+            .publish(
+                &self.exchange,
+                &self.routing_key,
+                payload,
+                BasicProperties::default()
+                    .with_content_type("application/msgpack".into())
+                    .with_headers(headers),
+            )
+            .await
+    }
+}
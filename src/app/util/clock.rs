@@ -0,0 +1,74 @@
+use std::{
+    sync::OnceLock,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use time::Duration;
+
+static REFERENCE: OnceLock<(Instant, Duration)> = OnceLock::new();
+
+/// current unix timestamp in seconds. The wall clock is only read once; afterward the time is
+/// advanced with the monotonic clock so NTP adjustments or a wall clock jump on this replica
+/// can never move session timestamps backward
+pub fn unix_now() -> i64 {
+    let (instant, wall) = REFERENCE.get_or_init(|| {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        (Instant::now(), Duration::seconds(wall.as_secs() as i64))
+    });
+
+    (*wall + instant.elapsed()).whole_seconds()
+}
+
+/// remaining lifetime of something granted `ttl` at unix timestamp `since`. Timestamps written
+/// by a replica with a clock ahead of this one are treated as just written and expired ones
+/// return zero, so the result is always clamped between zero and `ttl`
+pub fn remaining_ttl(since: i64, ttl: Duration) -> Duration {
+    let elapsed = Duration::seconds(unix_now().saturating_sub(since)).max(Duration::ZERO);
+
+    (ttl - elapsed).clamp(Duration::ZERO, ttl.max(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::minutes(30);
+
+    #[test]
+    fn session_near_expiry_keeps_its_remaining_ttl() {
+        let remaining = remaining_ttl(unix_now() - TTL.whole_seconds() + 5, TTL);
+
+        // a second boundary may be crossed between the two reads of the clock
+        assert!(remaining > Duration::ZERO);
+        assert!(remaining <= Duration::seconds(5));
+    }
+
+    #[test]
+    fn expired_session_has_no_remaining_ttl() {
+        assert_eq!(
+            remaining_ttl(unix_now() - TTL.whole_seconds() - 1, TTL),
+            Duration::ZERO
+        );
+        assert_eq!(
+            remaining_ttl(unix_now() - TTL.whole_seconds() * 10, TTL),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn timestamp_from_a_clock_ahead_is_treated_as_just_written() {
+        assert_eq!(remaining_ttl(unix_now() + 120, TTL), TTL);
+    }
+
+    #[test]
+    fn unix_now_follows_the_wall_clock() {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        assert!((unix_now() - wall).abs() <= 1);
+    }
+}
@@ -0,0 +1,111 @@
+use super::error::ServiceError;
+use serde::{de::DeserializeOwned, Serialize};
+
+// only the error mirror (`amqp` feature) encode msgpack for now
+#[cfg_attr(not(feature = "amqp"), allow(dead_code))]
+/// encode `value` as msgpack with named fields (a map instead of a positional array) so
+/// non-rust clients can decode it without knowing the field order
+pub fn encode_msgpack<T>(value: &T) -> Result<Vec<u8>, ServiceError>
+where
+    T: Serialize + ?Sized,
+{
+    Ok(rmp_serde::to_vec_named(value)?)
+}
+
+// `echo_msg_pack` decode into a dynamic value, only the tests decode into a typed one for now
+#[cfg_attr(not(test), allow(dead_code))]
+/// decode a msgpack encoded `payload` into `T`. The payload must hold exactly one value, trailing
+/// bytes are rejected. Decoding into `serde::de::IgnoredAny` validate any msgpack value (including
+/// binary data, extensions and maps with non-string keys) without keeping it
+pub fn decode_msgpack<T>(payload: &[u8]) -> Result<T, ServiceError>
+where
+    T: DeserializeOwned,
+{
+    let mut remaining = payload;
+    let value = T::deserialize(&mut rmp_serde::Deserializer::new(&mut remaining))?;
+
+    reject_trailing_bytes(remaining)?;
+
+    Ok(value)
+}
+
+/// decode a msgpack encoded `payload` into a dynamic `rmpv::Value`, keeping every msgpack value
+/// as-is. Trailing bytes are rejected the same as `decode_msgpack()`
+pub fn decode_msgpack_value(payload: &[u8]) -> Result<rmpv::Value, ServiceError> {
+    let mut remaining = payload;
+    let value = rmpv::decode::read_value(&mut remaining).map_err(|e| match e {
+        rmpv::decode::Error::InvalidMarkerRead(e) => rmp_serde::decode::Error::InvalidMarkerRead(e),
+        rmpv::decode::Error::InvalidDataRead(e) => rmp_serde::decode::Error::InvalidDataRead(e),
+        rmpv::decode::Error::DepthLimitExceeded => rmp_serde::decode::Error::DepthLimitExceeded,
+    })?;
+
+    reject_trailing_bytes(remaining)?;
+
+    Ok(value)
+}
+
+/// encode `value` with the shortest representation of every msgpack value it holds
+pub fn encode_msgpack_value(value: &rmpv::Value) -> Result<Vec<u8>, ServiceError> {
+    let mut payload = vec![];
+
+    rmpv::encode::write_value(&mut payload, value).map_err(rmp_serde::encode::Error::from)?;
+
+    Ok(payload)
+}
+
+fn reject_trailing_bytes(remaining: &[u8]) -> Result<(), ServiceError> {
+    if remaining.is_empty() {
+        Ok(())
+    } else {
+        Err(rmp_serde::decode::Error::Syntax(format!(
+            "{} trailing byte(s) after the msgpack value",
+            remaining.len()
+        ))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::IgnoredAny;
+
+    #[test]
+    fn decode_msgpack_accepts_binary_data_ext_and_non_string_keys() {
+        let mut payload = vec![];
+        rmp::encode::write_map_len(&mut payload, 2).unwrap();
+        rmp::encode::write_uint(&mut payload, 1).unwrap();
+        rmp::encode::write_bin(&mut payload, &[0, 159, 255]).unwrap();
+        rmp::encode::write_str(&mut payload, "ext").unwrap();
+        rmp::encode::write_ext_meta(&mut payload, 2, 7).unwrap();
+        payload.extend_from_slice(&[1, 2]);
+
+        assert!(decode_msgpack::<IgnoredAny>(&payload).is_ok());
+    }
+
+    #[test]
+    fn decode_msgpack_rejects_truncated_and_trailing_bytes() {
+        let mut payload = vec![];
+        rmp::encode::write_str(&mut payload, "demo").unwrap();
+
+        assert!(decode_msgpack::<IgnoredAny>(&payload[..payload.len() - 1]).is_err());
+
+        payload.push(0xc0);
+
+        assert!(decode_msgpack::<IgnoredAny>(&payload).is_err());
+    }
+
+    #[test]
+    fn encode_msgpack_names_the_fields() {
+        #[derive(Serialize)]
+        struct Envelope {
+            level: &'static str,
+        }
+
+        let payload = encode_msgpack(&Envelope { level: "error" }).unwrap();
+        let decoded =
+            decode_msgpack::<std::collections::HashMap<String, String>>(&payload).unwrap();
+
+        assert_eq!(decoded["level"], "error");
+    }
+}
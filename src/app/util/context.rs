@@ -0,0 +1,75 @@
+use sha2::{Digest, Sha256};
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+};
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST_CONTEXT: Option<RequestContext>;
+}
+
+#[derive(Debug, Clone)]
+/// identity of the request being served by the current task. It is scoped around every call by
+/// the `TracingLayer` middleware and carried over to tasks spawned through `spawn_with_name()`
+// only the error mirror (`amqp` feature) read the request identity for now
+#[cfg_attr(not(feature = "amqp"), allow(dead_code))]
+pub struct RequestContext {
+    pub request_id: String,
+    pub method: String,
+    uid: Arc<OnceLock<Uuid>>,
+    via: Arc<OnceLock<String>>,
+}
+
+impl RequestContext {
+    pub fn new(request_id: String, method: String) -> Self {
+        RequestContext {
+            request_id,
+            method,
+            uid: Arc::new(OnceLock::new()),
+            via: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// the context of the request served by the current task if any
+    pub fn current() -> Option<RequestContext> {
+        REQUEST_CONTEXT
+            .try_with(|context| context.clone())
+            .ok()
+            .flatten()
+    }
+
+    /// run `future` with `context` as the current request context
+    pub fn scope<F>(context: Option<RequestContext>, future: F) -> impl Future<Output = F::Output>
+    where
+        F: Future,
+    {
+        REQUEST_CONTEXT.scope(context, future)
+    }
+
+    /// record the uid of the authenticated session. Only the first call takes effect
+    pub fn set_uid(&self, uid: Uuid) {
+        let _ = self.uid.set(uid);
+    }
+
+    /// record the `x-via` value outbound requests made on behalf of this request must carry.
+    /// Only the first call takes effect
+    pub fn set_via(&self, via: String) {
+        let _ = self.via.set(via);
+    }
+
+    /// the `x-via` value to forward on outbound requests, see `set_via()`
+    #[cfg_attr(not(feature = "amqp"), allow(dead_code))]
+    pub fn via(&self) -> Option<&str> {
+        self.via.get().map(String::as_str)
+    }
+
+    /// pseudonymous form of the session uid (hex encoded sha256) so the uid itself never leave
+    /// the service while errors of the same user can still be correlated
+    #[cfg_attr(not(feature = "amqp"), allow(dead_code))]
+    pub fn uid_digest(&self) -> Option<String> {
+        self.uid
+            .get()
+            .map(|uid| format!("{:x}", Sha256::digest(uid.as_bytes())))
+    }
+}
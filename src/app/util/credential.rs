@@ -0,0 +1,116 @@
+use super::{error::ServiceError, redis::redis_with_timeout, retry::with_retry};
+use crate::app::config::database::RedisPool;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use cookie::{Cookie, CookieJar, Key};
+use std::{fmt, str::FromStr};
+use tracing::error;
+use uuid::Uuid;
+
+/// the credential lookup is idempotent so a transient failure is worth a second attempt
+const MAX_CREDENTIAL_LOOKUP_ATTEMPTS: usize = 2;
+
+/// redis key holding the credential of `username`. The key is a hash with an `uid` field and a
+/// `password` field containing an argon2 PHC string
+pub fn credential_key(username: &str) -> String {
+    format!("credential:{}", username)
+}
+
+/// verify `password` of `username` against the stored credential and return the user id
+pub async fn verify_credential(
+    redis_pool: &mut RedisPool,
+    username: &str,
+    password: &str,
+) -> Result<Uuid, ServiceError> {
+    let (uid, password_hash) = with_retry(MAX_CREDENTIAL_LOOKUP_ATTEMPTS, || {
+        let mut redis_pool = redis_pool.clone();
+
+        async move {
+            redis_with_timeout(
+                redis::cmd("HMGET")
+                    .arg(credential_key(username))
+                    .arg("uid")
+                    .arg("password")
+                    .query_async::<_, (Option<String>, Option<String>)>(&mut redis_pool),
+            )
+            .await
+        }
+    })
+    .await?;
+
+    let (uid, password_hash) = match (uid, password_hash) {
+        (Some(uid), Some(password_hash)) => (uid, password_hash),
+        _ => return Err(ServiceError::BadCredential),
+    };
+
+    let password = password.to_string();
+
+    // argon2 is intentionally expensive so keep it away from the async executor
+    let verified = tokio::task::spawn_blocking(move || match PasswordHash::new(&password_hash) {
+        Ok(password_hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok(),
+        Err(e) => {
+            error!("malformed password hash: {:?}", e);
+            false
+        }
+    })
+    .await?;
+
+    if verified {
+        Ok(Uuid::parse_str(&uid)?)
+    } else {
+        Err(ServiceError::BadCredential)
+    }
+}
+
+#[derive(Clone)]
+/// HMAC key signing the session cookie so a client can not forge a session id
+pub struct CookieKey(Key);
+
+impl CookieKey {
+    /// sign `cookie` by appending the HMAC of its value to the value
+    pub fn sign(&self, cookie: Cookie<'static>) -> Cookie<'static> {
+        let name = cookie.name().to_string();
+        let mut cookie_jar = CookieJar::new();
+
+        cookie_jar.signed_mut(&self.0).add(cookie);
+
+        cookie_jar
+            .get(&name)
+            .cloned()
+            .expect("expect a signed cookie to be added to the jar")
+    }
+
+    /// return the cookie `name` with its signature stripped. Return `None` if the cookie is
+    /// absent or its signature does not match
+    pub fn verify(&self, cookie_jar: &CookieJar, name: &str) -> Option<Cookie<'static>> {
+        cookie_jar.signed(&self.0).get(name)
+    }
+
+    /// same as `verify()` for the signed `value` of the cookie `name` sent outside of a cookie
+    /// header. Return the value with its signature stripped
+    pub fn verify_value(&self, name: &str, value: &str) -> Option<String> {
+        let mut cookie_jar = CookieJar::new();
+
+        cookie_jar.add(Cookie::new(name.to_string(), value.to_string()));
+
+        self.verify(&cookie_jar, name)
+            .map(|cookie| cookie.value().to_string())
+    }
+}
+
+impl FromStr for CookieKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Key::try_from(value.as_bytes())
+            .map(CookieKey)
+            .map_err(|_| "must be at least 64 bytes long".to_string())
+    }
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CookieKey(..)")
+    }
+}
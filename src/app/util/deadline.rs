@@ -0,0 +1,45 @@
+use hyper::header::HeaderMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// request extension holding the point in time after which the client gave up on the call, as
+/// advertised by its `grpc-timeout` header
+#[derive(Debug, Clone, Copy)]
+pub struct RequestDeadline(pub Instant);
+
+impl RequestDeadline {
+    /// derive the deadline from the `grpc-timeout` header. Return `None` if absent or malformed
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get("grpc-timeout")
+            .and_then(|header| header.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map(|timeout| RequestDeadline(Instant::now() + timeout))
+    }
+}
+
+/// parse a `grpc-timeout` value formatted as at most 8 digits followed by a unit of `H` (hours),
+/// `M` (minutes), `S` (seconds), `m` (millis), `u` (micros) or `n` (nanos)
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+
+    let (amount, unit) = value.split_at(value.len() - 1);
+
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let amount = amount.parse::<u64>().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
@@ -1,7 +1,9 @@
+use std::{str::FromStr, sync::OnceLock};
 use tonic::{Code, Status};
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 use crate::app::util::sentry::*;
+use crate::app::util::text::{truncate_utf8, MAX_LOGGED_BYTES};
 use sentry::capture_error as capture_exception;
 
 #[derive(thiserror::Error, Debug)]
@@ -15,6 +17,10 @@ pub enum ServiceError {
     MiddlewareNotSet(&'static str),
     #[error("app config not set")]
     ConfigNotSet,
+    #[error("invalid app config: {}", .0.join("; "))]
+    InvalidConfig(Vec<String>),
+    #[error("unreachable dependencies: {}", .0.join("; "))]
+    DependencyUnavailable(Vec<String>),
     #[error("Rc still has more than 0 reference(s). This is a bug")]
     RcHasReference,
     #[error("error: failed to parse error message: {0}")]
@@ -27,6 +33,16 @@ pub enum ServiceError {
     ParseUtf8(#[from] std::str::Utf8Error),
     #[error("bad credential")]
     BadCredential,
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("session issued by client version {version} is below the minimum supported version {minimum}, please login again")]
+    ReloginRequired {
+        version: String,
+        minimum: String,
+        login_url: Option<String>,
+    },
     #[error("rejected reason: {0}")]
     Rejected(String),
     #[error(transparent)]
@@ -66,18 +82,42 @@ pub enum ServiceError {
     QueueBasicAckTimeout,
     #[error("client response timeout")]
     ClientTimeout,
+    #[error("request did not complete within {0}s")]
+    RequestTimeout(u64),
+    #[error("message of {size} bytes exceeds the {limit} bytes limit")]
+    MessageTooLarge { size: usize, limit: usize },
+    #[error("rate limit exceeded, retry after {0}s")]
+    TooManyRequests(u64),
+    #[error("concurrent stream limit of {0} reached")]
+    StreamLimitReached(usize),
+    #[error("login locked out for {0} seconds after repeated failures")]
+    LoginLockedOut(u64),
+    #[error("service is shutting down")]
+    ShuttingDown,
+    #[error("service {0} is disabled")]
+    ServiceDisabled(String),
+    #[error("response stream producer ended unexpectedly")]
+    StreamAborted,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error(transparent)]
     CookieParse(#[from] cookie::ParseError),
     #[error(transparent)]
     HttpHeader(#[from] http::header::ToStrError),
     #[error("http header not found")]
     HttpHeaderNotFound,
+    #[error("request loop detected, request already passed through service {0}")]
+    ProxyLoop(String),
+    #[error("idempotency-key metadata is required by {0}")]
+    MissingIdempotencyKey(String),
     // #[error(transparent)]
     // AmqpTopicParseError(#[from] agripot_amqp_topic::error::ParseError),
     #[error(transparent)]
     MsgPackDecodeError(#[from] rmp_serde::decode::Error),
     #[error(transparent)]
     MsgPackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     // #[error(transparent)]
     // SerializablePacket(#[from] agripot_serializable_packet::error::PacketError),
 }
@@ -96,6 +136,8 @@ impl From<()> for ServiceError {
 
 impl ServiceError {
     pub fn get_code(&self) -> Code {
+        // `#[non_exhaustive]` only applies outside of this crate so this match is deliberately
+        // exhaustive without a catch-all arm. A new variant without a mapping fails to compile
         match self {
             Self::Reqwest(e) if e.is_body() => {
                 warn!("reqwest body failed: {:?}", e);
@@ -173,7 +215,10 @@ impl ServiceError {
                 Code::Unavailable
             }
             Self::MiddlewareNotSet(e) => {
-                error!("middleware not set: {:?}", e);
+                error!(
+                    "middleware not set: {:?}, it must be layered before the middleware reading its extension",
+                    e
+                );
                 capture_fatal("Service middleware was not properly setup");
                 Code::Internal
             }
@@ -182,8 +227,21 @@ impl ServiceError {
                 capture_fatal("Service configuration was not properly setup");
                 Code::Internal
             }
+            Self::InvalidConfig(e) => {
+                error!("invalid app config: {:?}", e);
+                capture_fatal("Service configuration was not properly setup");
+                Code::Internal
+            }
+            Self::DependencyUnavailable(e) => {
+                error!("unreachable dependencies: {:?}", e);
+                capture_fatal("Service dependencies were not reachable");
+                Code::Unavailable
+            }
             Self::ParseMessage(e) => {
-                error!("error: failed to parse error message: {}", e);
+                error!(
+                    "error: failed to parse error message: {}",
+                    truncate_utf8(e, MAX_LOGGED_BYTES)
+                );
                 capture_error(
                     "Service encountered failure while attempting to parse error response message",
                 );
@@ -212,8 +270,22 @@ impl ServiceError {
                 Code::InvalidArgument
             }
             Self::BadCredential => Code::Unauthenticated,
+            Self::NotFound(e) => {
+                warn!("{} not found", e);
+                capture_warning("Requested resource could not be found");
+                Code::NotFound
+            }
+            Self::Conflict(e) => {
+                warn!("conflict: {}", e);
+                capture_warning("Service rejected the creation of an already existing resource");
+                Code::AlreadyExists
+            }
+            Self::ReloginRequired { .. } => Code::Unauthenticated,
             Self::Rejected(e) => {
-                warn!("access rejected reason: {}", e);
+                warn!(
+                    "access rejected reason: {}",
+                    truncate_utf8(e, MAX_LOGGED_BYTES)
+                );
                 capture_warning(
                     "Incoming gRPC request attempted to access non-authorized resource",
                 );
@@ -342,6 +414,48 @@ impl ServiceError {
             Self::QueueBasicConsumeTimeout => Code::DeadlineExceeded,
             Self::QueueBasicAckTimeout => Code::DeadlineExceeded,
             Self::ClientTimeout => Code::DeadlineExceeded,
+            Self::RequestTimeout(e) => {
+                warn!("request did not complete within {} seconds", e);
+                capture_warning("Service gave up on a request exceeding the request timeout");
+                Code::DeadlineExceeded
+            }
+            Self::MessageTooLarge { size, limit } => {
+                warn!(
+                    "rejected a {} bytes message over the {} bytes limit",
+                    size, limit
+                );
+                Code::ResourceExhausted
+            }
+            Self::TooManyRequests(e) => {
+                warn!("rate limit exceeded, retry after {} seconds", e);
+                capture_warning("Service rejected a request exceeding the rate limit");
+                Code::ResourceExhausted
+            }
+            Self::StreamLimitReached(e) => {
+                warn!("stream rejected, all {} stream permits are in use", e);
+                Code::ResourceExhausted
+            }
+            Self::LoginLockedOut(e) => {
+                warn!("login locked out for {} seconds", e);
+                Code::ResourceExhausted
+            }
+            Self::ShuttingDown => Code::Unavailable,
+            Self::ServiceDisabled(e) => {
+                info!("request rejected by disabled service: {}", e);
+                Code::Unavailable
+            }
+            Self::StreamAborted => {
+                error!("response stream producer ended unexpectedly");
+                capture_error(
+                    "Service encountered failure while producing response stream from asynchronous task",
+                );
+                Code::Aborted
+            }
+            Self::Io(e) => {
+                error!("io error: {:?}", e);
+                capture_error("Service encountered failure while performing filesystem io");
+                Code::Internal
+            }
             Self::CookieParse(e) => {
                 warn!("cookie parse error: {:?}", e);
                 capture_warning(
@@ -363,6 +477,17 @@ impl ServiceError {
                 );
                 Code::Internal
             }
+            Self::ProxyLoop(e) => {
+                error!("request loop detected through service: {}", e);
+                capture_error(
+                    "Incoming gRPC request was forwarded back to the service by a misconfigured proxy",
+                );
+                Code::FailedPrecondition
+            }
+            Self::MissingIdempotencyKey(e) => {
+                info!("idempotency-key metadata missing on: {}", e);
+                Code::InvalidArgument
+            }
             // Self::AmqpTopicParseError(e) => {
             //     warn!("amqp topic parse error: {:?}", e);
             //     capture_warning("Service encountered failure while attempting to parse amqp topic");
@@ -389,18 +514,376 @@ impl ServiceError {
             //     );
             //     Code::FailedPrecondition
             // }
-            #[allow(unreachable_patterns)]
-            _ => {
-                error!("undocumented error: {}", &self.to_string());
-                capture_fatal("Service encountered exception from unknown source");
-                Code::Unknown
+            Self::Json(e) => {
+                warn!("json error: {:?}", e);
+                capture_warning("Service encountered failure while attempting to process json");
+                Code::InvalidArgument
             }
         }
     }
 }
 
+impl ServiceError {
+    /// structured fields of the error returned to clients as `x-error-*` metadata
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::ValidateFailure { field, reason } => vec![
+                ("x-error-field", field.to_string()),
+                ("x-error-reason", reason.clone()),
+            ],
+            Self::TryFrom {
+                field,
+                from,
+                into,
+                expect,
+            } => vec![
+                ("x-error-field", field.to_string()),
+                ("x-error-from", from.clone()),
+                ("x-error-into", into.to_string()),
+                ("x-error-expect", expect.to_string()),
+            ],
+            Self::LoginLockedOut(seconds) => vec![("retry-after", seconds.to_string())],
+            Self::TooManyRequests(seconds) => vec![("retry-after", seconds.to_string())],
+            Self::MessageTooLarge { limit, .. } => {
+                vec![("x-max-message-bytes", limit.to_string())]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// whether the failed outbound call may succeed if retried as-is
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::ClientTimeout => true,
+            Self::Redis(e) => e.is_timeout() || e.is_connection_dropped() || e.is_io_error(),
+            _ => false,
+        }
+    }
+}
+
+static ERROR_DETAIL_MODE: OnceLock<ErrorDetailMode> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// how much of a `ServiceError` is exposed to clients through the gRPC status message
+pub enum ErrorDetailMode {
+    /// only a stable generic message per status code. The full detail is only logged
+    Compact,
+    /// the full `Display` of the error
+    #[default]
+    Verbose,
+}
+
+impl FromStr for ErrorDetailMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "compact" => Ok(ErrorDetailMode::Compact),
+            "verbose" => Ok(ErrorDetailMode::Verbose),
+            mode => Err(format!("expect either compact or verbose but got {}", mode)),
+        }
+    }
+}
+
+/// set the process wide error detail mode. Only the first call takes effect
+pub fn set_error_detail_mode(mode: ErrorDetailMode) {
+    let _ = ERROR_DETAIL_MODE.set(mode);
+}
+
+/// stable client facing message of `code` used in compact error detail mode
+fn generic_message(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "ok",
+        Code::Cancelled => "request cancelled",
+        Code::Unknown => "unknown error",
+        Code::InvalidArgument => "invalid argument",
+        Code::DeadlineExceeded => "deadline exceeded",
+        Code::NotFound => "not found",
+        Code::AlreadyExists => "already exists",
+        Code::PermissionDenied => "permission denied",
+        Code::ResourceExhausted => "resource exhausted",
+        Code::FailedPrecondition => "failed precondition",
+        Code::Aborted => "aborted",
+        Code::OutOfRange => "out of range",
+        Code::Unimplemented => "unimplemented",
+        Code::Internal => "internal error",
+        Code::Unavailable => "service unavailable",
+        Code::DataLoss => "data loss",
+        Code::Unauthenticated => "unauthenticated",
+    }
+}
+
 impl From<ServiceError> for Status {
     fn from(error: ServiceError) -> Self {
-        Status::new(error.get_code(), error.to_string())
+        into_status(error, ERROR_DETAIL_MODE.get().copied().unwrap_or_default())
+    }
+}
+
+/// the status returned to clients for `error` under the error detail `mode`
+fn into_status(error: ServiceError, mode: ErrorDetailMode) -> Status {
+    let code = error.get_code();
+    let message = match mode {
+        ErrorDetailMode::Compact => {
+            info!(
+                "responding with compact error message: {}",
+                truncate_utf8(&error.to_string(), MAX_LOGGED_BYTES)
+            );
+            generic_message(code).to_string()
+        }
+        ErrorDetailMode::Verbose => error.to_string(),
+    };
+    let mut status = Status::new(code, message);
+
+    // machine readable details so clients can e.g. map a validation failure to a form field
+    for (key, value) in error.details() {
+        if let Ok(value) = value.parse() {
+            status.metadata_mut().insert(key, value);
+        }
+    }
+
+    if let ServiceError::ReloginRequired {
+        login_url: Some(login_url),
+        ..
+    } = &error
+    {
+        // hint the client where to send the user to login again
+        if let Ok(login_url) = login_url.parse() {
+            status.metadata_mut().insert("x-login-url", login_url);
+        }
+    }
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io, time::Duration};
+
+    const MODES: [ErrorDetailMode; 2] = [ErrorDetailMode::Compact, ErrorDetailMode::Verbose];
+
+    /// assert `error` maps to `code` and the status message follow each error detail mode
+    fn assert_status(error: impl Fn() -> ServiceError, code: Code) {
+        assert_eq!(error().get_code(), code, "{}", error());
+
+        for mode in MODES {
+            let status = into_status(error(), mode);
+            let message = match mode {
+                ErrorDetailMode::Compact => generic_message(code).to_string(),
+                ErrorDetailMode::Verbose => error().to_string(),
+            };
+
+            assert_eq!(status.code(), code, "{} in {:?} mode", error(), mode);
+            assert_eq!(status.message(), message, "{:?} mode", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn reqwest_timeout_is_deadline_exceeded() {
+        // a peer that accept the connection but never respond
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _peer = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let error = reqwest::Client::new()
+            .get(format!("http://{}", addr))
+            .timeout(Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+
+        assert!(error.is_timeout());
+        assert_eq!(ServiceError::from(error).get_code(), Code::DeadlineExceeded);
+    }
+
+    #[test]
+    fn redis_connection_refused_is_unavailable() {
+        assert_status(
+            || redis::RedisError::from(io::Error::from(io::ErrorKind::ConnectionRefused)).into(),
+            Code::Unavailable,
+        );
+    }
+
+    #[test]
+    fn redis_dropped_connection_is_unavailable() {
+        assert_status(
+            || redis::RedisError::from(io::Error::from(io::ErrorKind::BrokenPipe)).into(),
+            Code::Unavailable,
+        );
+    }
+
+    #[test]
+    fn lapin_channels_limit_is_resource_exhausted() {
+        assert_status(
+            || lapin::Error::ChannelsLimitReached.into(),
+            Code::ResourceExhausted,
+        );
+    }
+
+    #[test]
+    fn timeouts_are_deadline_exceeded() {
+        assert_status(|| ServiceError::QueueDeclareTimeout, Code::DeadlineExceeded);
+        assert_status(|| ServiceError::QueueBindTimeout, Code::DeadlineExceeded);
+        assert_status(
+            || ServiceError::QueueBasicConsumeTimeout,
+            Code::DeadlineExceeded,
+        );
+        assert_status(
+            || ServiceError::QueueBasicAckTimeout,
+            Code::DeadlineExceeded,
+        );
+        assert_status(|| ServiceError::ClientTimeout, Code::DeadlineExceeded);
+        assert_status(|| ServiceError::RequestTimeout(5), Code::DeadlineExceeded);
+    }
+
+    #[test]
+    fn bad_credential_is_unauthenticated() {
+        assert_status(|| ServiceError::BadCredential, Code::Unauthenticated);
+    }
+
+    #[test]
+    fn other_classes_keep_their_code() {
+        assert_status(|| ServiceError::NotFound("session"), Code::NotFound);
+        assert_status(
+            || ServiceError::Conflict("user".to_string()),
+            Code::AlreadyExists,
+        );
+        assert_status(
+            || ServiceError::Rejected("admin only".to_string()),
+            Code::PermissionDenied,
+        );
+        assert_status(
+            || ServiceError::ValidateFailure {
+                field: "name",
+                reason: "must not be empty".to_string(),
+            },
+            Code::InvalidArgument,
+        );
+        assert_status(
+            || ServiceError::TryFrom {
+                field: "age",
+                from: "-1".to_string(),
+                into: "u32",
+                expect: "a positive number",
+            },
+            Code::DataLoss,
+        );
+        assert_status(
+            || ServiceError::ReloginRequired {
+                version: "1.0.0".to_string(),
+                minimum: "2.0.0".to_string(),
+                login_url: None,
+            },
+            Code::Unauthenticated,
+        );
+        assert_status(
+            || ServiceError::MessageTooLarge {
+                size: 2048,
+                limit: 1024,
+            },
+            Code::ResourceExhausted,
+        );
+        assert_status(
+            || ServiceError::TooManyRequests(30),
+            Code::ResourceExhausted,
+        );
+        assert_status(
+            || ServiceError::StreamLimitReached(8),
+            Code::ResourceExhausted,
+        );
+        assert_status(|| ServiceError::LoginLockedOut(60), Code::ResourceExhausted);
+        assert_status(|| ServiceError::ShuttingDown, Code::Unavailable);
+        assert_status(
+            || ServiceError::ServiceDisabled("echo".to_string()),
+            Code::Unavailable,
+        );
+        assert_status(
+            || ServiceError::DependencyUnavailable(vec!["redis".to_string()]),
+            Code::Unavailable,
+        );
+        assert_status(|| ServiceError::StreamAborted, Code::Aborted);
+        assert_status(|| ServiceError::ConfigNotSet, Code::Internal);
+        assert_status(|| ServiceError::MiddlewareNotSet("cookie"), Code::Internal);
+        assert_status(
+            || ServiceError::ProxyLoop("gateway".to_string()),
+            Code::FailedPrecondition,
+        );
+        assert_status(
+            || ServiceError::MissingIdempotencyKey("/greeter/Send".to_string()),
+            Code::InvalidArgument,
+        );
+        assert_status(|| ServiceError::HttpHeaderNotFound, Code::Internal);
+        assert_status(|| ServiceError::SendError, Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn json_decode_failure_is_invalid_argument() {
+        let error = || {
+            ServiceError::from(
+                serde_json::from_str::<serde_json::Value>("{\"content\":").unwrap_err(),
+            )
+        };
+
+        assert!(matches!(error(), ServiceError::Json(_)));
+        assert_status(error, Code::InvalidArgument);
+    }
+
+    #[test]
+    fn redis_detail_is_only_exposed_in_verbose_mode() {
+        let error = || {
+            ServiceError::from(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "response was of incompatible type",
+                "WRONGTYPE on key session:1234".to_string(),
+            )))
+        };
+
+        let compact = into_status(error(), ErrorDetailMode::Compact);
+        let verbose = into_status(error(), ErrorDetailMode::Verbose);
+
+        assert_eq!(compact.code(), verbose.code());
+        assert_eq!(compact.message(), generic_message(compact.code()));
+        assert!(!compact.message().contains("session:1234"));
+        assert_eq!(verbose.message(), error().to_string());
+        assert!(verbose.message().contains("session:1234"));
+    }
+
+    #[test]
+    fn error_detail_mode_parses_both_modes() {
+        assert_eq!("compact".parse(), Ok(ErrorDetailMode::Compact));
+        assert_eq!("verbose".parse(), Ok(ErrorDetailMode::Verbose));
+        assert!("debug".parse::<ErrorDetailMode>().is_err());
+        assert_eq!(" Compact ".parse(), Ok(ErrorDetailMode::Compact));
+    }
+
+    #[test]
+    fn details_are_sent_in_both_modes() {
+        for mode in MODES {
+            let status = into_status(
+                ServiceError::ValidateFailure {
+                    field: "name",
+                    reason: "must not be empty".to_string(),
+                },
+                mode,
+            );
+
+            assert_eq!(status.metadata().get("x-error-field").unwrap(), "name");
+            assert_eq!(
+                status.metadata().get("x-error-reason").unwrap(),
+                "must not be empty"
+            );
+        }
+    }
+
+    #[test]
+    fn throttling_errors_hint_the_backoff_in_retry_after() {
+        for error in [
+            ServiceError::LoginLockedOut(30),
+            ServiceError::TooManyRequests(30),
+        ] {
+            assert_eq!(error.details(), [("retry-after", "30".to_string())]);
+        }
     }
 }
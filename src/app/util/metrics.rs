@@ -0,0 +1,414 @@
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tonic::Code;
+
+/// process wide counters of the server stream pipeline
+pub static STREAM_METRICS: StreamMetrics = StreamMetrics::new();
+
+lazy_static::lazy_static! {
+    /// process wide gRPC request metrics exposed in prometheus text format
+    pub static ref REQUEST_METRICS: RequestMetrics = RequestMetrics::new();
+}
+
+pub struct RequestMetrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    errors_total: IntCounterVec,
+    responses_total: IntCounterVec,
+    request_duration: HistogramVec,
+    redis_session_command_duration: HistogramVec,
+}
+
+/// upper bounds (in seconds) of the session redis command latency buckets, redis usually answer
+/// well below a millisecond so the default request buckets would be too coarse
+const REDIS_LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+impl RequestMetrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let requests_total = IntCounterVec::new(
+            Opts::new("grpc_requests_total", "Total number of gRPC requests"),
+            &["method"],
+        )
+        .expect("expect a valid grpc_requests_total metric");
+        let errors_total = IntCounterVec::new(
+            Opts::new("grpc_errors_total", "Total number of failed gRPC requests"),
+            &["method", "code"],
+        )
+        .expect("expect a valid grpc_errors_total metric");
+        // only labeled by the canonical code name so the cardinality is bounded by the 17 codes
+        let responses_total = IntCounterVec::new(
+            Opts::new(
+                "grpc_responses_total",
+                "Total number of gRPC responses per status code",
+            ),
+            &["code"],
+        )
+        .expect("expect a valid grpc_responses_total metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "grpc_request_duration_seconds",
+                "Latency until the gRPC response headers are ready",
+            ),
+            &["method"],
+        )
+        .expect("expect a valid grpc_request_duration_seconds metric");
+        let redis_session_command_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "redis_session_command_duration_seconds",
+                "Latency of the redis commands issued to manage sessions",
+            )
+            .buckets(REDIS_LATENCY_BUCKETS.to_vec()),
+            &["command"],
+        )
+        .expect("expect a valid redis_session_command_duration_seconds metric");
+
+        for collector in [
+            Box::new(requests_total.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(errors_total.clone()),
+            Box::new(responses_total.clone()),
+            Box::new(request_duration.clone()),
+            Box::new(redis_session_command_duration.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("expect every request metric to be registered once");
+        }
+
+        RequestMetrics {
+            registry,
+            requests_total,
+            errors_total,
+            responses_total,
+            request_duration,
+            redis_session_command_duration,
+        }
+    }
+
+    /// record a request to `method` that completed with `code` after `elapsed`
+    pub fn observe(&self, method: &str, code: Code, elapsed: Duration) {
+        self.requests_total.with_label_values(&[method]).inc();
+        self.request_duration
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+
+        if code != Code::Ok {
+            self.errors_total
+                .with_label_values(&[method, &format!("{:?}", code)])
+                .inc();
+        }
+    }
+
+    /// count a response completed with `code` under its canonical name (e.g. `ok`, `not_found`)
+    pub fn observe_status_code(&self, code: Code) {
+        self.responses_total
+            .with_label_values(&[code_label(code)])
+            .inc();
+    }
+
+    /// record a session redis `command` (e.g. `GETEX`) that completed after `elapsed`. The
+    /// p50/p95/p99 latencies are derived from the buckets with `histogram_quantile()`
+    pub fn observe_redis_command(&self, command: &str, elapsed: Duration) {
+        self.redis_session_command_duration
+            .with_label_values(&[command])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// number of responses completed with `code` counted so far
+    #[cfg(test)]
+    pub fn status_code_count(&self, code: Code) -> u64 {
+        self.responses_total
+            .with_label_values(&[code_label(code)])
+            .get()
+    }
+
+    /// number of session redis `command` recorded so far
+    #[cfg(test)]
+    pub fn redis_command_count(&self, command: &str) -> u64 {
+        self.redis_session_command_duration
+            .with_label_values(&[command])
+            .get_sample_count()
+    }
+
+    /// number of requests and failed requests recorded so far across every method
+    pub fn totals(&self) -> RequestTotals {
+        let sum = |counter: &IntCounterVec| -> u64 {
+            counter
+                .collect()
+                .iter()
+                .flat_map(|family| family.get_metric())
+                .map(|metric| metric.get_counter().get_value() as u64)
+                .sum()
+        };
+
+        RequestTotals {
+            requests: sum(&self.requests_total),
+            errors: sum(&self.errors_total),
+        }
+    }
+
+    /// encode every metric in prometheus text format
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = vec![];
+
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        // stream pipeline counters are plain atomics so they are appended by hand
+        let stream = STREAM_METRICS.snapshot();
+
+        for (name, help, kind, value) in [
+            (
+                "stream_items_delivered",
+                "Items delivered to stream clients",
+                "counter",
+                stream.items_delivered,
+            ),
+            (
+                "stream_items_dropped",
+                "Items produced but never delivered to stream clients",
+                "counter",
+                stream.items_dropped,
+            ),
+            (
+                "stream_heartbeats_sent",
+                "Heartbeats sent to stream clients",
+                "counter",
+                stream.heartbeats_sent,
+            ),
+            (
+                "stream_active",
+                "Server streams currently open",
+                "gauge",
+                stream.active_streams,
+            ),
+        ] {
+            buffer.extend(
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                    name = name,
+                    help = help,
+                    kind = kind,
+                    value = value
+                )
+                .into_bytes(),
+            );
+        }
+
+        Ok(buffer)
+    }
+}
+
+/// canonical snake case name of `code` as used by the gRPC specification
+fn code_label(code: Code) -> &'static str {
+    match code {
+        Code::Ok => "ok",
+        Code::Cancelled => "cancelled",
+        Code::Unknown => "unknown",
+        Code::InvalidArgument => "invalid_argument",
+        Code::DeadlineExceeded => "deadline_exceeded",
+        Code::NotFound => "not_found",
+        Code::AlreadyExists => "already_exists",
+        Code::PermissionDenied => "permission_denied",
+        Code::ResourceExhausted => "resource_exhausted",
+        Code::FailedPrecondition => "failed_precondition",
+        Code::Aborted => "aborted",
+        Code::OutOfRange => "out_of_range",
+        Code::Unimplemented => "unimplemented",
+        Code::Internal => "internal",
+        Code::Unavailable => "unavailable",
+        Code::DataLoss => "data_loss",
+        Code::Unauthenticated => "unauthenticated",
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestTotals {
+    pub requests: u64,
+    pub errors: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowAggregate {
+    pub requests: u64,
+    pub errors: u64,
+    pub requests_per_second: f64,
+    /// failed requests over requests within the window, 0 when there was no request
+    pub error_rate: f64,
+}
+
+#[derive(Debug)]
+/// rolling window over `RequestTotals` samples. The aggregate of a window is the difference
+/// between the newest sample and the oldest sample still within the window
+pub struct RequestWindow {
+    window: Duration,
+    samples: VecDeque<(Instant, RequestTotals)>,
+}
+
+impl RequestWindow {
+    pub fn new(window: Duration) -> Self {
+        RequestWindow {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// record `totals` sampled at `now` and aggregate the activity within the window
+    pub fn push(&mut self, now: Instant, totals: RequestTotals) -> WindowAggregate {
+        self.samples.push_back((now, totals));
+
+        // keep one sample at or before the window start so the window is always fully covered
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+
+        let (oldest_at, oldest) = self.samples[0];
+        let elapsed = now.duration_since(oldest_at).as_secs_f64();
+        let requests = totals.requests.saturating_sub(oldest.requests);
+        let errors = totals.errors.saturating_sub(oldest.errors);
+
+        WindowAggregate {
+            requests,
+            errors,
+            requests_per_second: if elapsed > 0.0 {
+                requests as f64 / elapsed
+            } else {
+                0.0
+            },
+            error_rate: if requests > 0 {
+                errors as f64 / requests as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// counters separating useful items delivered to clients from overhead. Items produced but never
+/// delivered (client disconnected, stream terminated early) are counted as dropped
+pub struct StreamMetrics {
+    items_delivered: AtomicU64,
+    items_dropped: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    active_streams: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamMetricsSnapshot {
+    pub items_delivered: u64,
+    pub items_dropped: u64,
+    pub heartbeats_sent: u64,
+    pub active_streams: u64,
+}
+
+impl StreamMetrics {
+    pub const fn new() -> Self {
+        StreamMetrics {
+            items_delivered: AtomicU64::new(0),
+            items_dropped: AtomicU64::new(0),
+            heartbeats_sent: AtomicU64::new(0),
+            active_streams: AtomicU64::new(0),
+        }
+    }
+
+    pub fn item_delivered(&self) {
+        self.items_delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn items_dropped(&self, count: u64) {
+        self.items_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn heartbeat_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_opened(&self) {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_closed(&self) {
+        self.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StreamMetricsSnapshot {
+        StreamMetricsSnapshot {
+            items_delivered: self.items_delivered.load(Ordering::Relaxed),
+            items_dropped: self.items_dropped.load(Ordering::Relaxed),
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            active_streams: self.active_streams.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totals(requests: u64, errors: u64) -> RequestTotals {
+        RequestTotals { requests, errors }
+    }
+
+    #[test]
+    fn redis_latencies_fill_the_buckets_of_their_command() {
+        let metrics = RequestMetrics::new();
+
+        metrics.observe_redis_command("GETEX", Duration::from_micros(300));
+        metrics.observe_redis_command("GETEX", Duration::from_millis(2));
+        metrics.observe_redis_command("GETEX", Duration::from_millis(40));
+        metrics.observe_redis_command("DEL", Duration::from_millis(2));
+
+        let encoded = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        let line = |labels: &str| {
+            let prefix = format!("redis_session_command_duration_seconds{} ", labels);
+
+            encoded
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .unwrap_or_else(|| panic!("expect a {} sample", prefix))
+                .to_string()
+        };
+
+        // buckets are cumulative
+        assert_eq!(line(r#"_bucket{command="GETEX",le="0.0005"}"#), "1");
+        assert_eq!(line(r#"_bucket{command="GETEX",le="0.0025"}"#), "2");
+        assert_eq!(line(r#"_bucket{command="GETEX",le="0.05"}"#), "3");
+        assert_eq!(line(r#"_count{command="GETEX"}"#), "3");
+        assert_eq!(line(r#"_bucket{command="DEL",le="0.001"}"#), "0");
+        assert_eq!(line(r#"_bucket{command="DEL",le="0.0025"}"#), "1");
+        assert_eq!(line(r#"_count{command="DEL"}"#), "1");
+        assert_eq!(metrics.redis_command_count("GETEX"), 3);
+        assert_eq!(metrics.redis_command_count("EXPIRE"), 0);
+    }
+
+    #[test]
+    fn window_aggregates_the_activity_within_the_window() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut window = RequestWindow::new(Duration::from_secs(1));
+
+        assert_eq!(window.push(at(0), totals(10, 0)).requests, 0);
+
+        let aggregate = window.push(at(500), totals(20, 5));
+
+        assert_eq!((aggregate.requests, aggregate.errors), (10, 5));
+        assert_eq!(aggregate.requests_per_second, 20.0);
+        assert_eq!(aggregate.error_rate, 0.5);
+
+        // the sample at 0ms left the window, the one at 500ms now start it
+        let aggregate = window.push(at(1500), totals(40, 5));
+
+        assert_eq!((aggregate.requests, aggregate.errors), (20, 0));
+        assert_eq!(aggregate.requests_per_second, 20.0);
+        assert_eq!(aggregate.error_rate, 0.0);
+    }
+}
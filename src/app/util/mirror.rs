@@ -0,0 +1,191 @@
+use super::{
+    amqp::AmqpPublisher, codec::encode_msgpack, context::RequestContext, error::ServiceError,
+};
+use crate::app::config::task::spawn_with_name;
+use sentry::Level;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tracing::warn;
+
+static ERROR_MIRROR: OnceLock<AmqpPublisher> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize)]
+/// msgpack payload published for every `ServiceError` captured by sentry. The request fields are
+/// taken from the `RequestContext` of the capturing task and are absent for errors raised outside
+/// of a request. `uid` is the digest from `RequestContext::uid_digest()`, never the raw uid
+pub struct ErrorEnvelope {
+    pub level: String,
+    pub message: String,
+    pub request_id: Option<String>,
+    pub method: Option<String>,
+    pub uid: Option<String>,
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+impl ErrorEnvelope {
+    pub fn new(level: Level, message: &str, context: Option<&RequestContext>) -> Self {
+        ErrorEnvelope {
+            level: level.to_string(),
+            message: message.to_string(),
+            request_id: context.map(|context| context.request_id.clone()),
+            method: context.map(|context| context.method.clone()),
+            uid: context.and_then(RequestContext::uid_digest),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+}
+
+/// install the publisher used to mirror captured errors. Only the first call takes effect
+pub fn install_error_mirror(publisher: AmqpPublisher) {
+    if ERROR_MIRROR.set(publisher).is_err() {
+        warn!("error mirror was already installed");
+    }
+}
+
+/// msgpack encoded `ErrorEnvelope` of an error captured by the current task
+fn envelope_payload(level: Level, message: &str) -> Result<Vec<u8>, ServiceError> {
+    encode_msgpack(&ErrorEnvelope::new(
+        level,
+        message,
+        RequestContext::current().as_ref(),
+    ))
+}
+
+/// best-effort publish of a captured error to the error mirror exchange. Failure to encode or
+/// publish is only logged and never propagated back to the caller
+pub fn mirror_error(level: Level, message: &str) {
+    let publisher = match (ERROR_MIRROR.get(), tokio::runtime::Handle::try_current()) {
+        (Some(publisher), Ok(_)) => publisher.clone(),
+        _ => return,
+    };
+
+    let payload = match envelope_payload(level, message) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("failed to encode mirrored error: {}", e);
+            return;
+        }
+    };
+
+    spawn_with_name(
+        async move {
+            if let Err(e) = publisher.publish(&payload).await {
+                warn!("failed to publish mirrored error: {}", e);
+            }
+        },
+        "error_mirror",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        config::app::AppConfig,
+        util::{amqp::PublishChannel, codec::decode_msgpack, sentry::capture_error},
+    };
+    use lapin::BasicProperties;
+    use std::{sync::Arc, time::Duration};
+    use tokio::{sync::mpsc, time::timeout};
+    use uuid::Uuid;
+
+    /// channel forwarding the exchange, routing key and payload of every publish it receives
+    struct RecordingChannel(mpsc::UnboundedSender<(String, String, Vec<u8>)>);
+
+    #[tonic::async_trait]
+    impl PublishChannel for RecordingChannel {
+        async fn publish(
+            &self,
+            exchange: &str,
+            routing_key: &str,
+            payload: &[u8],
+            _properties: BasicProperties,
+        ) -> Result<(), ServiceError> {
+            // the mirror outlive this test, later captures have nowhere to go
+            let _ = self.0.send((
+                exchange.to_string(),
+                routing_key.to_string(),
+                payload.to_vec(),
+            ));
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn captured_errors_are_published_to_the_mirror_exchange() {
+        let config = AppConfig::for_test(&[
+            ("AMQP_ADDRESS", "amqp://127.0.0.1:5672"),
+            ("ERROR_MIRROR_EXCHANGE", "errors"),
+            ("ERROR_MIRROR_ROUTING_KEY", "service.errors"),
+        ]);
+        let (published_pusher, mut published) = mpsc::unbounded_channel();
+
+        // the only test installing the mirror, it is process wide
+        install_error_mirror(AmqpPublisher::new(
+            Arc::new(RecordingChannel(published_pusher)),
+            config.error_mirror_exchange.as_deref().unwrap(),
+            &config.error_mirror_routing_key,
+        ));
+
+        let message = format!("mirrored {}", Uuid::new_v4());
+        capture_error(&message);
+
+        // other tests capture errors concurrently, wait for this one
+        let (exchange, routing_key, envelope) = timeout(Duration::from_secs(1), async {
+            loop {
+                let (exchange, routing_key, payload) = published.recv().await.unwrap();
+                let envelope: ErrorEnvelope = decode_msgpack(&payload).unwrap();
+
+                if envelope.message == message {
+                    return (exchange, routing_key, envelope);
+                }
+            }
+        })
+        .await
+        .expect("expect the captured error to be published");
+
+        assert_eq!(exchange, "errors");
+        assert_eq!(routing_key, "service.errors");
+        assert_eq!(envelope.level, "error");
+    }
+
+    #[tokio::test]
+    async fn envelope_carries_the_context_of_the_request() {
+        let uid = Uuid::new_v4();
+        let context = RequestContext::new("req-1".to_string(), "/chat/SendMessage".to_string());
+        context.set_uid(uid);
+
+        // errors are captured from tasks spawned on behalf of the request too
+        let payload = RequestContext::scope(Some(context), async {
+            spawn_with_name(
+                async { envelope_payload(Level::Error, "boom").unwrap() },
+                "test",
+            )
+            .await
+            .unwrap()
+        })
+        .await;
+        let envelope: ErrorEnvelope = decode_msgpack(&payload).unwrap();
+
+        assert_eq!(envelope.level, "error");
+        assert_eq!(envelope.message, "boom");
+        assert_eq!(envelope.request_id.as_deref(), Some("req-1"));
+        assert_eq!(envelope.method.as_deref(), Some("/chat/SendMessage"));
+        let digest = envelope.uid.expect("expect the uid digest");
+        assert_eq!(digest.len(), 64);
+        assert!(!digest.contains(&uid.simple().to_string()));
+        assert!(envelope.timestamp > 0);
+    }
+
+    #[test]
+    fn envelope_outside_of_a_request_has_no_context() {
+        let payload = envelope_payload(Level::Warning, "boom").unwrap();
+        let envelope: ErrorEnvelope = decode_msgpack(&payload).unwrap();
+
+        assert_eq!(envelope.request_id, None);
+        assert_eq!(envelope.method, None);
+        assert_eq!(envelope.uid, None);
+    }
+}
@@ -1,3 +1,23 @@
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod clock;
+pub mod codec;
+pub mod context;
+pub mod credential;
+pub mod deadline;
 pub mod error;
+pub mod metrics;
+#[cfg(feature = "amqp")]
+pub mod mirror;
+pub mod ratelimit;
+pub mod recent;
+pub mod redis;
+pub mod retry;
 pub mod sentry;
+pub mod session;
+pub mod shutdown;
 pub mod stream;
+pub mod text;
+pub mod upload;
+pub mod validator;
+pub mod version;
@@ -0,0 +1,166 @@
+use super::{error::ServiceError, redis::redis_with_timeout};
+use redis::aio::ConnectionLike;
+use std::time::Duration;
+use tracing::debug;
+
+/// window of the per address login attempt counter
+const LOGIN_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+/// how long consecutive login failures of an address are remembered
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// upper bound of a login lockout
+const MAX_LOGIN_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// redis key holding the rate limit counter of `identity` (a session uid or a client address)
+pub fn rate_limit_key(identity: &str) -> String {
+    format!("ratelimit:{}", identity)
+}
+
+/// redis key holding the number of login attempts of `address` within the current minute
+pub fn login_attempt_key(address: &str) -> String {
+    format!("login:attempts:{}", address)
+}
+
+/// redis key holding the number of consecutive login failures of `address`
+pub fn login_failure_key(address: &str) -> String {
+    format!("login:failures:{}", address)
+}
+
+/// redis key present while `address` is locked out of login
+pub fn login_lockout_key(address: &str) -> String {
+    format!("login:lockout:{}", address)
+}
+
+/// first address of an `X-Forwarded-For` header value i.e. the original client
+pub fn forwarded_for(header: &str) -> Option<String> {
+    header
+        .split(',')
+        .next()
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+}
+
+#[derive(Debug, Clone, Copy)]
+/// login throttling of a single client address. Every attempt count toward
+/// `attempts_per_minute` while only failures count toward the lockout. Once `lockout_threshold`
+/// consecutive failures are reached the address is locked out for `lockout_base`, doubling with
+/// every further failure up to an hour. A successful login reset the failure count
+pub struct LoginThrottle {
+    pub attempts_per_minute: u64,
+    pub lockout_threshold: u64,
+    pub lockout_base: Duration,
+}
+
+impl LoginThrottle {
+    /// record a login attempt of `address`. Fail with `ServiceError::LoginLockedOut` while the
+    /// address is locked out and with `ServiceError::TooManyRequests` once it made too many
+    /// attempts within the minute
+    pub async fn attempt<C>(&self, connection: &mut C, address: &str) -> Result<(), ServiceError>
+    where
+        C: ConnectionLike,
+    {
+        let lockout = redis_with_timeout(
+            redis::cmd("TTL")
+                .arg(login_lockout_key(address))
+                .query_async::<_, i64>(connection),
+        )
+        .await?;
+
+        // TTL is negative when the key does not exist
+        if lockout > 0 {
+            return Err(ServiceError::LoginLockedOut(lockout as u64));
+        }
+
+        let key = login_attempt_key(address);
+        let count = redis_with_timeout(
+            redis::cmd("INCR")
+                .arg(&key)
+                .query_async::<_, u64>(connection),
+        )
+        .await?;
+
+        // the window start with the first attempt so only the first increment set the expiry
+        if count == 1 {
+            redis_with_timeout(
+                redis::cmd("EXPIRE")
+                    .arg(&key)
+                    .arg(LOGIN_ATTEMPT_WINDOW.as_secs())
+                    .query_async::<_, ()>(connection),
+            )
+            .await?;
+        }
+
+        if count > self.attempts_per_minute {
+            let ttl = redis_with_timeout(
+                redis::cmd("TTL")
+                    .arg(&key)
+                    .query_async::<_, i64>(connection),
+            )
+            .await?;
+
+            debug!("login attempts exceeded for: {}", address);
+
+            return Err(ServiceError::TooManyRequests(ttl.max(1) as u64));
+        }
+
+        Ok(())
+    }
+
+    /// record a failed login of `address` and lock it out once the failure threshold is reached.
+    /// Return the lockout duration if one was applied
+    pub async fn failure<C>(
+        &self,
+        connection: &mut C,
+        address: &str,
+    ) -> Result<Option<Duration>, ServiceError>
+    where
+        C: ConnectionLike,
+    {
+        let key = login_failure_key(address);
+        let (failures,) = redis_with_timeout(
+            redis::pipe()
+                .atomic()
+                .incr(&key, 1)
+                .expire(&key, LOGIN_FAILURE_WINDOW.as_secs() as usize)
+                .ignore()
+                .query_async::<_, (u64,)>(connection),
+        )
+        .await?;
+
+        if failures < self.lockout_threshold {
+            return Ok(None);
+        }
+
+        let exponent = (failures - self.lockout_threshold).min(16) as u32;
+        let lockout = self
+            .lockout_base
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(MAX_LOGIN_LOCKOUT);
+
+        redis_with_timeout(
+            redis::cmd("SET")
+                .arg(login_lockout_key(address))
+                .arg(failures)
+                .arg("EX")
+                .arg(lockout.as_secs().max(1))
+                .query_async::<_, ()>(connection),
+        )
+        .await?;
+
+        Ok(Some(lockout))
+    }
+
+    /// forget the failures of `address` after a successful login
+    pub async fn success<C>(&self, connection: &mut C, address: &str) -> Result<(), ServiceError>
+    where
+        C: ConnectionLike,
+    {
+        redis_with_timeout(
+            redis::cmd("DEL")
+                .arg(login_failure_key(address))
+                .query_async::<_, i64>(connection),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use super::text::{truncate_utf8, MAX_LOGGED_BYTES};
+use sentry::Level;
+use std::{collections::VecDeque, sync::Mutex};
+
+/// default number of entries kept by the recent errors buffer
+pub const DEFAULT_RECENT_ERRORS_MAX_ENTRIES: usize = 100;
+/// default upper bound (in bytes) of the messages kept by the recent errors buffer
+pub const DEFAULT_RECENT_ERRORS_MAX_BYTES: usize = 64 * 1024;
+
+/// process wide buffer of the latest errors captured through `capture_*()`
+static RECENT_ERRORS: Mutex<RecentErrors> = Mutex::new(RecentErrors::new(
+    DEFAULT_RECENT_ERRORS_MAX_ENTRIES,
+    DEFAULT_RECENT_ERRORS_MAX_BYTES,
+));
+
+#[derive(Debug, Clone)]
+pub struct RecentError {
+    pub level: Level,
+    pub message: String,
+    /// unix timestamp in milliseconds
+    pub timestamp: i64,
+}
+
+#[derive(Debug)]
+/// ring buffer bounded by both the number of entries and the total bytes of their messages. The
+/// oldest entries are evicted first to stay within both bounds and every message is truncated to
+/// `MAX_LOGGED_BYTES` so a single huge error can not flush the whole buffer
+pub struct RecentErrors {
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    entries: VecDeque<RecentError>,
+}
+
+impl RecentErrors {
+    pub const fn new(max_entries: usize, max_bytes: usize) -> Self {
+        RecentErrors {
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// change both bounds, evicting the oldest entries that no longer fit
+    pub fn set_limits(&mut self, max_entries: usize, max_bytes: usize) {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self.evict(0, 0);
+    }
+
+    /// append an error, evicting the oldest entries to make room for it. An entry that does not
+    /// fit in the byte budget on its own (even truncated) is discarded
+    pub fn push(&mut self, level: Level, message: &str, timestamp: i64) {
+        let message = truncate_utf8(message, MAX_LOGGED_BYTES.min(self.max_bytes)).into_owned();
+
+        if self.max_entries == 0 || message.len() > self.max_bytes {
+            return;
+        }
+
+        self.evict(1, message.len());
+        self.bytes += message.len();
+        self.entries.push_back(RecentError {
+            level,
+            message,
+            timestamp,
+        });
+    }
+
+    /// total bytes of the messages currently kept
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// every kept entry, oldest first
+    pub fn entries(&self) -> Vec<RecentError> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// evict the oldest entries until `entries` more entries of `bytes` in total fit
+    fn evict(&mut self, entries: usize, bytes: usize) {
+        while self.entries.len() + entries > self.max_entries || self.bytes + bytes > self.max_bytes
+        {
+            match self.entries.pop_front() {
+                Some(evicted) => self.bytes -= evicted.message.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// set the bounds of the process wide recent errors buffer
+pub fn set_recent_errors_limits(max_entries: usize, max_bytes: usize) {
+    RECENT_ERRORS
+        .lock()
+        .expect("expect recent errors lock to not be poisoned")
+        .set_limits(max_entries, max_bytes);
+}
+
+/// record a captured error into the process wide recent errors buffer
+pub fn record_recent_error(level: Level, message: &str) {
+    RECENT_ERRORS
+        .lock()
+        .expect("expect recent errors lock to not be poisoned")
+        .push(level, message, chrono::Utc::now().timestamp_millis());
+}
+
+/// the entries of the process wide recent errors buffer (oldest first) and their total bytes
+pub fn recent_errors() -> (Vec<RecentError>, usize) {
+    let buffer = RECENT_ERRORS
+        .lock()
+        .expect("expect recent errors lock to not be poisoned");
+
+    (buffer.entries(), buffer.bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(buffer: &RecentErrors) -> Vec<String> {
+        buffer
+            .entries()
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    #[test]
+    fn byte_budget_evicts_the_oldest_entries_first() {
+        let mut buffer = RecentErrors::new(100, 25);
+
+        for (timestamp, message) in ["first-----", "second----", "third-----", "fourth----"]
+            .into_iter()
+            .enumerate()
+        {
+            buffer.push(Level::Error, message, timestamp as i64);
+
+            assert!(buffer.bytes() <= 25);
+        }
+
+        assert_eq!(messages(&buffer), ["third-----", "fourth----"]);
+        assert_eq!(buffer.bytes(), 20);
+        assert_eq!(buffer.entries()[0].timestamp, 2);
+    }
+
+    #[test]
+    fn entry_bound_evicts_the_oldest_entries_first() {
+        let mut buffer = RecentErrors::new(2, 1024);
+
+        // empty messages take no bytes but still count as entries
+        for message in ["first", "", "third"] {
+            buffer.push(Level::Error, message, 0);
+        }
+
+        assert_eq!(messages(&buffer), ["", "third"]);
+        assert_eq!(buffer.bytes(), 5);
+    }
+
+    #[test]
+    fn oversized_entries_are_truncated() {
+        let mut buffer = RecentErrors::new(100, MAX_LOGGED_BYTES * 4);
+        let message = "x".repeat(MAX_LOGGED_BYTES * 2);
+
+        buffer.push(Level::Error, &message, 0);
+
+        let kept = &buffer.entries()[0].message;
+
+        assert_eq!(*kept, truncate_utf8(&message, MAX_LOGGED_BYTES));
+        assert_eq!(buffer.bytes(), kept.len());
+        assert!(buffer.bytes() < message.len());
+    }
+
+    #[test]
+    fn entry_that_does_not_fit_once_truncated_is_discarded() {
+        let mut buffer = RecentErrors::new(100, 8);
+
+        buffer.push(Level::Error, "kept", 0);
+        // truncated to "abcdefgh…(+4 bytes)" which is still over the budget
+        buffer.push(Level::Error, "abcdefghijkl", 1);
+
+        assert_eq!(messages(&buffer), ["kept"]);
+        assert_eq!(buffer.bytes(), 4);
+    }
+
+    #[test]
+    fn shrinking_the_limits_evicts_what_no_longer_fit() {
+        let mut buffer = RecentErrors::new(100, 1024);
+
+        for message in ["first", "second", "third"] {
+            buffer.push(Level::Error, message, 0);
+        }
+
+        buffer.set_limits(100, 10);
+
+        assert_eq!(messages(&buffer), ["third"]);
+        assert_eq!(buffer.bytes(), 5);
+
+        buffer.set_limits(0, 10);
+
+        assert!(buffer.entries().is_empty());
+        assert_eq!(buffer.bytes(), 0);
+    }
+}
@@ -0,0 +1,264 @@
+use super::{error::ServiceError, metrics::REQUEST_METRICS};
+use redis::{aio::ConnectionLike, ErrorKind, RedisResult};
+use std::{future::Future, sync::OnceLock, time::Duration};
+use tokio::time::{timeout, Instant};
+use tracing::{info, warn};
+
+/// fallback used until `set_command_timeout()` is called e.g. in tools that skip the app config
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_millis(2000);
+
+static COMMAND_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// whether the redis server understand `GETEX` (redis 6.2+). Assumed supported until probed
+static GETEX_SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+/// whether session redis commands record their latency, disabled until `set_latency_metrics()`
+static LATENCY_METRICS: OnceLock<bool> = OnceLock::new();
+
+/// set the process wide redis command timeout. Only the first call takes effect
+pub fn set_command_timeout(command_timeout: Duration) {
+    let _ = COMMAND_TIMEOUT.set(command_timeout);
+}
+
+/// run a redis `operation` bounded by the redis command timeout configured through
+/// `REDIS_COMMAND_TIMEOUT_MS`. Every redis touching feature should go through this function so
+/// an unresponsive redis server is always reported as `ServiceError::ClientTimeout`
+pub async fn redis_with_timeout<F, T>(operation: F) -> Result<T, ServiceError>
+where
+    F: Future<Output = RedisResult<T>>,
+{
+    with_timeout(
+        *COMMAND_TIMEOUT.get().unwrap_or(&DEFAULT_COMMAND_TIMEOUT),
+        operation,
+    )
+    .await
+}
+
+async fn with_timeout<F, T>(command_timeout: Duration, operation: F) -> Result<T, ServiceError>
+where
+    F: Future<Output = RedisResult<T>>,
+{
+    match timeout(command_timeout, operation).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(ServiceError::ClientTimeout),
+    }
+}
+
+/// enable or disable the session redis command latency histogram. Only the first call takes
+/// effect
+pub fn set_latency_metrics(enabled: bool) {
+    let _ = LATENCY_METRICS.set(enabled);
+}
+
+/// same as `redis_with_timeout()` but record the latency of the session redis `command` (e.g.
+/// `GETEX`, `DEL`) into the `redis_session_command_duration_seconds` histogram when enabled
+/// through `REDIS_LATENCY_METRICS`. Failed and timed out commands are recorded as well
+pub async fn session_command<F, T>(command: &'static str, operation: F) -> Result<T, ServiceError>
+where
+    F: Future<Output = RedisResult<T>>,
+{
+    let started_at = Instant::now();
+    let result = redis_with_timeout(operation).await;
+
+    if *LATENCY_METRICS.get().unwrap_or(&false) {
+        REQUEST_METRICS.observe_redis_command(command, started_at.elapsed());
+    }
+
+    result
+}
+
+/// detect whether the redis server support `GETEX` and remember the result for
+/// `get_with_expire()`. Only the first call takes effect
+pub async fn probe_getex_support<C>(connection: &mut C) -> bool
+where
+    C: ConnectionLike,
+{
+    let supported = getex_supported(connection).await;
+    let supported = *GETEX_SUPPORTED.get_or_init(|| supported);
+
+    if supported {
+        info!("redis session TTL refresh mode: GETEX");
+    } else {
+        warn!(
+            "redis does not support GETEX (requires 6.2+), session TTL refresh mode: GET + EXPIRE"
+        );
+    }
+
+    supported
+}
+
+/// whether the redis server behind `connection` understand `GETEX`. Any failure other than an
+/// unknown command is assumed to be transient
+async fn getex_supported<C>(connection: &mut C) -> bool
+where
+    C: ConnectionLike,
+{
+    let probe = redis_with_timeout(
+        redis::cmd("GETEX")
+            .arg("getex:probe")
+            .arg("EX")
+            .arg(1)
+            .query_async::<_, Option<String>>(connection),
+    )
+    .await;
+
+    match probe {
+        Err(ServiceError::Redis(e))
+            if e.kind() == ErrorKind::ResponseError
+                && e.to_string().to_lowercase().contains("unknown command") =>
+        {
+            false
+        }
+        Err(e) => {
+            warn!(
+                "redis GETEX probe failed, assuming GETEX is supported: {}",
+                e
+            );
+            true
+        }
+        Ok(_) => true,
+    }
+}
+
+/// get the value of `key` and reset its expiry to `seconds`. Fall back to an atomic `GET` +
+/// `EXPIRE` pipeline when `probe_getex_support()` found the server does not understand `GETEX`
+pub async fn get_with_expire<C>(
+    connection: &mut C,
+    key: &str,
+    seconds: i64,
+) -> Result<Option<String>, ServiceError>
+where
+    C: ConnectionLike,
+{
+    get_with_expire_using(
+        connection,
+        key,
+        seconds,
+        *GETEX_SUPPORTED.get().unwrap_or(&true),
+    )
+    .await
+}
+
+/// `get_with_expire()` with `GETEX` when `getex` is set, `GET` + `EXPIRE` otherwise
+async fn get_with_expire_using<C>(
+    connection: &mut C,
+    key: &str,
+    seconds: i64,
+    getex: bool,
+) -> Result<Option<String>, ServiceError>
+where
+    C: ConnectionLike,
+{
+    if getex {
+        return session_command(
+            "GETEX",
+            redis::cmd("GETEX")
+                .arg(key)
+                .arg("EX")
+                .arg(seconds)
+                .query_async::<_, Option<String>>(connection),
+        )
+        .await;
+    }
+
+    let (value,) = session_command(
+        "GET_EXPIRE",
+        redis::pipe()
+            .atomic()
+            .cmd("GET")
+            .arg(key)
+            .cmd("EXPIRE")
+            .arg(key)
+            .arg(seconds)
+            .ignore()
+            .query_async::<_, (Option<String>,)>(connection),
+    )
+    .await?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::{database::test_redis_pool, fake_redis::FakeRedis};
+    use tonic::{Code, Status};
+
+    const TEST_TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn command_exceeding_the_timeout_is_deadline_exceeded() {
+        // the server accept the command but never answer it
+        let mut redis_pool = test_redis_pool().await;
+        let error = with_timeout(
+            TEST_TIMEOUT,
+            redis::cmd("GET")
+                .arg("sid")
+                .query_async::<_, Option<String>>(&mut redis_pool),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, ServiceError::ClientTimeout));
+        assert_eq!(Status::from(error).code(), Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn command_within_the_timeout_succeeds() {
+        let value = with_timeout(TEST_TIMEOUT, async { Ok(Some("uid".to_string())) })
+            .await
+            .unwrap();
+
+        assert_eq!(value.as_deref(), Some("uid"));
+    }
+
+    #[tokio::test]
+    async fn probe_detects_getex_support() {
+        let (_fake_redis, mut redis_pool) = FakeRedis::start().await;
+
+        assert!(getex_supported(&mut redis_pool).await);
+    }
+
+    #[tokio::test]
+    async fn probe_detects_unknown_getex() {
+        let (_fake_redis, mut redis_pool) = FakeRedis::start_legacy().await;
+
+        assert!(!getex_supported(&mut redis_pool).await);
+    }
+
+    #[tokio::test]
+    async fn modern_redis_refreshes_with_getex() {
+        let (fake_redis, mut redis_pool) = FakeRedis::start().await;
+
+        fake_redis.set("sid", "uid");
+
+        let value = get_with_expire_using(&mut redis_pool, "sid", 60, true)
+            .await
+            .unwrap();
+
+        assert_eq!(value.as_deref(), Some("uid"));
+        assert!(fake_redis.ttl("sid").unwrap() > Duration::from_secs(50));
+        assert!(fake_redis.commands().contains(&"GETEX".to_string()));
+    }
+
+    #[tokio::test]
+    async fn legacy_redis_falls_back_to_get_and_expire() {
+        let (fake_redis, mut redis_pool) = FakeRedis::start_legacy().await;
+
+        fake_redis.set("sid", "uid");
+
+        assert!(!getex_supported(&mut redis_pool).await);
+
+        let value = get_with_expire_using(&mut redis_pool, "sid", 60, false)
+            .await
+            .unwrap();
+        let commands = fake_redis.commands();
+
+        assert_eq!(value.as_deref(), Some("uid"));
+        assert!(fake_redis.ttl("sid").unwrap() > Duration::from_secs(50));
+        // only the probe tried `GETEX`
+        assert_eq!(commands.iter().filter(|name| *name == "GETEX").count(), 1);
+        assert!(commands.contains(&"GET".to_string()));
+        assert!(commands.contains(&"EXPIRE".to_string()));
+    }
+}
@@ -0,0 +1,194 @@
+use super::error::ServiceError;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+use tracing::warn;
+
+/// fallback used until `set_retry_budget_ratio()` is called
+const DEFAULT_RETRY_BUDGET_RATIO: f64 = 0.1;
+/// fixed point scale of the budget balance so fractional deposits are not lost
+const TOKEN: u64 = 1000;
+/// upper bound of retries that can be saved up while downstream is healthy
+const MAX_BALANCE: u64 = 10 * TOKEN;
+
+static RETRY_BUDGET: OnceLock<RetryBudget> = OnceLock::new();
+
+#[derive(Debug)]
+/// token bucket shared by every outbound call. Each call deposit `ratio` of a token and each retry
+/// withdraw a whole one so retries stay capped at `ratio` of the calls, no matter how many
+/// callers are failing at once
+pub struct RetryBudget {
+    deposit: u64,
+    balance: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64) -> Self {
+        RetryBudget {
+            deposit: (ratio.clamp(0.0, 1.0) * TOKEN as f64) as u64,
+            balance: AtomicU64::new(0),
+        }
+    }
+
+    /// credit the budget for a new outbound call
+    pub fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                Some((balance + self.deposit).min(MAX_BALANCE))
+            });
+    }
+
+    /// take a token for a retry. Return `false` if the budget is exhausted
+    pub fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                balance.checked_sub(TOKEN)
+            })
+            .is_ok()
+    }
+}
+
+/// set the process wide retry budget ratio (`RETRY_BUDGET_RATIO`). Only the first call takes
+/// effect
+pub fn set_retry_budget_ratio(ratio: f64) {
+    let _ = RETRY_BUDGET.set(RetryBudget::new(ratio));
+}
+
+fn retry_budget() -> &'static RetryBudget {
+    RETRY_BUDGET.get_or_init(|| RetryBudget::new(DEFAULT_RETRY_BUDGET_RATIO))
+}
+
+/// run `operation` up to `max_attempts` times. A failed attempt is only retried when the error is
+/// transient and the process wide retry budget allows it
+pub async fn with_retry<F, Fut, T>(max_attempts: usize, operation: F) -> Result<T, ServiceError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ServiceError>>,
+{
+    with_retry_budget(retry_budget(), max_attempts, operation).await
+}
+
+/// `with_retry()` drawing retries from `budget`
+async fn with_retry_budget<F, Fut, T>(
+    budget: &RetryBudget,
+    max_attempts: usize,
+    mut operation: F,
+) -> Result<T, ServiceError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ServiceError>>,
+{
+    budget.deposit();
+
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Err(e) if e.is_transient() && attempt < max_attempts && budget.withdraw() => {
+                warn!(
+                    "retrying outbound call after attempt {} failed: {}",
+                    attempt, e
+                );
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    const MAX_ATTEMPTS: usize = 3;
+
+    /// make a call failing every attempt with a transient error through `budget` and return the
+    /// number of attempts it made
+    async fn failing_call(budget: &RetryBudget) -> usize {
+        let attempts = AtomicUsize::new(0);
+        let result = with_retry_budget(budget, MAX_ATTEMPTS, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(ServiceError::ClientTimeout)
+        })
+        .await;
+
+        assert!(result.is_err());
+
+        attempts.into_inner()
+    }
+
+    #[test]
+    fn budget_allows_a_retry_per_ratio_of_calls() {
+        let budget = RetryBudget::new(0.1);
+
+        assert!(!budget.withdraw());
+
+        for _ in 0..10 {
+            budget.deposit();
+        }
+
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn saved_up_retries_are_capped() {
+        let budget = RetryBudget::new(1.0);
+
+        for _ in 0..100 {
+            budget.deposit();
+        }
+
+        let retries = std::iter::from_fn(|| budget.withdraw().then_some(())).count();
+
+        assert_eq!(retries as u64, MAX_BALANCE / TOKEN);
+    }
+
+    #[tokio::test]
+    async fn retries_are_throttled_once_the_budget_is_spent_and_resume_after_refill() {
+        let budget = RetryBudget::new(0.1);
+        let mut attempts = 0;
+
+        // every call fail, only one retry per ten calls is allowed
+        for _ in 0..100 {
+            attempts += failing_call(&budget).await;
+        }
+
+        assert!(attempts <= 100 + 10, "{} attempts", attempts);
+        assert_eq!(failing_call(&budget).await, 1);
+
+        // healthy calls refill the budget
+        for _ in 0..20 {
+            with_retry_budget(&budget, MAX_ATTEMPTS, || async { Ok(()) })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(failing_call(&budget).await, MAX_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let budget = RetryBudget::new(1.0);
+        let attempts = AtomicUsize::new(0);
+
+        for _ in 0..10 {
+            budget.deposit();
+        }
+
+        let result = with_retry_budget(&budget, MAX_ATTEMPTS, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(ServiceError::BadCredential)
+        })
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::BadCredential)));
+        assert_eq!(attempts.into_inner(), 1);
+    }
+}
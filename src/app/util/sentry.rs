@@ -1,3 +1,6 @@
+#[cfg(feature = "amqp")]
+use super::mirror::mirror_error;
+use super::recent::record_recent_error;
 use sentry::{capture_message, Level};
 
 pub fn capture_warning<T>(msg: T)
@@ -5,6 +8,10 @@ where
     T: AsRef<str>,
 {
     capture_message(msg.as_ref(), Level::Warning);
+    record_recent_error(Level::Warning, msg.as_ref());
+
+    #[cfg(feature = "amqp")]
+    mirror_error(Level::Warning, msg.as_ref());
 }
 
 pub fn capture_error<T>(msg: T)
@@ -12,6 +19,10 @@ where
     T: AsRef<str>,
 {
     capture_message(msg.as_ref(), Level::Error);
+    record_recent_error(Level::Error, msg.as_ref());
+
+    #[cfg(feature = "amqp")]
+    mirror_error(Level::Error, msg.as_ref());
 }
 
 pub fn capture_fatal<T>(msg: T)
@@ -19,4 +30,8 @@ where
     T: AsRef<str>,
 {
     capture_message(msg.as_ref(), Level::Fatal);
+    record_recent_error(Level::Fatal, msg.as_ref());
+
+    #[cfg(feature = "amqp")]
+    mirror_error(Level::Fatal, msg.as_ref());
 }
@@ -0,0 +1,869 @@
+use super::{
+    clock::{remaining_ttl, unix_now},
+    error::ServiceError,
+    redis::{get_with_expire, redis_with_timeout, session_command},
+};
+use crate::app::config::database::RedisPool;
+use futures::future::try_join_all;
+use std::{collections::HashMap, fmt, str::FromStr, sync::Mutex};
+use time::Duration;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// how the lifetime of a session evolves once it has been issued
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionExpiryMode {
+    /// every access pushes the expiry further according to the read and write TTL
+    #[default]
+    Sliding,
+    /// the session expires once the write TTL elapsed since login, whatever the activity
+    Absolute,
+}
+
+impl FromStr for SessionExpiryMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_ascii_lowercase().as_str() {
+            "sliding" => Ok(SessionExpiryMode::Sliding),
+            "absolute" => Ok(SessionExpiryMode::Absolute),
+            mode => Err(format!(
+                "expect either sliding or absolute but got {}",
+                mode
+            )),
+        }
+    }
+}
+
+/// where the session records are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionBackend {
+    /// shared by every replica
+    #[default]
+    Redis,
+    /// local to this process and lost on restart, only suitable for a single replica
+    Memory,
+}
+
+impl FromStr for SessionBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend.trim().to_ascii_lowercase().as_str() {
+            "redis" => Ok(SessionBackend::Redis),
+            "memory" => Ok(SessionBackend::Memory),
+            backend => Err(format!("expect either redis or memory but got {}", backend)),
+        }
+    }
+}
+
+/// lifetime rules a `SessionStore` apply to the sessions it holds
+#[derive(Debug, Clone, Copy)]
+pub struct SessionPolicy {
+    /// lifetime granted by a read access, never shorter than what the last write access granted
+    pub read_ttl: Duration,
+    /// lifetime granted on login and by a write access
+    pub write_ttl: Duration,
+    pub expiry_mode: SessionExpiryMode,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            read_ttl: Duration::hours(24),
+            write_ttl: Duration::hours(24),
+            expiry_mode: SessionExpiryMode::Sliding,
+        }
+    }
+}
+
+/// how a request access its session
+#[derive(Debug, Clone, Copy)]
+pub struct SessionAccess {
+    pub is_write: bool,
+    /// also fetch the version of the client that issued the session
+    pub with_client_version: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    pub uid: Uuid,
+    /// only fetched when asked through `SessionAccess::with_client_version`
+    pub client_version: Option<String>,
+}
+
+/// storage of the `sid -> uid` session records. The store apply its `SessionPolicy` on every
+/// access so the callers never deal with the session lifetime themselves
+#[tonic::async_trait]
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    /// resolve the session `sid` and extend its lifetime according to `access`. Missing, expired
+    /// and corrupted sessions are `None`, the latter being deleted
+    async fn get(
+        &self,
+        sid: &str,
+        access: SessionAccess,
+    ) -> Result<Option<SessionRecord>, ServiceError>;
+
+    /// resolve the uid of every session in `sids` without extending their lifetime. The result
+    /// is aligned with `sids`; missing sessions and records holding an invalid uid are both `None`
+    async fn get_many(&self, sids: &[String]) -> Result<Vec<Option<Uuid>>, ServiceError>;
+
+    /// issue the session `sid` of `uid` with the write TTL, recording the version of the client
+    /// that logged in if known. An existing session `sid` is left untouched and reported as
+    /// `ServiceError::Conflict`
+    async fn set(
+        &self,
+        sid: &str,
+        uid: Uuid,
+        client_version: Option<&str>,
+    ) -> Result<(), ServiceError>;
+
+    /// delete the session `sid`. Deleting an already expired session is not an error
+    async fn delete(&self, sid: &str) -> Result<(), ServiceError>;
+}
+
+// the metadata keys hash tag the sid so in cluster mode they land in the slot of the `sid` key
+// itself (a key without a hash tag is hashed whole) and a session can be handled by a single
+// multi-key command
+
+/// redis key holding the client version that issued the session `sid`
+pub fn client_version_key(sid: &str) -> String {
+    format!("{{{}}}:client_version", sid)
+}
+
+/// redis key holding the unix timestamp of the last write method access of the session `sid`
+pub fn last_write_key(sid: &str) -> String {
+    format!("{{{}}}:last_write", sid)
+}
+
+/// redis key holding the unix timestamp at which the session `sid` expires in absolute mode
+pub fn expires_at_key(sid: &str) -> String {
+    format!("{{{}}}:expires_at", sid)
+}
+
+#[derive(Clone)]
+/// redis backed store keeping the uid under the `sid` key and the session metadata under the
+/// `{sid}:*` keys (see `client_version_key()`, `last_write_key()` and `expires_at_key()`)
+pub struct RedisSessionStore {
+    redis_pool: RedisPool,
+    policy: SessionPolicy,
+}
+
+impl RedisSessionStore {
+    pub fn new(redis_pool: RedisPool, policy: SessionPolicy) -> Self {
+        RedisSessionStore { redis_pool, policy }
+    }
+
+    /// fetch the uid of the session `sid` without extending its lifetime. A session without a
+    /// recorded expiry (e.g. issued in sliding mode) or past it is reported as missing. Return the
+    /// uid (if the session is still valid) and its remaining lifetime
+    async fn fetch_absolute_session(
+        redis_pool: &mut RedisPool,
+        sid: &str,
+    ) -> Result<(Option<String>, Duration), ServiceError> {
+        let expires_at = session_command(
+            "GET",
+            redis::cmd("GET")
+                .arg(expires_at_key(sid))
+                .query_async::<_, Option<i64>>(redis_pool),
+        )
+        .await?;
+        let remaining = match expires_at {
+            Some(expires_at) if expires_at > unix_now() => {
+                Duration::seconds(expires_at - unix_now())
+            }
+            _ => return Ok((None, Duration::ZERO)),
+        };
+
+        let uid = session_command(
+            "GET",
+            redis::cmd("GET")
+                .arg(sid)
+                .query_async::<_, Option<String>>(redis_pool),
+        )
+        .await?;
+
+        Ok((uid, remaining))
+    }
+
+    /// fetch the uid of the session `sid` and extend its lifetime. A write access get the write
+    /// TTL and record its timestamp while a read access get the read TTL unless the last write
+    /// granted a longer remaining lifetime. Return the uid (if the session exist) and the applied
+    /// TTL. In absolute mode the lifetime is left untouched, see `fetch_absolute_session()`
+    async fn touch(
+        &self,
+        redis_pool: &mut RedisPool,
+        sid: &str,
+        is_write: bool,
+    ) -> Result<(Option<String>, Duration), ServiceError> {
+        if self.policy.expiry_mode == SessionExpiryMode::Absolute {
+            return Self::fetch_absolute_session(redis_pool, sid).await;
+        }
+
+        let write_ttl = self.policy.write_ttl;
+        let now = unix_now();
+
+        let ttl = if is_write {
+            write_ttl
+        } else {
+            let last_write = session_command(
+                "GET",
+                redis::cmd("GET")
+                    .arg(last_write_key(sid))
+                    .query_async::<_, Option<i64>>(redis_pool),
+            )
+            .await?;
+            let remaining = last_write
+                .map(|last_write| remaining_ttl(last_write, write_ttl))
+                .unwrap_or(Duration::ZERO);
+
+            remaining.max(self.policy.read_ttl)
+        };
+
+        let uid = get_with_expire(redis_pool, sid, ttl.whole_seconds()).await?;
+
+        if is_write && uid.is_some() {
+            session_command(
+                "SET",
+                redis::cmd("SET")
+                    .arg(last_write_key(sid))
+                    .arg(now)
+                    .arg("EX")
+                    .arg(ttl.whole_seconds())
+                    .query_async::<_, ()>(redis_pool),
+            )
+            .await?;
+        }
+
+        Ok((uid, ttl))
+    }
+}
+
+impl fmt::Debug for RedisSessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisSessionStore")
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+#[tonic::async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn get(
+        &self,
+        sid: &str,
+        access: SessionAccess,
+    ) -> Result<Option<SessionRecord>, ServiceError> {
+        let mut redis_pool = self.redis_pool.clone();
+        let (uid, ttl) = self.touch(&mut redis_pool, sid, access.is_write).await?;
+        let uid = match uid.as_deref().map(Uuid::parse_str) {
+            Some(Ok(uid)) => uid,
+            // a corrupted record is a server side problem, the client only has to login again
+            Some(Err(e)) => {
+                error!("session {} hold an invalid uid, deleting it: {:?}", sid, e);
+
+                if let Err(e) = self.delete(sid).await {
+                    warn!("failed to delete the invalid session {}: {}", sid, e);
+                }
+
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        // the version shares the lifetime of the session it was issued with
+        let client_version = match access.with_client_version {
+            true => {
+                get_with_expire(
+                    &mut redis_pool,
+                    &client_version_key(sid),
+                    ttl.whole_seconds(),
+                )
+                .await?
+            }
+            false => None,
+        };
+
+        Ok(Some(SessionRecord {
+            uid,
+            client_version,
+        }))
+    }
+
+    async fn get_many(&self, sids: &[String]) -> Result<Vec<Option<Uuid>>, ServiceError> {
+        // `MGET` without any key is a redis error
+        if sids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut redis_pool = self.redis_pool.clone();
+        let records = match redis_pool {
+            RedisPool::Single(_) => {
+                redis_with_timeout(
+                    redis::cmd("MGET")
+                        .arg(sids)
+                        .query_async::<_, Vec<Option<String>>>(&mut redis_pool),
+                )
+                .await?
+            }
+            // the sessions hash to arbitrary slots and a multi-key command must stay in one slot
+            RedisPool::Cluster(_) => {
+                try_join_all(sids.iter().map(|sid| {
+                    let mut redis_pool = redis_pool.clone();
+
+                    async move {
+                        redis_with_timeout(
+                            redis::cmd("GET")
+                                .arg(sid)
+                                .query_async::<_, Option<String>>(&mut redis_pool),
+                        )
+                        .await
+                    }
+                }))
+                .await?
+            }
+        };
+
+        Ok(sids
+            .iter()
+            .zip(records)
+            .map(|(sid, record)| {
+                record.and_then(|uid| match Uuid::parse_str(&uid) {
+                    Ok(uid) => Some(uid),
+                    Err(e) => {
+                        warn!("session {} hold an invalid uid: {:?}", sid, e);
+                        None
+                    }
+                })
+            })
+            .collect())
+    }
+
+    async fn set(
+        &self,
+        sid: &str,
+        uid: Uuid,
+        client_version: Option<&str>,
+    ) -> Result<(), ServiceError> {
+        let mut redis_pool = self.redis_pool.clone();
+        let ttl = self.policy.write_ttl;
+        let expire = usize::try_from(ttl.whole_seconds()).map_err(|_| ServiceError::TryFrom {
+            field: "session_write_ttl",
+            from: ttl.whole_seconds().to_string(),
+            into: "usize",
+            expect: "a positive number of seconds",
+        })?;
+
+        // claim the sid on its own so the metadata of an existing session is never overwritten
+        let claimed = session_command(
+            "SET",
+            redis::cmd("SET")
+                .arg(sid)
+                .arg(uid.to_string())
+                .arg("EX")
+                .arg(ttl.whole_seconds())
+                .arg("NX")
+                .query_async::<_, bool>(&mut redis_pool),
+        )
+        .await?;
+
+        if !claimed {
+            return Err(ServiceError::Conflict("session already exists".to_string()));
+        }
+
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .set_ex(last_write_key(sid), unix_now(), expire)
+            .ignore();
+
+        // in absolute mode the session is bound to the expiry recorded here whatever the activity
+        if self.policy.expiry_mode == SessionExpiryMode::Absolute {
+            pipeline
+                .set_ex(
+                    expires_at_key(sid),
+                    unix_now() + ttl.whole_seconds(),
+                    expire,
+                )
+                .ignore();
+        }
+
+        // record the issuing client version so outdated sessions can be forced to login again
+        if let Some(client_version) = client_version {
+            pipeline
+                .set_ex(client_version_key(sid), client_version, expire)
+                .ignore();
+        }
+
+        session_command("SETEX", pipeline.query_async::<_, ()>(&mut redis_pool)).await
+    }
+
+    async fn delete(&self, sid: &str) -> Result<(), ServiceError> {
+        let mut redis_pool = self.redis_pool.clone();
+
+        session_command(
+            "DEL",
+            redis::cmd("DEL")
+                .arg(sid)
+                .arg(client_version_key(sid))
+                .arg(last_write_key(sid))
+                .arg(expires_at_key(sid))
+                .query_async::<_, i64>(&mut redis_pool),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct MemorySession {
+    uid: Uuid,
+    client_version: Option<String>,
+    /// unix timestamp at which the session expires
+    expires_at: i64,
+    /// unix timestamp of the last write access
+    last_write: i64,
+}
+
+/// in-process store applying the same lifetime rules as `RedisSessionStore`. Expired sessions
+/// are evicted when accessed and whenever a new session is issued
+#[derive(Debug)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, MemorySession>>,
+    policy: SessionPolicy,
+}
+
+impl MemorySessionStore {
+    pub fn new(policy: SessionPolicy) -> Self {
+        MemorySessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            policy,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn get(
+        &self,
+        sid: &str,
+        access: SessionAccess,
+    ) -> Result<Option<SessionRecord>, ServiceError> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .expect("expect memory session store lock to not be poisoned");
+        let now = unix_now();
+
+        let session = match sessions.get_mut(sid) {
+            Some(session) if session.expires_at > now => session,
+            Some(_) => {
+                sessions.remove(sid);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+
+        if self.policy.expiry_mode == SessionExpiryMode::Sliding {
+            let write_ttl = self.policy.write_ttl;
+            let ttl = match access.is_write {
+                true => {
+                    session.last_write = now;
+                    write_ttl
+                }
+                false => remaining_ttl(session.last_write, write_ttl).max(self.policy.read_ttl),
+            };
+
+            session.expires_at = now + ttl.whole_seconds();
+        }
+
+        Ok(Some(SessionRecord {
+            uid: session.uid,
+            client_version: access
+                .with_client_version
+                .then(|| session.client_version.clone())
+                .flatten(),
+        }))
+    }
+
+    async fn get_many(&self, sids: &[String]) -> Result<Vec<Option<Uuid>>, ServiceError> {
+        let sessions = self
+            .sessions
+            .lock()
+            .expect("expect memory session store lock to not be poisoned");
+        let now = unix_now();
+
+        Ok(sids
+            .iter()
+            .map(|sid| {
+                sessions
+                    .get(sid)
+                    .filter(|session| session.expires_at > now)
+                    .map(|session| session.uid)
+            })
+            .collect())
+    }
+
+    async fn set(
+        &self,
+        sid: &str,
+        uid: Uuid,
+        client_version: Option<&str>,
+    ) -> Result<(), ServiceError> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .expect("expect memory session store lock to not be poisoned");
+        let now = unix_now();
+
+        sessions.retain(|_, session| session.expires_at > now);
+
+        if sessions.contains_key(sid) {
+            return Err(ServiceError::Conflict("session already exists".to_string()));
+        }
+
+        sessions.insert(
+            sid.to_string(),
+            MemorySession {
+                uid,
+                client_version: client_version.map(str::to_string),
+                expires_at: now + self.policy.write_ttl.whole_seconds(),
+                last_write: now,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn delete(&self, sid: &str) -> Result<(), ServiceError> {
+        self.sessions
+            .lock()
+            .expect("expect memory session store lock to not be poisoned")
+            .remove(sid);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{
+        config::fake_redis::FakeRedis,
+        util::{metrics::REQUEST_METRICS, redis::set_latency_metrics},
+    };
+
+    #[tokio::test]
+    async fn redis_get_many_aligns_with_the_input_order() {
+        let (fake_redis, redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(redis_pool, SessionPolicy::default());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        fake_redis.set("present-1", &first.to_string());
+        fake_redis.set("corrupt", "not-a-uuid");
+        fake_redis.set("present-2", &second.to_string());
+
+        let sids = ["present-2", "missing", "corrupt", "present-1"].map(String::from);
+
+        assert_eq!(
+            store.get_many(&sids).await.unwrap(),
+            [Some(second), None, None, Some(first)]
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_get_many_without_sids_is_empty() {
+        let (_fake_redis, redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(redis_pool, SessionPolicy::default());
+
+        assert!(store.get_many(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_get_many_aligns_with_the_input_order() {
+        let store = MemorySessionStore::new(SessionPolicy::default());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        store.set("present-1", first, None).await.unwrap();
+        store.set("present-2", second, None).await.unwrap();
+
+        let sids = ["present-2", "missing", "present-1"].map(String::from);
+
+        assert_eq!(
+            store.get_many(&sids).await.unwrap(),
+            [Some(second), None, Some(first)]
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_store_records_the_latency_of_each_command() {
+        // recording is process wide, other tests may record commands concurrently
+        set_latency_metrics(true);
+
+        let (_fake_redis, redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(redis_pool, SessionPolicy::default());
+        let count = |command| REQUEST_METRICS.redis_command_count(command);
+        let before = ["SET", "DEL"].map(count);
+
+        store.set("sid", Uuid::new_v4(), None).await.unwrap();
+        store.delete("sid").await.unwrap();
+
+        assert!(count("SET") > before[0]);
+        assert!(count("DEL") > before[1]);
+    }
+
+    const ACCESS: SessionAccess = SessionAccess {
+        is_write: false,
+        with_client_version: false,
+    };
+
+    fn policy(expiry_mode: SessionExpiryMode) -> SessionPolicy {
+        SessionPolicy {
+            read_ttl: Duration::hours(1),
+            write_ttl: Duration::hours(1),
+            expiry_mode,
+        }
+    }
+
+    /// redis store issuing the session `sid` of a random uid, whose lifetime is then cut short to
+    /// a few seconds as if it had been issued a while ago
+    async fn redis_session(expiry_mode: SessionExpiryMode) -> (FakeRedis, RedisSessionStore) {
+        let (fake_redis, mut redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(redis_pool.clone(), policy(expiry_mode));
+
+        store.set("sid", Uuid::new_v4(), None).await.unwrap();
+        redis::cmd("EXPIRE")
+            .arg("sid")
+            .arg(5)
+            .query_async::<_, ()>(&mut redis_pool)
+            .await
+            .unwrap();
+
+        (fake_redis, store)
+    }
+
+    #[tokio::test]
+    async fn redis_sliding_access_extends_the_session() {
+        let (fake_redis, store) = redis_session(SessionExpiryMode::Sliding).await;
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_some());
+        assert!(fake_redis.ttl("sid").unwrap() > std::time::Duration::from_secs(60));
+        assert!(fake_redis.get(&expires_at_key("sid")).is_none());
+    }
+
+    #[tokio::test]
+    async fn redis_absolute_access_leaves_the_expiry_untouched() {
+        let (fake_redis, store) = redis_session(SessionExpiryMode::Absolute).await;
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_some());
+        assert!(fake_redis.ttl("sid").unwrap() <= std::time::Duration::from_secs(5));
+
+        let expires_at: i64 = fake_redis
+            .get(&expires_at_key("sid"))
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert!(expires_at > unix_now() + 60);
+    }
+
+    #[tokio::test]
+    async fn redis_absolute_session_past_its_expiry_is_missing() {
+        let (fake_redis, store) = redis_session(SessionExpiryMode::Absolute).await;
+
+        fake_redis.set(&expires_at_key("sid"), &(unix_now() - 1).to_string());
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_none());
+
+        // a session issued in sliding mode has no recorded expiry to enforce
+        let sliding =
+            RedisSessionStore::new(store.redis_pool.clone(), policy(SessionExpiryMode::Sliding));
+
+        sliding.set("other", Uuid::new_v4(), None).await.unwrap();
+
+        assert!(store.get("other", ACCESS).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_store_extends_only_sliding_sessions() {
+        for (expiry_mode, extended) in [
+            (SessionExpiryMode::Sliding, true),
+            (SessionExpiryMode::Absolute, false),
+        ] {
+            let store = MemorySessionStore::new(policy(expiry_mode));
+            let expires_at = unix_now() + 5;
+
+            store.set("sid", Uuid::new_v4(), None).await.unwrap();
+            store
+                .sessions
+                .lock()
+                .unwrap()
+                .get_mut("sid")
+                .unwrap()
+                .expires_at = expires_at;
+
+            assert!(store.get("sid", ACCESS).await.unwrap().is_some());
+            assert_eq!(
+                store.sessions.lock().unwrap()["sid"].expires_at > expires_at,
+                extended,
+                "{:?}",
+                expiry_mode
+            );
+
+            store
+                .sessions
+                .lock()
+                .unwrap()
+                .get_mut("sid")
+                .unwrap()
+                .expires_at = unix_now();
+
+            assert!(store.get("sid", ACCESS).await.unwrap().is_none());
+        }
+    }
+
+    /// the behavior every `SessionStore` must share whatever its backend
+    async fn assert_store_contract(store: &dyn SessionStore) {
+        let uid = Uuid::new_v4();
+        let access = |with_client_version| SessionAccess {
+            is_write: false,
+            with_client_version,
+        };
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_none());
+        store.set("sid", uid, Some("1.2.0")).await.unwrap();
+
+        let record = store.get("sid", access(true)).await.unwrap().unwrap();
+
+        assert_eq!(record.uid, uid);
+        assert_eq!(record.client_version.as_deref(), Some("1.2.0"));
+        assert_eq!(
+            store
+                .get("sid", access(false))
+                .await
+                .unwrap()
+                .unwrap()
+                .client_version,
+            None
+        );
+
+        // an issued session is never overwritten
+        assert!(matches!(
+            store.set("sid", Uuid::new_v4(), None).await,
+            Err(ServiceError::Conflict(_))
+        ));
+        assert_eq!(store.get("sid", ACCESS).await.unwrap().unwrap().uid, uid);
+
+        store.delete("sid").await.unwrap();
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_none());
+        store.delete("sid").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn redis_store_follows_the_store_contract() {
+        let (_fake_redis, redis_pool) = FakeRedis::start().await;
+
+        assert_store_contract(&RedisSessionStore::new(
+            redis_pool,
+            SessionPolicy::default(),
+        ))
+        .await;
+    }
+
+    #[tokio::test]
+    async fn memory_store_follows_the_store_contract() {
+        assert_store_contract(&MemorySessionStore::new(SessionPolicy::default())).await;
+    }
+
+    #[tokio::test]
+    async fn redis_read_access_keeps_the_longer_ttl_of_the_last_write() {
+        let (fake_redis, mut redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(
+            redis_pool.clone(),
+            SessionPolicy {
+                read_ttl: Duration::hours(1),
+                write_ttl: Duration::hours(2),
+                expiry_mode: SessionExpiryMode::Sliding,
+            },
+        );
+        let hours = |hours: u64| std::time::Duration::from_secs(hours * 60 * 60);
+        let ttl = || fake_redis.ttl("sid").unwrap();
+
+        store.set("sid", Uuid::new_v4(), None).await.unwrap();
+        redis::cmd("EXPIRE")
+            .arg("sid")
+            .arg(5)
+            .query_async::<_, ()>(&mut redis_pool)
+            .await
+            .unwrap();
+
+        // the login just granted the write TTL
+        store.get("sid", ACCESS).await.unwrap().unwrap();
+        assert!(ttl() > hours(1));
+        assert!(fake_redis.commands().contains(&"GETEX".to_string()));
+
+        // once the write TTL elapsed a read only grant the read TTL
+        fake_redis.set(
+            &last_write_key("sid"),
+            &(unix_now() - 2 * 60 * 60).to_string(),
+        );
+        store.get("sid", ACCESS).await.unwrap().unwrap();
+        assert!(ttl() <= hours(1));
+
+        let write = SessionAccess {
+            is_write: true,
+            with_client_version: false,
+        };
+
+        store.get("sid", write).await.unwrap().unwrap();
+        assert!(ttl() > hours(1));
+        assert!(
+            fake_redis
+                .get(&last_write_key("sid"))
+                .unwrap()
+                .parse::<i64>()
+                .unwrap()
+                >= unix_now() - 1
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_corrupted_session_is_missing_and_deleted() {
+        let (fake_redis, redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(redis_pool, SessionPolicy::default());
+
+        fake_redis.set("sid", "not-a-uuid");
+        fake_redis.set(&client_version_key("sid"), "1.0.0");
+        fake_redis.set(&last_write_key("sid"), &unix_now().to_string());
+
+        assert!(store.get("sid", ACCESS).await.unwrap().is_none());
+
+        for key in [
+            "sid".to_string(),
+            client_version_key("sid"),
+            last_write_key("sid"),
+        ] {
+            assert!(fake_redis.get(&key).is_none(), "{} was not deleted", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_negative_write_ttl_is_rejected_before_any_write() {
+        let (fake_redis, redis_pool) = FakeRedis::start().await;
+        let store = RedisSessionStore::new(
+            redis_pool,
+            SessionPolicy {
+                write_ttl: Duration::seconds(-1),
+                ..SessionPolicy::default()
+            },
+        );
+
+        assert!(matches!(
+            store.set("sid", Uuid::new_v4(), Some("1.0.0")).await,
+            Err(ServiceError::TryFrom { .. })
+        ));
+        assert!(fake_redis.commands().is_empty());
+    }
+}
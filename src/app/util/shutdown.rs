@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+use tokio::{sync::Notify, time::Instant};
+
+#[derive(Debug, Default)]
+/// application wide shutdown signal. Unlike a bare `tokio::sync::Notify`, this struct remember
+/// that shutdown was already triggered so anything that start waiting after the fact will still
+/// resolve immediately instead of waiting for a notification that will never come again
+pub struct ShutdownSignal {
+    notifier: Notify,
+    triggered: AtomicBool,
+    triggered_at: OnceLock<Instant>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        ShutdownSignal::default()
+    }
+
+    /// mark the application as shutting down and wake up every waiter
+    pub fn trigger(&self) {
+        let _ = self.triggered_at.set(Instant::now());
+        self.triggered.store(true, Ordering::SeqCst);
+        self.notifier.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// point in time at which the shutdown was first triggered, `None` while still serving
+    pub fn triggered_at(&self) -> Option<Instant> {
+        self.triggered_at.get().copied()
+    }
+
+    /// wait until the shutdown signal is triggered. Resolve immediately if it already was
+    pub async fn notified(&self) {
+        // `Notified` created before `notify_waiters()` is called will still be woken up so the
+        // future has to be created before checking the flag to not miss the notification
+        let notified = self.notifier.notified();
+
+        if self.is_triggered() {
+            return;
+        }
+
+        notified.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn waiter_before_the_trigger_is_woken() {
+        let signal = Arc::new(ShutdownSignal::new());
+        let waiter = tokio::spawn({
+            let signal = Arc::clone(&signal);
+
+            async move { signal.notified().await }
+        });
+
+        tokio::task::yield_now().await;
+        signal.trigger();
+
+        timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(signal.triggered_at().is_some());
+    }
+
+    #[tokio::test]
+    async fn waiter_after_the_trigger_resolves_immediately() {
+        let signal = ShutdownSignal::new();
+
+        signal.trigger();
+
+        timeout(Duration::from_secs(1), signal.notified())
+            .await
+            .unwrap();
+    }
+}
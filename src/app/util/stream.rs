@@ -1,7 +1,143 @@
-use std::sync::Arc;
-use tokio::sync::{mpsc, Notify};
+use super::{error::ServiceError, metrics::STREAM_METRICS};
+use crate::app::config::task::spawn_with_name;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Notify, OwnedSemaphorePermit,
+    },
+    task::JoinHandle,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
 use tokio_stream::Stream;
-use tracing::debug;
+use tonic::Status;
+use tracing::{debug, warn};
+
+/// minimum time between two stream backlog warnings across the whole process
+const BACKLOG_WARNING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// a stream is considered backed up once at most `1 / BACKLOG_FREE_RATIO` of its buffer is free
+const BACKLOG_FREE_RATIO: usize = 4;
+
+static BACKLOG_WARNINGS: Mutex<BacklogWarnings> = Mutex::new(BacklogWarnings {
+    last_warned: None,
+    suppressed: 0,
+});
+
+struct BacklogWarnings {
+    last_warned: Option<Instant>,
+    suppressed: u64,
+}
+
+#[derive(Debug)]
+/// this struct keep track of every `ClientCancellableStream` that was registered through
+/// `ClientCancellableStream::register()`. The registry only hold the terminal slot of each stream
+/// (not the data channel) so it will never keep a finished stream alive on its own
+pub struct StreamRegistry<T> {
+    next_id: AtomicU64,
+    terminals: Mutex<HashMap<u64, oneshot::Sender<T>>>,
+}
+
+impl<T> StreamRegistry<T> {
+    pub fn new() -> Self {
+        StreamRegistry {
+            next_id: AtomicU64::new(0),
+            terminals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// push a terminal item created by `terminal` into every registered stream. Each stream stop
+    /// accepting new items, flush what is already buffered, then yield that item as its very last
+    /// message and close. Returns the number of streams that received the terminal item
+    pub fn drain<F>(&self, terminal: F) -> usize
+    where
+        F: Fn() -> T,
+    {
+        let terminals = std::mem::take(
+            &mut *self
+                .terminals
+                .lock()
+                .expect("expect stream registry lock to not be poisoned"),
+        );
+
+        terminals
+            .into_values()
+            .filter_map(|terminal_pusher| terminal_pusher.send(terminal()).ok())
+            .count()
+    }
+
+    fn insert(&self, terminal_pusher: oneshot::Sender<T>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.terminals
+            .lock()
+            .expect("expect stream registry lock to not be poisoned")
+            .insert(id, terminal_pusher);
+
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        self.terminals
+            .lock()
+            .expect("expect stream registry lock to not be poisoned")
+            .remove(&id);
+    }
+}
+
+impl<T> Default for StreamRegistry<T> {
+    fn default() -> Self {
+        StreamRegistry::new()
+    }
+}
+
+/// completion guard of a `ClientCancellableStream` producer. The guard must be moved into the
+/// producer task and `finish()` must be called once the producer completed normally. If the guard
+/// get dropped without finishing (e.g. the producer task panicked or was aborted), the stream will
+/// yield the item created by `aborted` as its terminal item instead of closing silently
+pub struct StreamCompletion<T> {
+    slot: Arc<Mutex<Option<T>>>,
+    aborted: Option<Box<dyn FnOnce() -> T + Send>>,
+    // keep the data channel open until the guard is dropped so the stream can never observe the
+    // closed channel before the terminal item is set
+    _stream_data_pusher: mpsc::Sender<T>,
+}
+
+impl<T> StreamCompletion<T> {
+    /// mark the producer as completed normally
+    pub fn finish(mut self) {
+        self.aborted = None;
+    }
+}
+
+impl<T> Drop for StreamCompletion<T> {
+    fn drop(&mut self) {
+        if let Some(aborted) = self.aborted.take() {
+            debug!("stream producer ended abnormally");
+            *self
+                .slot
+                .lock()
+                .expect("expect stream completion lock to not be poisoned") = Some(aborted());
+        }
+    }
+}
+
+/// terminal item of a server stream whose producer ended abnormally, for
+/// `ClientCancellableStream::completion()`
+#[allow(clippy::result_large_err)] // tonic mandates `Status` as the error of the stream items
+pub fn stream_aborted<T>() -> Result<T, Status> {
+    Err(ServiceError::StreamAborted.into())
+}
 
 #[derive(Debug)]
 /// this struct represent `tokio_stream::Stream` that will send `tokio::sync::Notify::notified()`
@@ -10,11 +146,24 @@ use tracing::debug;
 pub struct ClientCancellableStream<T> {
     notifier: Arc<Notify>,
     inner: mpsc::Receiver<T>,
+    terminal: Option<oneshot::Receiver<T>>,
+    registration: Option<(Arc<StreamRegistry<T>>, u64)>,
+    permit: Option<OwnedSemaphorePermit>,
+    completion: Arc<Mutex<Option<T>>>,
+    /// terminal item pushed by `StreamRegistry::drain()`, held until the buffer is flushed
+    drained: Option<T>,
+    terminated: bool,
 }
 
 impl<T> ClientCancellableStream<T> {
     pub fn new() -> (mpsc::Sender<T>, Self, Arc<Notify>) {
-        let (stream_data_pusher, stream_data_receiver) = mpsc::channel::<T>(4);
+        ClientCancellableStream::with_capacity(4)
+    }
+
+    /// same as `ClientCancellableStream::new()` but with a caller chosen channel buffer size.
+    /// `capacity` must be greater than 0
+    pub fn with_capacity(capacity: usize) -> (mpsc::Sender<T>, Self, Arc<Notify>) {
+        let (stream_data_pusher, stream_data_receiver) = mpsc::channel::<T>(capacity);
         let client_cancellation_signal_notifier = Arc::new(Notify::new());
 
         (
@@ -22,12 +171,73 @@ impl<T> ClientCancellableStream<T> {
             ClientCancellableStream {
                 notifier: Arc::clone(&client_cancellation_signal_notifier),
                 inner: stream_data_receiver,
+                terminal: None,
+                registration: None,
+                permit: None,
+                completion: Arc::new(Mutex::new(None)),
+                drained: None,
+                terminated: false,
             },
             client_cancellation_signal_notifier,
         )
     }
+
+    /// register the stream into `registry` so a terminal item can be pushed into it during the
+    /// shutdown drain phase. The stream will unregister itself once dropped
+    pub fn register(mut self, registry: &Arc<StreamRegistry<T>>) -> Self {
+        let (terminal_pusher, terminal_receiver) = oneshot::channel::<T>();
+        let id = registry.insert(terminal_pusher);
+
+        self.terminal = Some(terminal_receiver);
+        self.registration = Some((Arc::clone(registry), id));
+
+        self
+    }
+
+    /// create a completion guard for the producer feeding `stream_data_pusher`. See
+    /// `StreamCompletion` for details
+    pub fn completion<F>(
+        &self,
+        stream_data_pusher: &mpsc::Sender<T>,
+        aborted: F,
+    ) -> StreamCompletion<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        StreamCompletion {
+            slot: Arc::clone(&self.completion),
+            aborted: Some(Box::new(aborted)),
+            _stream_data_pusher: stream_data_pusher.clone(),
+        }
+    }
+
+    /// discard every item still buffered in the channel and count them as dropped
+    fn drop_buffered(&mut self) {
+        let mut dropped = 0;
+
+        while self.inner.try_recv().is_ok() {
+            dropped += 1;
+        }
+
+        if dropped > 0 {
+            STREAM_METRICS.items_dropped(dropped);
+        }
+    }
+
+    /// keep `permit` alive for as long as the stream is alive. The permit is released back to its
+    /// semaphore once the stream is dropped
+    pub fn hold_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        if self.permit.replace(permit).is_none() {
+            STREAM_METRICS.stream_opened();
+        }
+
+        self
+    }
 }
 
+// items are only ever moved in and out of the stream, never pinned
+impl<T> Unpin for ClientCancellableStream<T> {}
+
 impl<T> Stream for ClientCancellableStream<T> {
     type Item = T;
 
@@ -35,13 +245,260 @@ impl<T> Stream for ClientCancellableStream<T> {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.inner.poll_recv(cx)
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+
+        if let Some(terminal) = self.terminal.as_mut() {
+            match Pin::new(terminal).poll(cx) {
+                Poll::Ready(Ok(item)) => {
+                    // stop accepting items but flush what is already buffered, the terminal item
+                    // is yielded once the buffer is empty
+                    self.terminal = None;
+                    self.drained = Some(item);
+                    self.inner.close();
+                }
+                Poll::Ready(Err(_)) => self.terminal = None,
+                Poll::Pending => {}
+            }
+        }
+
+        match self.inner.poll_recv(cx) {
+            Poll::Ready(None) => {
+                // the buffer is flushed and either the stream is drained or every producer is gone,
+                // in the latter case emit the terminal item if one of them ended abnormally
+                let terminal = self.drained.take().or_else(|| {
+                    self.completion
+                        .lock()
+                        .expect("expect stream completion lock to not be poisoned")
+                        .take()
+                });
+                self.terminated = true;
+
+                Poll::Ready(terminal)
+            }
+            Poll::Ready(Some(item)) => {
+                STREAM_METRICS.item_delivered();
+
+                Poll::Ready(Some(item))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
 impl<T> Drop for ClientCancellableStream<T> {
     fn drop(&mut self) {
+        if let Some((registry, id)) = self.registration.take() {
+            registry.remove(id);
+        }
+        self.drop_buffered();
+        if self.permit.take().is_some() {
+            STREAM_METRICS.stream_closed();
+        }
+        // wake every task currently waiting (e.g. producer and heartbeat) and keep a permit for
+        // the one that is not waiting yet
+        self.notifier.notify_waiters();
         self.notifier.notify_one();
         debug!("client dropped stream");
     }
 }
+
+/// spawn a task pushing the item created by `heartbeat` into `stream_data_pusher` every `period`
+/// so intermediary proxies does not kill an idle stream. The task only hold a weak handle of the
+/// channel thus it never keep the stream open on its own and it exits once the stream is dropped,
+/// every producer is gone or `cancellation` fires. A heartbeat is skipped when the buffer is full
+/// since the stream is not idle anyway
+pub fn spawn_heartbeat<T, F>(
+    stream_data_pusher: &mpsc::Sender<T>,
+    cancellation: Arc<Notify>,
+    period: Duration,
+    heartbeat: F,
+) -> JoinHandle<()>
+where
+    T: Send + 'static,
+    F: Fn() -> T + Send + 'static,
+{
+    let stream_data_pusher = stream_data_pusher.downgrade();
+
+    spawn_with_name(
+        async move {
+            let mut ticker = interval_at(Instant::now() + period, period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = cancellation.notified() => break,
+                }
+
+                let stream_data_pusher = match stream_data_pusher.upgrade() {
+                    Some(stream_data_pusher) => stream_data_pusher,
+                    None => break,
+                };
+
+                match stream_data_pusher.try_send(heartbeat()) {
+                    Ok(()) => STREAM_METRICS.heartbeat_sent(),
+                    Err(TrySendError::Full(_)) => {}
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            }
+
+            debug!("stream heartbeat stopped");
+        },
+        "stream_heartbeat",
+    )
+}
+
+/// log a warning when the buffer of `stream_data_pusher` is almost full, which means the client
+/// consume the stream slower than it is produced and will likely end up timing out. Warnings are
+/// rate limited process wide to one per `BACKLOG_WARNING_INTERVAL`, the number of warnings skipped
+/// in between is reported with the next one
+pub fn warn_on_backlog<T>(stream_data_pusher: &mpsc::Sender<T>) {
+    let capacity = stream_data_pusher.capacity();
+    let max_capacity = stream_data_pusher.max_capacity();
+
+    if capacity * BACKLOG_FREE_RATIO > max_capacity {
+        return;
+    }
+
+    let mut warnings = BACKLOG_WARNINGS
+        .lock()
+        .expect("expect backlog warnings lock to not be poisoned");
+    let now = Instant::now();
+
+    match warnings.last_warned {
+        Some(last_warned) if now.duration_since(last_warned) < BACKLOG_WARNING_INTERVAL => {
+            warnings.suppressed += 1;
+        }
+        _ => {
+            warn!(
+                buffered = max_capacity - capacity,
+                capacity = max_capacity,
+                suppressed = warnings.suppressed,
+                "server stream is backing up, the client is consuming slower than it is produced"
+            );
+            warnings.last_warned = Some(now);
+            warnings.suppressed = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+    use tonic::Code;
+
+    #[tokio::test]
+    async fn drain_flushes_the_buffer_before_the_terminal_item() {
+        let registry = Arc::new(StreamRegistry::new());
+        let (stream_data_pusher, stream, _) = ClientCancellableStream::with_capacity(4);
+        let stream = stream.register(&registry);
+
+        stream_data_pusher.send("first").await.unwrap();
+        stream_data_pusher.send("second").await.unwrap();
+
+        assert_eq!(registry.drain(|| "shutdown"), 1);
+        assert_eq!(
+            stream.collect::<Vec<_>>().await,
+            ["first", "second", "shutdown"]
+        );
+        // the stream stopped accepting items once it saw the terminal item
+        assert!(stream_data_pusher.send("late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drain_skips_dropped_streams() {
+        let registry = Arc::new(StreamRegistry::new());
+        let (_stream_data_pusher, stream, _) = ClientCancellableStream::<&str>::new();
+
+        drop(stream.register(&registry));
+
+        assert_eq!(registry.drain(|| "shutdown"), 0);
+    }
+
+    #[test]
+    fn with_capacity_buffers_past_the_default_size() {
+        let (stream_data_pusher, _stream, _) = ClientCancellableStream::with_capacity(16);
+
+        // nothing is consuming the stream, every push must fit in the buffer
+        for item in 0..16 {
+            stream_data_pusher.try_send(item).unwrap();
+        }
+
+        assert!(matches!(
+            stream_data_pusher.try_send(16),
+            Err(TrySendError::Full(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn counters_track_delivered_dropped_and_heartbeats() {
+        // the counters are process wide and other tests stream concurrently, only assert deltas
+        let before = STREAM_METRICS.snapshot();
+        let (stream_data_pusher, mut stream, cancellation) =
+            ClientCancellableStream::with_capacity(8);
+        let heartbeat = spawn_heartbeat(
+            &stream_data_pusher,
+            cancellation,
+            Duration::from_millis(10),
+            || "heartbeat",
+        );
+
+        stream_data_pusher.send("item").await.unwrap();
+
+        assert_eq!(stream.next().await, Some("item"));
+        assert_eq!(stream.next().await, Some("heartbeat"));
+
+        // client disconnect with items still buffered
+        stream_data_pusher.send("undelivered").await.unwrap();
+        stream_data_pusher.send("undelivered").await.unwrap();
+        drop(stream);
+        heartbeat.await.unwrap();
+
+        let after = STREAM_METRICS.snapshot();
+
+        assert!(after.items_delivered >= before.items_delivered + 2);
+        assert!(after.items_dropped >= before.items_dropped + 2);
+        assert!(after.heartbeats_sent > before.heartbeats_sent);
+    }
+
+    #[test]
+    fn new_keeps_a_buffer_of_four() {
+        let (stream_data_pusher, _stream, _) = ClientCancellableStream::new();
+
+        for item in 0..4 {
+            stream_data_pusher.try_send(item).unwrap();
+        }
+
+        assert!(matches!(
+            stream_data_pusher.try_send(4),
+            Err(TrySendError::Full(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn producer_dropping_its_completion_ends_the_stream_aborted() {
+        let (stream_data_pusher, stream, _) =
+            ClientCancellableStream::<Result<&str, Status>>::new();
+        let completion = stream.completion(&stream_data_pusher, stream_aborted);
+
+        // the producer ends without calling `finish()`, as if it panicked halfway
+        tokio::spawn(async move {
+            let _completion = completion;
+
+            stream_data_pusher.send(Ok("first")).await.unwrap();
+            stream_data_pusher.send(Ok("second")).await.unwrap();
+        })
+        .await
+        .unwrap();
+
+        let items = stream.collect::<Vec<_>>().await;
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap(), &"first");
+        assert_eq!(items[1].as_ref().unwrap(), &"second");
+        assert_eq!(items[2].as_ref().unwrap_err().code(), Code::Aborted);
+    }
+}
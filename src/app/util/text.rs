@@ -0,0 +1,56 @@
+use std::borrow::Cow;
+
+/// default upper bound (in bytes) of user provided content or error detail written to the logs
+pub const MAX_LOGGED_BYTES: usize = 1024;
+
+/// keep at most `max` bytes of `s` without splitting a multibyte char. Truncated content get an
+/// ellipsis followed by the number of omitted bytes e.g. `abc…(+42 bytes)`
+pub fn truncate_utf8(s: &str, max: usize) -> Cow<'_, str> {
+    if s.len() <= max {
+        return Cow::Borrowed(s);
+    }
+
+    // `0` is always a char boundary so this can never underflow past the start of `s`
+    let end = (0..=max)
+        .rev()
+        .find(|&index| s.is_char_boundary(index))
+        .unwrap_or_default();
+
+    Cow::Owned(format!("{}…(+{} bytes)", &s[..end], s.len() - end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_content_is_borrowed_untouched() {
+        assert!(matches!(truncate_utf8("hello", 5), Cow::Borrowed("hello")));
+        assert!(matches!(truncate_utf8("", 0), Cow::Borrowed("")));
+    }
+
+    #[test]
+    fn ascii_content_is_cut_at_max() {
+        assert_eq!(truncate_utf8("hello world", 5), "hello…(+6 bytes)");
+    }
+
+    #[test]
+    fn multibyte_content_is_cut_on_a_char_boundary() {
+        // `é` is 2 bytes, `€` is 3 bytes and `🦀` is 4 bytes
+        let content = "é€🦀é€🦀";
+
+        // every limit falling inside a char must keep the chars before it and never panic
+        for max in 0..content.len() {
+            let truncated = truncate_utf8(content, max);
+            let (kept, suffix) = truncated.split_once('…').unwrap();
+
+            assert!(kept.len() <= max);
+            assert!(content.starts_with(kept));
+            assert_eq!(suffix, format!("(+{} bytes)", content.len() - kept.len()));
+        }
+
+        assert_eq!(truncate_utf8(content, 4), "é…(+16 bytes)");
+        assert_eq!(truncate_utf8(content, 5), "é€…(+13 bytes)");
+        assert_eq!(truncate_utf8(content, 1), "…(+18 bytes)");
+    }
+}
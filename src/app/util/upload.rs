@@ -0,0 +1,226 @@
+use super::error::ServiceError;
+use sha2::{Digest, Sha256};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+/// an in-progress upload written to `<upload_id>.part` inside the upload directory. Once the
+/// checksum is verified by `finish` the file is moved to `<upload_id>`. The partial file is
+/// removed when dropped before finishing e.g. when the client disconnect mid upload or the upload
+/// is rejected
+pub struct PartialUpload {
+    dir: PathBuf,
+    upload_id: Uuid,
+    file: File,
+    hasher: Sha256,
+    size: u64,
+    limit: u64,
+    completed: bool,
+}
+
+impl PartialUpload {
+    /// open the partial file of `upload_id` and continue writing at `offset`. `offset` must not
+    /// be past the bytes already uploaded and anything written after it is discarded
+    pub async fn open(
+        dir: &Path,
+        upload_id: Uuid,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Self, ServiceError> {
+        fs::create_dir_all(dir).await?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(partial_path(dir, &upload_id))
+            .await?;
+
+        let uploaded = file.metadata().await?.len();
+
+        if offset > uploaded {
+            return Err(ServiceError::ValidateFailure {
+                field: "offset",
+                reason: format!(
+                    "offset {} is past the {} byte(s) already uploaded",
+                    offset, uploaded
+                ),
+            });
+        }
+
+        if offset > limit {
+            return Err(exceeded(limit));
+        }
+
+        file.set_len(offset).await?;
+
+        // the checksum cover the whole file so the already uploaded part has to be hashed again
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+
+        file.seek(SeekFrom::Start(0)).await?;
+
+        loop {
+            let read = file.read(&mut buffer).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(PartialUpload {
+            dir: dir.to_path_buf(),
+            upload_id,
+            file,
+            hasher,
+            size: offset,
+            limit,
+            completed: false,
+        })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ServiceError> {
+        if self.size + data.len() as u64 > self.limit {
+            return Err(exceeded(self.limit));
+        }
+
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// verify the hex encoded sha256 `checksum` against everything written so far and keep the
+    /// file. Return the total size of the upload
+    pub async fn finish(mut self, checksum: &str) -> Result<u64, ServiceError> {
+        self.file.flush().await?;
+
+        let digest = format!("{:x}", self.hasher.finalize_reset());
+
+        if !digest.eq_ignore_ascii_case(checksum.trim()) {
+            return Err(ServiceError::ValidateFailure {
+                field: "sha256",
+                reason: "checksum does not match the uploaded content".to_string(),
+            });
+        }
+
+        fs::rename(
+            partial_path(&self.dir, &self.upload_id),
+            self.dir.join(self.upload_id.to_string()),
+        )
+        .await?;
+
+        self.completed = true;
+
+        Ok(self.size)
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = std::fs::remove_file(partial_path(&self.dir, &self.upload_id));
+        }
+    }
+}
+
+fn partial_path(dir: &Path, upload_id: &Uuid) -> PathBuf {
+    dir.join(format!("{}.part", upload_id))
+}
+
+fn exceeded(limit: u64) -> ServiceError {
+    ServiceError::ValidateFailure {
+        field: "data",
+        reason: format!("upload exceeds the maximum of {} byte(s)", limit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"hello, world";
+
+    fn upload_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("upload-test-{}", Uuid::new_v4()))
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[tokio::test]
+    async fn upload_with_a_matching_checksum_is_kept() {
+        let dir = upload_dir();
+        let upload_id = Uuid::new_v4();
+        let mut upload = PartialUpload::open(&dir, upload_id, 0, 1024).await.unwrap();
+
+        upload.write(&CONTENT[..5]).await.unwrap();
+        upload.write(&CONTENT[5..]).await.unwrap();
+
+        let size = upload
+            .finish(&sha256(CONTENT).to_uppercase())
+            .await
+            .unwrap();
+
+        assert_eq!(size, CONTENT.len() as u64);
+        assert_eq!(
+            fs::read(dir.join(upload_id.to_string())).await.unwrap(),
+            CONTENT
+        );
+        assert!(!partial_path(&dir, &upload_id).exists());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_with_a_mismatched_checksum_is_rejected_and_removed() {
+        let dir = upload_dir();
+        let upload_id = Uuid::new_v4();
+        let mut upload = PartialUpload::open(&dir, upload_id, 0, 1024).await.unwrap();
+
+        upload.write(CONTENT).await.unwrap();
+
+        let error = upload.finish(&sha256(b"something else")).await.unwrap_err();
+
+        assert!(matches!(
+            error,
+            ServiceError::ValidateFailure {
+                field: "sha256",
+                ..
+            }
+        ));
+        assert!(!partial_path(&dir, &upload_id).exists());
+        assert!(!dir.join(upload_id.to_string()).exists());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_over_the_limit_is_rejected() {
+        let dir = upload_dir();
+        let mut upload = PartialUpload::open(&dir, Uuid::new_v4(), 0, 4)
+            .await
+            .unwrap();
+
+        let error = upload.write(CONTENT).await.unwrap_err();
+
+        assert!(matches!(
+            error,
+            ServiceError::ValidateFailure { field: "data", .. }
+        ));
+
+        drop(upload);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
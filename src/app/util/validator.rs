@@ -0,0 +1,159 @@
+use super::error::ServiceError;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+/// run the `validator` rules derived on `request` (see `build.rs`). Only the first failing field
+/// (by name) is reported so the resulting `ServiceError::ValidateFailure` stay deterministic
+pub fn validate_request<T: Validate>(request: &T) -> Result<(), ServiceError> {
+    request.validate().map_err(validate_failure)
+}
+
+fn validate_failure(errors: ValidationErrors) -> ServiceError {
+    let mut fields = errors.field_errors().into_iter().collect::<Vec<_>>();
+
+    fields.sort_by_key(|(field, _)| *field);
+
+    match fields
+        .into_iter()
+        .find_map(|(field, errors)| errors.first().map(|error| (field, error)))
+    {
+        Some((field, error)) => ServiceError::ValidateFailure {
+            field,
+            reason: match &error.message {
+                Some(message) => message.to_string(),
+                None => error.code.to_string(),
+            },
+        },
+        None => ServiceError::ValidateFailure {
+            field: "request",
+            reason: errors.to_string(),
+        },
+    }
+}
+
+fn rule(code: &'static str, message: &'static str) -> ValidationError {
+    let mut error = ValidationError::new(code);
+
+    error.message = Some(message.into());
+
+    error
+}
+
+/// reject an empty or whitespace only string
+pub fn validate_not_blank(value: &str) -> Result<(), ValidationError> {
+    match value.trim().is_empty() {
+        true => Err(rule("blank", "must not be empty or whitespace only")),
+        false => Ok(()),
+    }
+}
+
+/// reject a repeated `field` of more than `max` entries. Run it before any per entry work so an
+/// oversized batch within the message size limit is still turned away cheaply
+pub fn validate_max_entries<T>(
+    field: &'static str,
+    values: &[T],
+    max: usize,
+) -> Result<(), ServiceError> {
+    match values.len() > max {
+        true => Err(ServiceError::ValidateFailure {
+            field,
+            reason: format!(
+                "must contain at most {} entries but got {}",
+                max,
+                values.len()
+            ),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// bounds (inclusive) of the number of entries accepted by `validate_custom_length_vec()`
+const MIN_VEC_LENGTH: usize = 1;
+const MAX_VEC_LENGTH: usize = 32;
+
+/// require between `MIN_VEC_LENGTH` and `MAX_VEC_LENGTH` entries
+#[cfg_attr(not(feature = "amqp"), allow(dead_code))]
+pub fn validate_custom_length_vec<T>(values: &[T]) -> Result<(), ValidationError> {
+    let error = |message: String| {
+        let mut error = ValidationError::new("length");
+
+        error.message = Some(message.into());
+        error.add_param("min".into(), &MIN_VEC_LENGTH);
+        error.add_param("max".into(), &MAX_VEC_LENGTH);
+        error.add_param("value".into(), &values.len());
+
+        error
+    };
+
+    match values.len() {
+        0 => Err(error("must not be empty".to_string())),
+        len if len < MIN_VEC_LENGTH => Err(error(format!(
+            "must contain at least {} entries but got {}",
+            MIN_VEC_LENGTH, len
+        ))),
+        len if len > MAX_VEC_LENGTH => Err(error(format!(
+            "must contain at most {} entries but got {}",
+            MAX_VEC_LENGTH, len
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reason(error: ValidationError) -> String {
+        error.message.unwrap().to_string()
+    }
+
+    #[test]
+    fn custom_length_vec_rejects_an_empty_vec() {
+        let error = validate_custom_length_vec::<u8>(&[]).unwrap_err();
+
+        assert_eq!(error.code, "length");
+        assert_eq!(reason(error), "must not be empty");
+    }
+
+    #[test]
+    fn custom_length_vec_accepts_both_bounds() {
+        assert!(validate_custom_length_vec(&[0; MIN_VEC_LENGTH]).is_ok());
+        assert!(validate_custom_length_vec(&[0; MAX_VEC_LENGTH]).is_ok());
+    }
+
+    #[test]
+    fn custom_length_vec_rejects_over_max() {
+        let error = validate_custom_length_vec(&[0; MAX_VEC_LENGTH + 1]).unwrap_err();
+
+        assert_eq!(error.params["value"], MAX_VEC_LENGTH + 1);
+        assert_eq!(error.params["max"], MAX_VEC_LENGTH);
+        assert_eq!(
+            reason(error),
+            format!(
+                "must contain at most {} entries but got {}",
+                MAX_VEC_LENGTH,
+                MAX_VEC_LENGTH + 1
+            )
+        );
+    }
+
+    #[cfg(feature = "amqp")]
+    #[test]
+    fn subscriptions_length_is_validated() {
+        use crate::app::service::amqp_subscription::amqp_subscription::SubscriptionCommandInitial;
+
+        let command = |len: usize| SubscriptionCommandInitial {
+            subscriptions: vec!["orders.created".to_string(); len],
+        };
+
+        assert!(validate_request(&command(1)).is_ok());
+
+        for len in [0, MAX_VEC_LENGTH + 1] {
+            match validate_request(&command(len)) {
+                Err(ServiceError::ValidateFailure { field, .. }) => {
+                    assert_eq!(field, "subscriptions")
+                }
+                other => panic!("expect a validate failure but got {:?}", other),
+            }
+        }
+    }
+}
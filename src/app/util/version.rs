@@ -0,0 +1,106 @@
+use super::error::ServiceError;
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+#[derive(Debug, Clone)]
+/// dot separated client application version e.g. `1.4.0`. Missing trailing components are treated
+/// as `0` so `1.4` and `1.4.0` compare as equal. Pre-release and build suffixes (`-beta`, `+42`)
+/// are ignored
+pub struct ClientVersion {
+    raw: String,
+    parts: Vec<u64>,
+}
+
+impl FromStr for ClientVersion {
+    type Err = ServiceError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let raw = raw.trim();
+        let core = raw
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default();
+
+        let parts = core
+            .split('.')
+            .map(u64::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ClientVersion {
+            raw: raw.to_string(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl Ord for ClientVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.parts.len().max(other.parts.len());
+
+        (0..len)
+            .map(|i| {
+                let lhs = self.parts.get(i).copied().unwrap_or_default();
+                let rhs = other.parts.get(i).copied().unwrap_or_default();
+
+                lhs.cmp(&rhs)
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for ClientVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ClientVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ClientVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(raw: &str) -> ClientVersion {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn missing_trailing_components_are_zero() {
+        assert_eq!(version("1.4"), version("1.4.0"));
+        assert_eq!(version("2"), version("2.0.0"));
+    }
+
+    #[test]
+    fn prefix_and_suffixes_are_ignored() {
+        assert_eq!(version("v1.2.3"), version("1.2.3"));
+        assert_eq!(version("1.2.3-beta"), version("1.2.3"));
+        assert_eq!(version("1.2.3+42"), version("1.2.3"));
+        assert_eq!(version(" 1.2.3 ").to_string(), "1.2.3");
+    }
+
+    #[test]
+    fn components_compare_numerically() {
+        assert!(version("1.10") > version("1.9"));
+        assert!(version("1.9.9") < version("2"));
+    }
+
+    #[test]
+    fn invalid_versions_are_rejected() {
+        for raw in ["", "1.x", "1..2", "latest"] {
+            assert!(raw.parse::<ClientVersion>().is_err(), "{}", raw);
+        }
+    }
+}
@@ -1,62 +1,114 @@
 use app::{
-    config::database::init_redis,
+    config::{
+        app::AppConfig,
+        database::init_redis,
+        healthcheck::{report_health, verify_dependencies},
+        listener::{bind_listener, tcp_incoming, ServerListener},
+        metrics::serve_metrics,
+        subscriber::{init_tracing, shutdown_tracing},
+        tls::{sni_server_config, tls_incoming},
+    },
+    interceptor::cookie_session::cookie_session_interceptor,
     middleware::{
-        config::layer::ConfigSessionLayer, cookie::layer::CookieSessionLayer,
-        sentry::layer::SentrySessionLayer, tracing::layer::TracingLayer,
+        availability::layer::AvailabilityLayer, config::layer::ConfigSessionLayer,
+        cookie::layer::CookieSessionLayer, drain::layer::DrainLayer,
+        idempotency::layer::IdempotencyLayer, limit::layer::MessageLimitLayer,
+        metrics::layer::MetricsLayer, ratelimit::layer::RateLimitLayer,
+        sentry::layer::SentrySessionLayer, timeout::layer::TimeoutLayer,
+        tracing::layer::TracingLayer, via::layer::ViaLayer,
     },
     service::test_message::{
-        test_message::test_message_service_server::TestMessageServiceServer, TestMessageGreeter,
+        test_message::{test_message_service_server::TestMessageServiceServer, ResponseMessage},
+        ResponseStreamRegistry, TestMessageGreeter,
+    },
+    util::{
+        error::set_error_detail_mode,
+        metrics::STREAM_METRICS,
+        recent::set_recent_errors_limits,
+        redis::{probe_getex_support, set_command_timeout, set_latency_metrics},
+        retry::set_retry_budget_ratio,
+        session::{
+            MemorySessionStore, RedisSessionStore, SessionBackend, SessionPolicy, SessionStore,
+        },
+        shutdown::ShutdownSignal,
     },
 };
-use sentry_tracing::EventFilter;
-use std::{env::var, sync::Arc};
-use tokio::{signal, sync::Notify, time::Duration};
-use tonic::transport::Server;
-use tracing::{info, info_span, log::debug};
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_futures::Instrument;
-use tracing_log::LogTracer;
-use tracing_subscriber::{
-    layer::SubscriberExt,
-    {EnvFilter, Registry},
+use futures::FutureExt;
+use std::sync::Arc;
+use tokio::{
+    signal,
+    sync::Semaphore,
+    time::{timeout_at, Duration, Instant},
+};
+use tonic::{
+    codegen::InterceptedService,
+    transport::{Identity, Server, ServerTlsConfig},
 };
+use tracing::{error, info, info_span, log::debug, warn};
+use tracing_futures::Instrument;
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
 
 use crate::app::config::task::spawn_with_name;
 
+#[cfg(feature = "grpc-web")]
+use crate::app::config::web::cors_layer;
+#[cfg(feature = "reflection")]
+use crate::app::service::test_message::test_message::FILE_DESCRIPTOR_SET;
+#[cfg(feature = "amqp")]
+use crate::app::{
+    config::amqp::AmqpConnection,
+    service::amqp_subscription::{
+        amqp_subscription::amqp_subscription_service_server::AmqpSubscriptionServiceServer,
+        AmqpSubscriptionGreeter,
+    },
+    util::{amqp::AmqpPublisher, mirror::install_error_mirror},
+};
+#[cfg(not(unix))]
+use app::config::listener::BindError;
+#[cfg(unix)]
+use app::config::listener::{bind_unix_listener, unix_incoming};
+#[cfg(feature = "grpc-web")]
+use tonic_web::GrpcWebLayer;
+
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
 const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+/// process exit code when the listen address could not be bound
+const EXIT_BIND_FAILURE: i32 = 2;
+/// process exit code when a dependency (redis, AMQP broker) is unreachable at startup
+const EXIT_DEPENDENCY_FAILURE: i32 = 3;
 
 lazy_static::lazy_static! {
     static ref APP_NAME: &'static str = env!("CARGO_PKG_NAME");
     static ref APP_VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    static ref APP_URL: String = var("APP_URL").expect("expect an APP_URL to be set. app url define internal IP address for application to bind and is usually 0.0.0.0");
-    static ref APP_PORT: String = var("APP_PORT").expect("expect an APP_PORT to be set. app port define virtual port for app to bind to");
-    static ref AMQP_ADDRESS: String = var("AMQP_ADDRESS").expect("expect an AMQP_ADDRESS to be setup");
-    static ref AMQP_ADMIN_USERNAME: String = var("AMQP_ADMIN_USERNAME").expect("expect an AMQP admin username to be set. admin username is used to authenticate into RabbitMQ to perform administration task");
-    static ref AMQP_ADMIN_PASSWORD: String = var("AMQP_ADMIN_PASSWORD").expect("expect an AMQP admin password to be set. admin password is used to authenticate into RabbitMQ to perform administration task");
-    static ref REDIS_URL: String = var("REDIS_URL").expect("expect a valid redis server url. redis url define address for redis client to connect to");
-    static ref SENTRY_URL: String = var("SENTRY_URL").expect("expect SENTRY_URL to be set");
 }
 
 mod app;
 
 #[tokio::main]
 async fn main() {
-    // install `log -> tracing` converter
-    LogTracer::init().expect("expect log tracer to complete the setup process");
     // setup .env file parser
     dotenv::dotenv().expect("expect a .env file and valid syntax");
+    // parse and validate every env var up front so all misconfiguration is reported at once
+    let config =
+        Arc::new(AppConfig::from_env().expect("expect every required env var to be set and valid"));
+    set_command_timeout(config.redis_command_timeout);
+    set_latency_metrics(config.redis_latency_metrics);
+    set_retry_budget_ratio(config.retry_budget_ratio);
+    set_error_detail_mode(config.error_detail_mode);
+    set_recent_errors_limits(
+        config.recent_errors_max_entries,
+        config.recent_errors_max_bytes,
+    );
 
     let name = &*APP_NAME;
     let version = &*APP_VERSION;
     // setup sentry DSN from env if `sentry-io` feature is enabled
-    let sentry_url = &*SENTRY_URL.to_owned();
+    let sentry_url = &*config.sentry_url;
     // setup sentry client hub and bind to the main thread
     let sentry_guard = sentry::init((
         sentry_url, // <- the DSN key
@@ -68,55 +120,131 @@ async fn main() {
             ],
             session_mode: sentry::SessionMode::Request, // <- setup a session mode to be `per request`
             auto_session_tracking: true,                // <- made session tracking automatic
-            ..Default::default()                        // <- leave other options to default
+            traces_sample_rate: config.sentry_traces_sample_rate, // <- share of requests sent as performance transactions
+            ..Default::default() // <- leave other options to default
         },
     ));
 
-    #[cfg(not(feature = "stdout"))]
-    let file_appender =
-        tracing_appender::rolling::hourly("/tmp/react-native-test-api/log", "hourly.log");
-    #[cfg(not(feature = "stdout"))]
-    let (non_blocking_writer, _guard) = tracing_appender::non_blocking(file_appender);
-
-    #[cfg(feature = "stdout")]
-    let (non_blocking_writer, _non_blocking_writer_guard) =
-        tracing_appender::non_blocking(std::io::stdout());
-
-    let bunyan_formatting_layer =
-        BunyanFormattingLayer::new(format!("{}-{}", name, version), non_blocking_writer);
-    let sentry_layer = sentry_tracing::layer().event_filter(|md| match *md.level() {
-        tracing::Level::ERROR | tracing::Level::WARN => EventFilter::Breadcrumb,
-        _ => EventFilter::Ignore,
-    });
-
-    let filter_layer = EnvFilter::new("INFO");
-    let subscriber = Registry::default()
-        .with(filter_layer)
-        .with(JsonStorageLayer)
-        .with(bunyan_formatting_layer)
-        .with(sentry_layer);
-    tracing::subscriber::set_global_default(subscriber)
+    // setup bunyan formatted tracing subscriber
+    let _non_blocking_writer_guard = init_tracing(name, version, &config)
         .expect("expect a tracing subscriber to complete the setup process");
+    // claim the listen address before anything else so a taken port fail fast with a clear reason
+    let listener = match &config.uds_path {
+        #[cfg(unix)]
+        Some(uds_path) => bind_unix_listener(uds_path).map(ServerListener::Unix),
+        // config validation already reject UDS_PATH here, never quietly fall back to TCP
+        #[cfg(not(unix))]
+        Some(uds_path) => Err(BindError::SocketUnsupported(uds_path.clone())),
+        None => bind_listener(config.addr).await.map(ServerListener::Tcp),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}", e);
+            // flush the buffered log lines before the process exit
+            drop(_non_blocking_writer_guard);
+            std::process::exit(EXIT_BIND_FAILURE);
+        }
+    };
     // initialize redis database connection manager
-    let redis_pool = init_redis().await;
+    let redis_pool = match init_redis(
+        &config.redis_url,
+        config.redis_cluster,
+        config.redis_connect_retries,
+    )
+    .await
+    {
+        Ok(redis_pool) => redis_pool,
+        Err(e) => {
+            error!("failed to connect to redis: {}", e);
+            // flush the buffered log lines before the process exit
+            drop(_non_blocking_writer_guard);
+            std::process::exit(EXIT_DEPENDENCY_FAILURE);
+        }
+    };
+    // init_redis waited for redis to come up, now make sure every dependency answer so a
+    // misconfigured address fail fast with all the reasons at once
+    if let Err(e) = verify_dependencies(&config).await {
+        error!("{}", e);
+        // flush the buffered log lines before the process exit
+        drop(_non_blocking_writer_guard);
+        std::process::exit(EXIT_DEPENDENCY_FAILURE);
+    }
+    // managed redis older than 6.2 does not know GETEX, pick the session TTL refresh mode once
+    probe_getex_support(&mut redis_pool.clone()).await;
+    // mirror captured errors to the central error processing exchange if configured
+    #[cfg(feature = "amqp")]
+    if let (Some(exchange), Some(amqp_address)) =
+        (&config.error_mirror_exchange, &config.amqp_address)
+    {
+        let publisher =
+            AmqpPublisher::connect(amqp_address, exchange, &config.error_mirror_routing_key)
+                .await
+                .expect(
+                    "expect an AMQP publisher for ERROR_MIRROR_EXCHANGE to be successfully setup",
+                );
+
+        install_error_mirror(publisher);
+    }
     // thread safe application shutdown signal notifier
-    let shutdown_signal_notifier = Arc::new(Notify::new());
+    let shutdown_signal_notifier = Arc::new(ShutdownSignal::new());
+    // every open server stream hold a permit until it is dropped
+    let stream_semaphore = Arc::new(Semaphore::new(config.max_concurrent_streams));
+    // registry of active server streams which will receive a shutdown notice during drain phase
+    let stream_registry = Arc::new(ResponseStreamRegistry::new());
+    // session records shared by the cookie middleware and the login/logout handlers
+    let session_policy = SessionPolicy {
+        read_ttl: config.session_read_ttl,
+        write_ttl: config.session_write_ttl,
+        expiry_mode: config.session_expiry_mode,
+    };
+    let session_store: Arc<dyn SessionStore> = match config.session_backend {
+        SessionBackend::Redis => {
+            Arc::new(RedisSessionStore::new(redis_pool.clone(), session_policy))
+        }
+        SessionBackend::Memory => Arc::new(MemorySessionStore::new(session_policy)),
+    };
 
-    // parse socket address from env
-    let addr = format!("{}:{}", *APP_URL, *APP_PORT)
-        .parse()
-        .expect("expect a successfully parsed url");
+    // broker connection shared by the subscription service and the deep health check
+    #[cfg(feature = "amqp")]
+    let amqp_connection = match &config.amqp_address {
+        Some(amqp_address) => Some(Arc::new(
+            AmqpConnection::connect(amqp_address)
+                .await
+                .expect("expect an AMQP connection for the subscription service"),
+        )),
+        None => None,
+    };
 
     let test_messag_greeter = TestMessageGreeter {
         shutdown_signal_notifier: Arc::clone(&shutdown_signal_notifier),
         redis_pool: redis_pool.clone(),
+        session_store: Arc::clone(&session_store),
+        stream_registry: Arc::clone(&stream_registry),
+        stream_semaphore: Arc::clone(&stream_semaphore),
+        paused_streams: Default::default(),
+        config: Arc::clone(&config),
+        #[cfg(feature = "amqp")]
+        amqp_connection: amqp_connection.clone(),
     };
 
+    // the subscription service is only served when an AMQP broker is configured
+    #[cfg(feature = "amqp")]
+    let amqp_subscription_service = amqp_connection.map(|connection| {
+        AmqpSubscriptionServiceServer::new(AmqpSubscriptionGreeter {
+            shutdown_signal_notifier: Arc::clone(&shutdown_signal_notifier),
+            connection,
+            exchange: config.amqp_subscription_exchange.clone(),
+            operation_timeout: config.amqp_operation_timeout,
+        })
+    });
+
     // graceful shutdown handler
     spawn_with_name(
         {
             let root_span = info_span!("shutdown interceptor");
             let shutdown_signal_notifier = Arc::clone(&shutdown_signal_notifier);
+            let stream_registry = Arc::clone(&stream_registry);
 
             async move {
                 debug!("waiting for ctrl-c signal...");
@@ -126,53 +254,242 @@ async fn main() {
                     .expect("expect ctrl-c signal to be successfully received");
                 debug!("received ctrl-c signal");
 
+                // drain phase: tell every active stream to reconnect elsewhere before closing it
+                #[allow(clippy::result_large_err)]
+                // tonic mandates `Status` as the error of the items
+                let drained = stream_registry.drain(|| Ok(ResponseMessage::shutdown_notice()));
+                debug!("sent shutdown notice to {} active stream(s)", drained);
+
                 // notify all client about application shutting down
-                shutdown_signal_notifier.notify_waiters();
+                shutdown_signal_notifier.trigger();
             }
             .instrument(root_span)
         },
         "shutdown interceptor",
     );
+    // serve prometheus metrics on a separate port if configured
+    if let Some(metrics_port) = config.metrics_port {
+        spawn_with_name(
+            serve_metrics(
+                (config.addr.ip(), metrics_port).into(),
+                Arc::clone(&shutdown_signal_notifier),
+            )
+            .instrument(info_span!("metrics server")),
+            "metrics server",
+        );
+    }
     // setup service layer a.k.a. middleware service
-    let layers = tower::ServiceBuilder::new().layer(TracingLayer);
+    let layers = tower::ServiceBuilder::new();
+
+    // answer CORS preflights and translate grpc-web calls into regular gRPC calls before any
+    // other middleware so tracing, sentry and the session layers see every call the same way
+    #[cfg(feature = "grpc-web")]
+    let layers = layers
+        .layer(cors_layer(&config.allowed_origins))
+        .layer(GrpcWebLayer::new());
+
+    let layers = layers
+        .layer(TracingLayer::new(config.trace_extra_headers.clone()))
+        .layer(MetricsLayer::new(config.grpc_status_metrics));
 
     let layers = layers.layer(SentrySessionLayer::builder().emit_header(true).finish());
 
+    let layers = layers.layer(ViaLayer(config.service_id.clone()));
+
+    let layers = layers.layer(AvailabilityLayer::new(config.disabled_services.clone()));
+
+    let layers = layers.layer(DrainLayer::new(
+        Arc::clone(&shutdown_signal_notifier),
+        config.shutdown_grace,
+    ));
+
+    // tonic 0.8 does not bound the size of decoded messages on its own
+    let layers = layers.layer(MessageLimitLayer(config.max_message_bytes));
+
+    // bound unary calls including the session lookup done by the layers added after it
+    let layers = layers.layer(TimeoutLayer::new(config.request_timeout));
+
+    // the config layer inject the redis pool read by the rate limit layer added after it
     let layers = layers
-        .layer(ConfigSessionLayer(redis_pool.clone()))
-        .layer(CookieSessionLayer);
+        .layer(ConfigSessionLayer::new(redis_pool.clone()))
+        .layer(
+            CookieSessionLayer::builder(Arc::clone(&session_store))
+                .force_relogin_below_version(config.force_relogin_below_version.clone())
+                .login_url(config.login_url.clone())
+                .write_methods(config.session_write_methods.clone())
+                .auth_exempt_methods(config.auth_exempt_methods.clone())
+                .signing_key(config.cookie_signing_key.clone())
+                .cookie_name(config.session_cookie_name.clone())
+                .finish(),
+        );
+
+    let layers = layers.layer(RateLimitLayer::new(
+        config.rate_limit_max_requests,
+        config.rate_limit_window,
+    ));
+
+    let layers = layers.layer(IdempotencyLayer::new(
+        config.require_idempotency_methods.clone(),
+    ));
 
     let layers = layers.into_inner();
 
     // setup google `grpc.health.v1.Health` compliant health reporter service
-    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
-    health_reporter
-        .set_serving::<TestMessageServiceServer<TestMessageGreeter>>()
-        .await;
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    report_health::<TestMessageServiceServer<TestMessageGreeter>>(
+        health_reporter,
+        &config,
+        redis_pool.clone(),
+    )
+    .await;
+
+    // setup `grpc.reflection.v1alpha.ServerReflection` service so the API can be explored with
+    // tools like `grpcurl` without a local copy of the proto files
+    #[cfg(feature = "reflection")]
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()
+        .expect("expect a reflection service to be successfully built");
+
+    // setup optional TLS termination. plaintext is used when neither cert nor key is provided.
+    // Restricting the SNI hostnames need a custom rustls acceptor so tonic does not terminate TLS
+    // itself in that case
+    let sni_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) if !config.allowed_sni_hosts.is_empty() => Some(
+            sni_server_config(cert_path, key_path, &config.allowed_sni_hosts)
+                .expect("expect a valid TLS certificate and private key pair"),
+        ),
+        _ => None,
+    };
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) if sni_config.is_none() => {
+            let cert = std::fs::read(cert_path)
+                .expect("expect TLS_CERT_PATH to point to a readable PEM encoded certificate");
+            let key = std::fs::read(key_path)
+                .expect("expect TLS_KEY_PATH to point to a readable PEM encoded private key");
+
+            Some(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)))
+        }
+        _ => None,
+    };
+
+    let mut server = Server::builder();
+
+    // grpc-web clients (browsers) may only speak HTTP/1.1
+    #[cfg(feature = "grpc-web")]
+    {
+        server = server.accept_http1(true);
+    }
+
+    if let Some(tls_config) = tls_config {
+        server = server
+            .tls_config(tls_config)
+            .expect("expect a valid TLS certificate and private key pair");
+    }
+
+    let test_message_service = test_messag_greeter.into_server();
+
+    // enforce a valid session on every non exempt method
+    let test_message_service =
+        InterceptedService::new(test_message_service, cookie_session_interceptor);
 
     // configure and build tonic gRPC server
-    Server::builder()
+    let router = server
         .layer(layers)
-        // .accept_http1(true)
         .tcp_keepalive(Some(KEEP_ALIVE_TIMEOUT))
         .http2_keepalive_interval(Some(KEEP_ALIVE_TIMEOUT / 3))
         .http2_keepalive_timeout(Some(KEEP_ALIVE_TIMEOUT))
         .add_service(health_service)
-        .add_service(TestMessageServiceServer::new(test_messag_greeter))
-        // .add_service(amqp_subscription_http11)
-        // bind shutdown signal for graceful shutdown
-        .serve_with_shutdown(addr, shutdown_signal_notifier.notified())
-        .await
-        .expect("expect a server to be successfully served");
-
-    // wait 10 seconds for all client to acknowledge the shutdown signal and sentry client to flush all events
+        .add_service(test_message_service);
+
+    #[cfg(feature = "amqp")]
+    let router = router.add_optional_service(amqp_subscription_service);
+
+    #[cfg(feature = "reflection")]
+    let router = router.add_service(reflection_service);
+
+    let server = match (listener, sni_config) {
+        (ServerListener::Tcp(listener), Some(sni_config)) => {
+            let incoming = tls_incoming(listener, sni_config);
+
+            router
+                // bind shutdown signal for graceful shutdown
+                .serve_with_incoming_shutdown(incoming, shutdown_signal_notifier.notified())
+                .boxed()
+        }
+        (ServerListener::Tcp(listener), None) => {
+            let incoming = tcp_incoming(listener, Some(KEEP_ALIVE_TIMEOUT))
+                .expect("expect a bound TCP listener to be successfully registered");
+
+            router
+                // bind shutdown signal for graceful shutdown
+                .serve_with_incoming_shutdown(incoming, shutdown_signal_notifier.notified())
+                .boxed()
+        }
+        // SNI restriction over a unix domain socket is rejected by the config validation
+        #[cfg(unix)]
+        (ServerListener::Unix(listener), _) => router
+            // bind shutdown signal for graceful shutdown
+            .serve_with_incoming_shutdown(
+                unix_incoming(listener),
+                shutdown_signal_notifier.notified(),
+            )
+            .boxed(),
+    };
+
+    // once the shutdown signal is received, only wait for connected clients to acknowledge it
+    // for the configured grace period before dropping the remaining connections
+    let mut server = server;
+    tokio::select! {
+        result = &mut server => result.expect("expect a server to be successfully served"),
+        deadline = async {
+            shutdown_signal_notifier.notified().await;
+            let deadline = Instant::now() + config.shutdown_grace;
+
+            // every permit is back once the last stream delivered its buffer and shutdown notice
+            // and was dropped, so stop waiting on the streams as soon as they are all gone
+            match timeout_at(
+                deadline,
+                stream_semaphore.acquire_many(config.max_concurrent_streams as u32),
+            )
+            .await
+            {
+                Ok(_) => info!("every active stream was drained"),
+                Err(_) => warn!(
+                    "{} stream(s) still active after the shutdown grace period",
+                    STREAM_METRICS.snapshot().active_streams
+                ),
+            }
+
+            deadline
+        } => {
+            // the server complete as soon as the in-flight unary calls are done, which the drain
+            // layer bound to the grace period
+            match timeout_at(deadline, server).await {
+                Ok(result) => result.expect("expect a server to be successfully served"),
+                Err(_) => warn!("shutdown grace period elapsed, dropping remaining connections"),
+            }
+        }
+    }
+
+    // wait for sentry client to flush all events within the grace period
     // or wait for ctrl-c signal to force application shutdown
     // FIXME! Caveats: stdout pipe seem to get disconnected when ctrl-c was received so these trace
     // will not show up in the console.
     // TODO! test whether they will show up in the log file or not
-    info!("performing graceful shutdown which may take up to 10 seconds... or ctrl-c to force shutdown");
+    let shutdown_grace = config.shutdown_grace;
+    info!(
+        "performing graceful shutdown which may take up to {} seconds... or ctrl-c to force shutdown",
+        shutdown_grace.as_secs()
+    );
     tokio::select! {
-        _ = tokio::task::spawn_blocking(move || sentry_guard.flush(Some(Duration::from_secs(10)))) => {
+        _ = tokio::task::spawn_blocking(move || {
+            sentry_guard.flush(Some(shutdown_grace));
+            shutdown_tracing();
+        }) => {
             debug!("exiting...");
         }
         _ = signal::ctrl_c() => {